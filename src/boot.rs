@@ -0,0 +1,184 @@
+use crate::register::{Register, FlagRegister};
+
+/// Catridge header, bit 7 set if CGB is supported
+pub const CGB_FLAG_ADDR: usize = 0x143;
+
+/// DMG boot ROM is mapped at 0x0000-0x00FF
+const BOOT_ROM_LOW_END: u16 = 0x00ff;
+/// CGB boot ROM is additionally mapped at 0x0200-0x08FF,
+/// 0x0100-0x01FF is left for catridge header
+const BOOT_ROM_HIGH_START: u16 = 0x0200;
+const BOOT_ROM_HIGH_END: u16 = 0x08ff;
+
+/// Whether address is covered by a boot ROM of given size
+pub fn in_boot_rom(addr: u16, size: usize) -> bool {
+    match addr {
+        0 ..= BOOT_ROM_LOW_END => true,
+        BOOT_ROM_HIGH_START ..= BOOT_ROM_HIGH_END => (addr as usize) < size,
+        _ => false,
+    }
+}
+
+/// Hardware model, decides the state left by boot ROM
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Model {
+    /// early DMG
+    DMG0,
+    DMG,
+    /// Game Boy Pocket
+    MGB,
+    /// Super Game Boy
+    SGB,
+    CGB,
+    /// Game Boy Advance in CGB mode
+    AGB,
+}
+
+impl Model {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "dmg0" => Some(Model::DMG0),
+            "dmg" => Some(Model::DMG),
+            "mgb" => Some(Model::MGB),
+            "sgb" => Some(Model::SGB),
+            "cgb" => Some(Model::CGB),
+            "agb" => Some(Model::AGB),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Model::DMG0 => "dmg0",
+            Model::DMG => "dmg",
+            Model::MGB => "mgb",
+            Model::SGB => "sgb",
+            Model::CGB => "cgb",
+            Model::AGB => "agb",
+        }
+    }
+
+    /// CGB if the catridge supports it, DMG otherwise
+    pub fn detect(binary: &[u8]) -> Self {
+        match binary.get(CGB_FLAG_ADDR) {
+            Some(flag) if flag & 0x80 != 0 => Model::CGB,
+            _ => Model::DMG,
+        }
+    }
+
+    pub fn is_cgb(&self) -> bool {
+        *self == Model::CGB || *self == Model::AGB
+    }
+
+    /// Whether catridge runs in CGB mode, a DMG-only catridge
+    /// runs in DMG compatibility mode on CGB
+    pub fn cgb_mode(&self, binary: &[u8]) -> bool {
+        self.is_cgb() && Model::detect(binary).is_cgb()
+    }
+
+    /// CPU registers after boot, A distinguishes the model for games
+    pub fn registers(&self, cgb_mode: bool) -> Register {
+        let (a, f, b, c, d, e, h, l) = match self {
+            Model::DMG0 => (0x01, 0x00, 0xff, 0x13, 0x00, 0xc1, 0x84, 0x03),
+            Model::DMG  => (0x01, 0xb0, 0x00, 0x13, 0x00, 0xd8, 0x01, 0x4d),
+            Model::MGB  => (0xff, 0xb0, 0x00, 0x13, 0x00, 0xd8, 0x01, 0x4d),
+            Model::SGB  => (0x01, 0x00, 0x00, 0x14, 0x00, 0x00, 0xc0, 0x60),
+            // B, H and L vary with licensee and title in DMG compatibility mode,
+            // values of catridges not published by Nintendo
+            Model::CGB if !cgb_mode => (0x11, 0x80, 0x00, 0x00, 0x00, 0x08, 0x00, 0x7c),
+            Model::AGB if !cgb_mode => (0x11, 0x00, 0x01, 0x00, 0x00, 0x08, 0x00, 0x7c),
+            Model::CGB  => (0x11, 0x80, 0x00, 0x00, 0xff, 0x56, 0x00, 0x0d),
+            Model::AGB  => (0x11, 0x00, 0x01, 0x00, 0xff, 0x56, 0x00, 0x0d),
+        };
+        Register {
+            a: a,
+            b: b,
+            c: c,
+            d: d,
+            e: e,
+            f: FlagRegister::from(f),
+            h: h,
+            l: l,
+        }
+    }
+
+    /// DIV after boot, depends on how long the boot ROM runs
+    pub fn div(&self) -> u8 {
+        match self {
+            Model::DMG0 => 0x18,
+            Model::DMG | Model::MGB => 0xab,
+            // vary with catridge header, not documented
+            _ => 0x00,
+        }
+    }
+
+    /// DMA register after boot
+    pub fn dma(&self) -> u8 {
+        if self.is_cgb() { 0x00 } else { 0xff }
+    }
+
+    /// IO registers written by boot ROM, in order,
+    /// DIV, DMA and HDMA5 are not included since writing them has side effects,
+    /// CGB registers are locked in DMG compatibility mode
+    pub fn io_registers(&self, cgb_mode: bool) -> Vec<(u16, u8)> {
+        let sc = if self.is_cgb() { 0x7f } else { 0x7e };
+        let nr52 = if *self == Model::SGB { 0xf0 } else { 0xf1 };
+        let mut registers = vec![
+            (0xff00, 0xcf), // P1
+            (0xff01, 0x00), // SB
+            (0xff02, sc),   // SC
+            (0xff05, 0x00), // TIMA
+            (0xff06, 0x00), // TMA
+            (0xff07, 0xf8), // TAC
+            (0xff0f, 0xe1), // IF
+            (0xff10, 0x80), // NR10
+            (0xff11, 0xbf), // NR11
+            (0xff12, 0xf3), // NR12
+            (0xff13, 0xff), // NR13
+            (0xff14, 0xbf), // NR14
+            (0xff16, 0x3f), // NR21
+            (0xff17, 0x00), // NR22
+            (0xff18, 0xff), // NR23
+            (0xff19, 0xbf), // NR24
+            (0xff1a, 0x7f), // NR30
+            (0xff1b, 0xff), // NR31
+            (0xff1c, 0x9f), // NR32
+            (0xff1d, 0xff), // NR33
+            (0xff1e, 0xbf), // NR34
+            (0xff20, 0xff), // NR41
+            (0xff21, 0x00), // NR42
+            (0xff22, 0x00), // NR43
+            (0xff23, 0xbf), // NR44
+            (0xff24, 0x77), // NR50
+            (0xff25, 0xf3), // NR51
+            (0xff26, nr52), // NR52
+            (0xff40, 0x91), // LCDC
+            (0xff41, 0x85), // STAT
+            (0xff42, 0x00), // SCY
+            (0xff43, 0x00), // SCX
+            (0xff45, 0x00), // LYC
+            (0xff47, 0xfc), // BGP
+            // not written by DMG boot ROM, random on hardware
+            (0xff48, 0xff), // OBP0
+            (0xff49, 0xff), // OBP1
+            (0xff4a, 0x00), // WY
+            (0xff4b, 0x00), // WX
+        ];
+        if cgb_mode {
+            registers.extend_from_slice(&[
+                (0xff4d, 0x7e), // KEY1
+                (0xff4f, 0xfe), // VBK
+                (0xff51, 0xff), // HDMA1
+                (0xff52, 0xff), // HDMA2
+                (0xff53, 0xff), // HDMA3
+                (0xff54, 0xff), // HDMA4
+                // left with auto increment after loading palettes
+                (0xff68, 0xc0), // BCPS
+                (0xff6a, 0xc0), // OCPS
+                (0xff70, 0xf8), // SVBK
+            ]);
+        }
+        registers.push((0xffff, 0x00)); // IE
+        registers
+    }
+}
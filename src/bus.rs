@@ -1,7 +1,14 @@
-use crate::memory::{Memory, Permission};
-use crate::gpu::{Gpu, LCDC, VRAM_START, VRAM_END, OAM_START, OAM_END};
+use crate::memory::{Memory, Permission, Wram};
+use crate::gpu::{Gpu, VRAM_START, VRAM_END, OAM_START, OAM_END};
 use crate::timer::{Timer, TIMER_START, TIMER_END};
 use crate::joypad::{Joypad, JOYPAD_ADDR};
+use crate::dma::{OamDma, Hdma, HDMA_BLOCK_LEN};
+use crate::boot::{self, Model};
+use crate::savestate::{Snapshot, StateWriter, StateReader};
+use crate::watch::{Watchpoints, Access};
+use crate::coverage::{self, Coverage};
+use crate::disasm::ROM_BANK_SIZE;
+use crate::instruction::Instruction;
 
 use num_traits::FromPrimitive;
 use num_derive::FromPrimitive;
@@ -12,6 +19,8 @@ const CATRIDGE_START: u16 = 0x0000;
 const CATRIDGE_END:   u16 = 0x7fff;
 const RAM_START:      u16 = 0xc000;
 const RAM_END:        u16 = 0xdfff;
+const SOUND_START:    u16 = 0xff10;
+const SOUND_END:      u16 = 0xff3f;
 const UNUSABLE_START: u16 = 0xfea0;
 const UNUSABLE_END:   u16 = 0xfeff;
 const HRAM_START:     u16 = 0xff80;
@@ -110,12 +119,26 @@ enum IO {
     SCY     = 0xff42,
     SCX     = 0xff43,
     LY      = 0xff44,
+    LYC     = 0xff45,
     DMA     = 0xff46,
     BGP     = 0xff47,
     OBP0    = 0xff48,
     OBP1    = 0xff49,
     WINY    = 0xff4a,
     WINX    = 0xff4b,
+    KEY1    = 0xff4d,
+    VBK     = 0xff4f,
+    BOOT    = 0xff50,
+    HDMA1   = 0xff51,
+    HDMA2   = 0xff52,
+    HDMA3   = 0xff53,
+    HDMA4   = 0xff54,
+    HDMA5   = 0xff55,
+    BCPS    = 0xff68,
+    BCPD    = 0xff69,
+    OCPS    = 0xff6a,
+    OCPD    = 0xff6b,
+    SVBK    = 0xff70,
     Dummy7f = 0xff7f,
 }
 
@@ -125,42 +148,156 @@ pub trait Device {
 }
 
 pub struct Bus {
+    /// boot ROM overlays catridge until 0xFF50 is written
+    boot_rom: Option<Memory>,
     catridge: Memory,
     pub gpu: Gpu,
     pub timer: Timer,
-    ram: Memory,
+    ram: Wram,
     hram: Memory,
+    /// sound registers and wave RAM, only stored since sound is not emulated
+    sound: Memory,
     unusable: Memory,
     pub interruptenb: InterruptFlag,
     pub joypad: Joypad,
+    oam_dma: OamDma,
+    hdma: Hdma,
+    /// run in CGB mode
+    pub cgb: bool,
+    /// CPU runs at 8MHz
+    pub double_speed: bool,
+    /// KEY1 bit 0, switch speed on next STOP
+    speed_switch: bool,
+    /// CPU accesses watched by debugger
+    pub watch: Watchpoints,
+    /// code/data log of catridge ROM, if enabled
+    pub coverage: Option<Coverage>,
 }
 
 impl Bus {
-    pub fn new(binary: Vec<u8>) -> Self {
+    /// Without boot ROM, IO registers are set as boot ROM of the model leaves
+    pub fn new(binary: Vec<u8>, model: Model, boot_rom: Option<Vec<u8>>) -> Self {
+        let cgb = model.cgb_mode(&binary);
+        if cgb {
+            info!("Run in CGB mode");
+        }
         let catridge = Memory::new(0, binary, Permission::ReadOnly);
-        Self {
+        let mut bus = Self {
+            boot_rom: boot_rom.map(|rom| Memory::new(0, rom, Permission::ReadOnly)),
             catridge: catridge,
-            gpu: Gpu::new(),
+            gpu: Gpu::new(cgb),
             timer: Timer::new(),
-            ram: Memory::new_empty(RAM_START as usize, (RAM_END - RAM_START + 1) as usize, Permission::Normal),
+            ram: Wram::new(RAM_START as usize),
             hram: Memory::new_empty(HRAM_START as usize, (HRAM_END - HRAM_START + 1) as usize, Permission::Normal),
+            sound: Memory::new_empty(SOUND_START as usize, (SOUND_END - SOUND_START + 1) as usize, Permission::Normal),
             unusable: Memory::new_empty(UNUSABLE_START as usize, (UNUSABLE_END - UNUSABLE_START + 1) as usize, Permission::Invalid),
             joypad: Joypad::new(),
             interruptenb: Default::default(),
+            oam_dma: Default::default(),
+            hdma: Default::default(),
+            cgb: cgb,
+            double_speed: false,
+            speed_switch: false,
+            watch: Default::default(),
+            coverage: None,
+        };
+        if bus.boot_rom.is_some() {
+            // boot ROM turns on LCD by itself
+            bus.gpu.set_lcdc(0);
+        } else {
+            bus.init_registers(model);
+        }
+        bus
+    }
+
+    fn init_registers(&mut self, model: Model) {
+        for (addr, value) in model.io_registers(self.cgb) {
+            if self.store(addr, value).is_err() {
+                error!("Fail to initialize register {:#X}", addr);
+            }
+        }
+        self.timer.set_div(model.div());
+        self.oam_dma.register = model.dma();
+    }
+
+    fn boot_rom_mapped(&self, addr: u16) -> bool {
+        match &self.boot_rom {
+            Some(rom) => boot::in_boot_rom(addr, rom.size()),
+            None => false,
+        }
+    }
+
+    /// pass clock to devices on bus, and run HDMA which stalls CPU
+    pub fn update(&mut self, clock: u64) {
+        self.update_devices(clock);
+        let stall = self.update_hdma();
+        if stall > 0 {
+            self.update_devices(stall);
+        }
+    }
+
+    /// GPU runs at the same speed when CPU is in double speed mode
+    fn update_devices(&mut self, clock: u64) {
+        let gpu_clock = if self.double_speed { clock / 2 } else { clock };
+        self.gpu.update(gpu_clock);
+        self.timer.update(clock);
+        self.update_dma(clock);
+    }
+
+    /// copy pending HDMA blocks, return clock CPU is stalled
+    fn update_hdma(&mut self) -> u64 {
+        let hblank_entered = std::mem::replace(&mut self.gpu.hblank_entered, false);
+        let mut copied = 0;
+        for _ in 0..self.hdma.pending_blocks(hblank_entered) {
+            let (src, dst) = match self.hdma.next_block() {
+                Some(block) => block,
+                None => break,
+            };
+            for i in 0..HDMA_BLOCK_LEN {
+                self.cover(src.wrapping_add(i), coverage::DMA);
+                // not blocked by GPU mode like CPU accesses
+                let byte = self.peek8(src.wrapping_add(i)).unwrap_or(0xff);
+                self.gpu.write_vram(dst + i, byte);
+            }
+            copied += 1;
+        }
+        copied * Hdma::block_clock(self.double_speed)
+    }
+
+    /// called on STOP, switch speed if requested by KEY1,
+    /// return false if not switched and CPU should enter STOP mode
+    pub fn switch_speed(&mut self) -> bool {
+        if self.cgb && self.speed_switch {
+            self.double_speed = !self.double_speed;
+            self.speed_switch = false;
+            info!("Switch to {} speed", if self.double_speed { "double" } else { "normal" });
+            return true;
         }
+        false
+    }
+
+    fn load_key1(&self) -> u8 {
+        (self.double_speed as u8) << 7 | 0x7e | self.speed_switch as u8
     }
 
     fn load_interrupt(&self) -> u8 {
-       ( if self.gpu.is_interrupt   { 1 << VBLANK_SHIFT } else { 0 } ) |
-       ( if self.timer.is_interrupt { 1 << TIMER_SHIFT  } else { 0 } )
+       ( if self.gpu.is_interrupt    { 1 << VBLANK_SHIFT } else { 0 } ) |
+       ( if self.gpu.is_stat_interrupt { 1 << LCDC_SHIFT } else { 0 } ) |
+       ( if self.timer.is_interrupt  { 1 << TIMER_SHIFT  } else { 0 } ) |
+       ( if self.joypad.is_interrupt { 1 << JOYPAD_SHIFT } else { 0 } )
     }
 
     fn store_interrupt(&mut self, value: u8) {
-        self.gpu.is_interrupt   = (value >> VBLANK_SHIFT) & 0x1 != 0;
-        self.timer.is_interrupt = (value >> TIMER_SHIFT)  & 0x4 != 0;
+        self.gpu.is_interrupt    = (value >> VBLANK_SHIFT) & 0x1 != 0;
+        self.gpu.is_stat_interrupt = (value >> LCDC_SHIFT) & 0x1 != 0;
+        self.timer.is_interrupt  = (value >> TIMER_SHIFT)  & 0x1 != 0;
+        self.joypad.is_interrupt = (value >> JOYPAD_SHIFT) & 0x1 != 0;
     }
 
     fn find_device(&self, addr: u16) -> Option<&dyn Device> {
+        if self.boot_rom_mapped(addr) {
+            return self.boot_rom.as_ref().map(|rom| rom as &dyn Device);
+        }
         match addr {
             CATRIDGE_START ..= CATRIDGE_END => Some(&self.catridge),
             VRAM_START ..= VRAM_END => Some(&self.gpu),
@@ -168,6 +305,7 @@ impl Bus {
             OAM_START ..= OAM_END => Some(&self.gpu),
             HRAM_START ..= HRAM_END => Some(&self.hram),
             TIMER_START ..= TIMER_END => Some(&self.timer),
+            SOUND_START ..= SOUND_END => Some(&self.sound),
             JOYPAD_ADDR => Some(&self.joypad),
             UNUSABLE_START ..= UNUSABLE_END => Some(&self.unusable),
             _ => return None,
@@ -184,12 +322,30 @@ impl Bus {
                     // match IO line
                     match FromPrimitive::from_u16(addr) {
                         Some(IO::LCDC) => Ok(self.gpu.lcdc.to_u8()),
+                        Some(IO::STAT) => Ok(self.gpu.load_stat()),
                         Some(IO::SCY) => Ok(self.gpu.scy),
                         Some(IO::SCX) => Ok(self.gpu.scx),
                         Some(IO::LY) => Ok(self.gpu.line),
+                        Some(IO::LYC) => Ok(self.gpu.lyc),
+                        Some(IO::DMA) => Ok(self.oam_dma.register),
                         Some(IO::BGP) => Ok(self.gpu.bg_palette),
                         Some(IO::OBP0) => Ok(self.gpu.ob0_palette),
                         Some(IO::OBP1) => Ok(self.gpu.ob1_palette),
+                        Some(IO::WINY) => Ok(self.gpu.wy),
+                        Some(IO::WINX) => Ok(self.gpu.wx),
+                        Some(IO::BOOT) => Ok(0xff),
+                        Some(IO::KEY1) if self.cgb => Ok(self.load_key1()),
+                        Some(IO::VBK) if self.cgb => Ok(self.gpu.load_vram_bank()),
+                        Some(IO::HDMA5) if self.cgb => Ok(self.hdma.load_control()),
+                        Some(IO::BCPS) if self.cgb => Ok(self.gpu.bg_colors.load_spec()),
+                        Some(IO::BCPD) if self.cgb => Ok(self.gpu.bg_colors.load_data()),
+                        Some(IO::OCPS) if self.cgb => Ok(self.gpu.obj_colors.load_spec()),
+                        Some(IO::OCPD) if self.cgb => Ok(self.gpu.obj_colors.load_data()),
+                        Some(IO::SVBK) if self.cgb => Ok(self.ram.load_bank()),
+                        Some(IO::KEY1) | Some(IO::VBK) | Some(IO::BCPS) | Some(IO::BCPD) |
+                        Some(IO::OCPS) | Some(IO::OCPD) | Some(IO::SVBK) |
+                        Some(IO::HDMA1) | Some(IO::HDMA2) | Some(IO::HDMA3) |
+                        Some(IO::HDMA4) | Some(IO::HDMA5) => Ok(0xff),
                         Some(_) => {
                             info!("Unimplemented load on address {:#X}", addr);
                             Ok(0)
@@ -211,6 +367,7 @@ impl Bus {
             OAM_START ..= OAM_END => Some(&mut self.gpu),
             HRAM_START ..= HRAM_END => Some(&mut self.hram),
             TIMER_START ..= TIMER_END => Some(&mut self.timer),
+            SOUND_START ..= SOUND_END => Some(&mut self.sound),
             JOYPAD_ADDR => Some(&mut self.joypad),
             CATRIDGE_START ..= CATRIDGE_END => Some(&mut self.catridge),
             UNUSABLE_START ..= UNUSABLE_END => Some(&mut self.unusable),
@@ -227,14 +384,34 @@ impl Bus {
                 _ => {
                     // match IO line
                     match FromPrimitive::from_u16(addr) {
-                        Some(IO::LCDC) => self.gpu.lcdc = LCDC::from_u8(value),
+                        Some(IO::LCDC) => self.gpu.set_lcdc(value),
+                        Some(IO::STAT) => self.gpu.store_stat(value),
                         Some(IO::SCY) => self.gpu.scy = value,
                         Some(IO::SCX) => self.gpu.scx = value,
                         Some(IO::LY) => self.gpu.line = 0,
-                        Some(IO::DMA) => self.dma(value),
+                        Some(IO::LYC) => self.gpu.store_lyc(value),
+                        Some(IO::DMA) => self.oam_dma.start(value),
                         Some(IO::BGP) => self.gpu.bg_palette = value,
                         Some(IO::OBP0) => self.gpu.ob0_palette = value,
                         Some(IO::OBP1) => self.gpu.ob1_palette = value,
+                        Some(IO::WINY) => self.gpu.wy = value,
+                        Some(IO::WINX) => self.gpu.wx = value,
+                        Some(IO::BOOT) if value != 0 && self.boot_rom.is_some() => {
+                            info!("Boot ROM unmapped");
+                            self.boot_rom = None;
+                        },
+                        Some(IO::KEY1) if self.cgb => self.speed_switch = value & 0x1 != 0,
+                        Some(IO::VBK) if self.cgb => self.gpu.store_vram_bank(value),
+                        Some(IO::HDMA1) if self.cgb => self.hdma.store_source_high(value),
+                        Some(IO::HDMA2) if self.cgb => self.hdma.store_source_low(value),
+                        Some(IO::HDMA3) if self.cgb => self.hdma.store_dest_high(value),
+                        Some(IO::HDMA4) if self.cgb => self.hdma.store_dest_low(value),
+                        Some(IO::HDMA5) if self.cgb => self.hdma.store_control(value),
+                        Some(IO::BCPS) if self.cgb => self.gpu.bg_colors.store_spec(value),
+                        Some(IO::BCPD) if self.cgb => self.gpu.bg_colors.store_data(value),
+                        Some(IO::OCPS) if self.cgb => self.gpu.obj_colors.store_spec(value),
+                        Some(IO::OCPD) if self.cgb => self.gpu.obj_colors.store_data(value),
+                        Some(IO::SVBK) if self.cgb => self.ram.store_bank(value),
                         Some(_) => {},
                         None => {
                             error!("Invalid store to address {:#X}", addr);
@@ -247,40 +424,225 @@ impl Bus {
         }
     }
 
-    fn dma(&mut self, value: u8) {
-        /* dma copy 40 * 28 bits data to OAM zone 0xFE00-0xFE9F
-         * each sprite takes 28 bits space (note that 4 bits are not used in each sprite)
-         * the source address can be designated every 0x100 from 0x0000 to 0xF100.
-         * Depend on the value stored to dma IO line:
-         * 0x00 -> 0x0000
-         * 0x01 -> 0x0100
-         * ...
-         */
-        let addr = (value as u16) << 8;
-        // copy memory to OAM
-        for i in 0..(40 * 4) {
-            let byte = self.load(addr + i).unwrap();
-            self.store(OAM_START + i, byte).unwrap();
+    fn update_dma(&mut self, clock: u64) {
+        for i in self.oam_dma.update(clock) {
+            self.cover(self.oam_dma.source(i), coverage::DMA);
+            let byte = self.load(self.oam_dma.source(i)).unwrap_or(0xff);
+            self.gpu.write_oam(i as usize, byte);
         }
     }
 
+    /// During OAM DMA, CPU can only access HRAM,
+    /// IO registers are inside CPU thus also accessible
+    fn cpu_accessible(&self, addr: u16) -> bool {
+        !self.oam_dma.is_active() || addr >= 0xff00
+    }
+
     pub fn load8(&self, addr: u16) -> Result<u8, ()> {
+        if !self.cpu_accessible(addr) {
+            return Ok(0xff);
+        }
+        let value = self.load(addr)?;
+        if self.coverage.as_ref().is_some_and(|coverage| !coverage.in_instruction(addr)) {
+            self.cover(addr, coverage::DATA);
+        }
+        if !self.watch.is_empty() {
+            self.watch.check(Access::Read, addr, value, value);
+        }
+        Ok(value)
+    }
+
+    /// read of opcode and operands, not checked by read watchpoints since
+    /// the debugger checks the instruction as execute access
+    pub fn fetch8(&self, addr: u16) -> Result<u8, ()> {
+        if !self.cpu_accessible(addr) {
+            return Ok(0xff);
+        }
         self.load(addr)
     }
 
-    pub fn load16(&self, addr: u16) -> Result<u16, ()> {
-        let msb = self.load(addr+1)?;
-        let lsb = self.load(addr)?;
-        Ok(((msb as u16) << 8) | (lsb as u16))
+    /// read for debugging, not blocked by DMA or GPU mode nor watched, and
+    /// unmapped addresses fail without logging since viewers read every address
+    pub fn peek8(&self, addr: u16) -> Result<u8, ()> {
+        let io: Option<IO> = FromPrimitive::from_u16(addr);
+        if self.find_device(addr).is_none() && addr != INT && addr != INTENB && io.is_none() {
+            return Err(());
+        }
+        match addr {
+            VRAM_START ..= VRAM_END | OAM_START ..= OAM_END => self.gpu.peek(addr),
+            _ => self.load(addr),
+        }
+    }
+
+    /// write for debugging like peek8, not blocked by DMA or GPU mode nor watched
+    pub fn poke8(&mut self, addr: u16, value: u8) -> Result<(), ()> {
+        match addr {
+            VRAM_START ..= VRAM_END | OAM_START ..= OAM_END => self.gpu.poke(addr, value),
+            _ => self.store(addr, value),
+        }
+    }
+
+    /// Bank mapped at addr, as numbered in symbol files. The catridge
+    /// has no MBC, so bank 1 is always mapped at 0x4000-0x7FFF
+    pub fn bank(&self, addr: u16) -> u16 {
+        match addr {
+            0x4000 ..= 0x7fff => 1,
+            0x8000 ..= 0x9fff => (self.gpu.load_vram_bank() & 0x1) as u16,
+            0xd000 ..= 0xdfff => (self.ram.load_bank() & 0x7) as u16,
+            _ => 0,
+        }
+    }
+
+    /// offset in catridge ROM of addr, None if not mapped to ROM
+    pub fn rom_offset(&self, addr: u16) -> Option<usize> {
+        if addr >= 0x8000 || self.boot_rom_mapped(addr) {
+            return None;
+        }
+        let bank = self.bank(addr) as usize;
+        Some(bank * ROM_BANK_SIZE + (addr as usize & (ROM_BANK_SIZE - 1)))
+    }
+
+    /// log access to ROM at addr
+    fn cover(&self, addr: u16, flag: u8) {
+        if let (Some(coverage), Some(offset)) = (&self.coverage, self.rom_offset(addr)) {
+            coverage.mark(offset, flag);
+        }
+    }
+
+    /// log instruction at pc as code, before it runs
+    pub fn cover_instruction(&mut self, pc: u16) {
+        if self.coverage.is_none() {
+            return;
+        }
+        let opcode = self.peek8(pc).unwrap_or(0);
+        // CB prefixed opcodes are two bytes
+        let len = match Instruction::from_byte(opcode) {
+            _ if opcode == 0xcb => 2,
+            Some(inst) => 1 + inst.len(),
+            None => 1,
+        };
+        self.cover(pc, coverage::CODE);
+        for i in 1..len {
+            let flag = if opcode == 0xcb { coverage::CODE } else { coverage::OPERAND };
+            self.cover(pc.wrapping_add(i), flag);
+        }
+        if let Some(coverage) = self.coverage.as_mut() {
+            coverage.instruction = (pc, pc.wrapping_add(len));
+        }
     }
 
     pub fn store8(&mut self, addr: u16, value: u8) -> Result<(), ()> {
+        if !self.cpu_accessible(addr) {
+            return Ok(());
+        }
+        if !self.watch.is_empty() && self.watch.watches(Access::Write, addr) {
+            let old = self.load(addr).unwrap_or(0xff);
+            self.watch.check(Access::Write, addr, old, value);
+        }
         self.store(addr, value)
     }
+}
+
+/// Catridge and boot ROM are not saved, only whether boot ROM is mapped
+impl Snapshot for Bus {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.chunk(b"REGS", |w| {
+            w.bool(self.cgb);
+            w.bool(self.double_speed);
+            w.bool(self.speed_switch);
+            w.u8(u8::from(&self.interruptenb));
+            w.bool(self.boot_rom.is_some());
+        });
+        w.chunk(b"GPU ", |w| self.gpu.save_state(w));
+        w.chunk(b"TIMR", |w| self.timer.save_state(w));
+        w.chunk(b"JOYP", |w| self.joypad.save_state(w));
+        w.chunk(b"WRAM", |w| self.ram.save_state(w));
+        w.chunk(b"HRAM", |w| self.hram.save_state(w));
+        w.chunk(b"SND ", |w| self.sound.save_state(w));
+        w.chunk(b"ODMA", |w| self.oam_dma.save_state(w));
+        w.chunk(b"HDMA", |w| self.hdma.save_state(w));
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        if let Some(mut r) = r.chunk(b"REGS") {
+            self.cgb = r.bool();
+            self.gpu.cgb = self.cgb;
+            self.double_speed = r.bool();
+            self.speed_switch = r.bool();
+            self.interruptenb = InterruptFlag::from(r.u8());
+            if !r.bool() {
+                self.boot_rom = None;
+            }
+        }
+        let devices: [(&[u8; 4], &mut dyn Snapshot); 8] = [
+            (b"GPU ", &mut self.gpu),
+            (b"TIMR", &mut self.timer),
+            (b"JOYP", &mut self.joypad),
+            (b"WRAM", &mut self.ram),
+            (b"HRAM", &mut self.hram),
+            (b"SND ", &mut self.sound),
+            (b"ODMA", &mut self.oam_dma),
+            (b"HDMA", &mut self.hdma),
+        ];
+        for (tag, dev) in devices {
+            if let Some(mut r) = r.chunk(tag) {
+                dev.load_state(&mut r);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpu::GpuMode;
+
+    fn cgb_bus() -> Bus {
+        let mut rom = vec![0; 0x8000];
+        rom[boot::CGB_FLAG_ADDR] = 0x80;
+        Bus::new(rom, Model::CGB, None)
+    }
+
+    /// start general purpose HDMA of blocks from src to VRAM dst
+    fn start_hdma(bus: &mut Bus, src: u16, dst: u16, blocks: u8) {
+        bus.store8(0xff51, (src >> 8) as u8).unwrap();
+        bus.store8(0xff52, src as u8).unwrap();
+        bus.store8(0xff53, (dst >> 8) as u8).unwrap();
+        bus.store8(0xff54, dst as u8).unwrap();
+        bus.store8(0xff55, blocks - 1).unwrap();
+    }
+
+    #[test]
+    fn general_hdma_is_not_blocked_by_gpu() {
+        let mut bus = cgb_bus();
+        for i in 0..0x20 {
+            bus.store8(0xc000 + i, i as u8 + 1).unwrap();
+            bus.poke8(0x8800 + i, i as u8 + 0x41).unwrap();
+        }
+        // run into mode 3, VRAM is blocked for CPU
+        while bus.gpu.mode != GpuMode::ScanlineVRAM {
+            bus.update(4);
+        }
+        start_hdma(&mut bus, 0xc000, 0x8000, 2);
+        bus.update(4);
+        while bus.gpu.mode != GpuMode::ScanlineVRAM {
+            bus.update(4);
+        }
+        // source in VRAM reads what is there, though games do not rely on it
+        start_hdma(&mut bus, 0x8800, 0x9000, 2);
+        bus.update(4);
+        for i in 0..0x20 {
+            assert_eq!(bus.gpu.peek(0x8000 + i), Ok(i as u8 + 1));
+            assert_eq!(bus.gpu.peek(0x9000 + i), Ok(i as u8 + 0x41));
+        }
+    }
 
-    pub fn store16(&mut self, addr: u16, value: u16) -> Result<(), ()> {
-        self.store(addr, (value & 0xff) as u8)?;
-        self.store(addr+1, ((value >> 8) & 0xff) as u8)?;
-        Ok(())
+    #[test]
+    fn general_hdma_stall_advances_lines() {
+        let mut bus = cgb_bus();
+        start_hdma(&mut bus, 0xc000, 0x8000, 128);
+        let stall = 128 * Hdma::block_clock(false);
+        bus.update(4);
+        assert_eq!(bus.gpu.line as u64, (stall + 4) / 456);
     }
 }
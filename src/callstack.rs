@@ -0,0 +1,210 @@
+use std::collections::VecDeque;
+
+/// Frames kept in call stack, the oldest are dropped when a program
+/// calls without returning
+const MAX_DEPTH: usize = 256;
+/// Executed PCs kept for crash report
+const RECENT_PCS: usize = 16;
+/// CPU writes kept for crash report
+const RECENT_WRITES: usize = 16;
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum FrameKind {
+    Call,
+    Rst,
+    Interrupt,
+}
+
+impl FrameKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            FrameKind::Call => "call",
+            FrameKind::Rst => "rst",
+            FrameKind::Interrupt => "interrupt",
+        }
+    }
+}
+
+#[derive(Debug,Clone,Copy)]
+pub struct Frame {
+    pub kind: FrameKind,
+    /// address of CALL or RST, or PC interrupted
+    pub from: u16,
+    /// address jumped to
+    pub to: u16,
+    /// SP after return address is pushed
+    pub sp: u16,
+}
+
+/// Shadow of the stack, with a frame for each CALL, RST and interrupt
+/// not returned yet
+#[derive(Default)]
+pub struct CallStack {
+    pub frames: VecDeque<Frame>,
+}
+
+impl CallStack {
+    pub fn push(&mut self, frame: Frame) {
+        if self.frames.len() == MAX_DEPTH {
+            self.frames.pop_front();
+        }
+        self.frames.push_back(frame);
+    }
+
+    /// RET or RETI with SP at return address, frames deeper than SP
+    /// are left by programs which reset or adjust the stack
+    pub fn ret(&mut self, sp: u16) {
+        while self.frames.back().is_some_and(|frame| frame.sp <= sp) {
+            self.frames.pop_back();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+}
+
+#[derive(Debug,Clone,Copy)]
+pub struct Write {
+    /// PC of instruction which writes
+    pub pc: u16,
+    pub addr: u16,
+    pub value: u16,
+    /// 16 bits value is written
+    pub word: bool,
+}
+
+/// Recently executed PCs and CPU writes
+#[derive(Default)]
+pub struct Recent {
+    pub pcs: VecDeque<u16>,
+    pub writes: VecDeque<Write>,
+}
+
+impl Recent {
+    pub fn exec(&mut self, pc: u16) {
+        if self.pcs.len() == RECENT_PCS {
+            self.pcs.pop_front();
+        }
+        self.pcs.push_back(pc);
+    }
+
+    pub fn write(&mut self, addr: u16, value: u16, word: bool) {
+        if self.writes.len() == RECENT_WRITES {
+            self.writes.pop_front();
+        }
+        let pc = self.pcs.back().copied().unwrap_or(0);
+        self.writes.push_back(Write { pc: pc, addr: addr, value: value, word: word });
+    }
+
+    pub fn clear(&mut self) {
+        self.pcs.clear();
+        self.writes.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::boot::Model;
+    use crate::cpu::Cpu;
+
+    /// CPU at $0150 running code placed at given addresses
+    fn cpu(code: &[(usize, &[u8])]) -> Cpu {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x104].copy_from_slice(&[0x00, 0xc3, 0x50, 0x01]); // jp $0150
+        for (addr, bytes) in code {
+            rom[*addr..*addr + bytes.len()].copy_from_slice(bytes);
+        }
+        let mut cpu = Cpu::new(rom, Model::DMG, None);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        cpu
+    }
+
+    fn targets(cpu: &Cpu) -> Vec<(FrameKind, u16)> {
+        cpu.calls.frames.iter().map(|frame| (frame.kind, frame.to)).collect()
+    }
+
+    #[test]
+    fn call_and_return() {
+        let mut cpu = cpu(&[
+            (0x0008, &[0xd9]),              // reti
+            (0x0150, &[0xcd, 0x60, 0x01,    // call $0160
+                       0xcf,                // rst $08
+                       0x18, 0xfe]),        // jr $0154
+            (0x0160, &[0xcd, 0x70, 0x01,    // call $0170
+                       0xc9]),              // ret
+            (0x0170, &[0xc9]),              // ret
+        ]);
+        cpu.step().unwrap();
+        assert_eq!(targets(&cpu), [(FrameKind::Call, 0x0160)]);
+        cpu.step().unwrap();
+        assert_eq!(targets(&cpu), [(FrameKind::Call, 0x0160), (FrameKind::Call, 0x0170)]);
+        assert_eq!(cpu.calls.frames[1].from, 0x0160);
+        cpu.step().unwrap();
+        assert_eq!(targets(&cpu), [(FrameKind::Call, 0x0160)]);
+        cpu.step().unwrap();
+        assert!(cpu.calls.frames.is_empty());
+        assert_eq!(cpu.pc, 0x0153);
+        cpu.step().unwrap();
+        assert_eq!(targets(&cpu), [(FrameKind::Rst, 0x0008)]);
+        cpu.step().unwrap();
+        assert!(cpu.calls.frames.is_empty());
+        assert_eq!(cpu.pc, 0x0154);
+    }
+
+    #[test]
+    fn return_unwinds_dropped_frames() {
+        let mut cpu = cpu(&[
+            (0x0150, &[0xcd, 0x60, 0x01,    // call $0160
+                       0x18, 0xfe]),        // jr $0153
+            (0x0160, &[0xcd, 0x70, 0x01]),  // call $0170
+            (0x0170, &[0xe1,                // pop hl
+                       0xc9]),              // ret
+        ]);
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.calls.frames.len(), 2);
+        // returns to $0153 from both calls
+        cpu.step().unwrap();
+        assert!(cpu.calls.frames.is_empty());
+        assert_eq!(cpu.pc, 0x0153);
+    }
+
+    #[test]
+    fn return_below_frame_keeps_it() {
+        let mut cpu = cpu(&[
+            (0x0150, &[0xcd, 0x60, 0x01]),  // call $0160
+            (0x0160, &[0x21, 0x70, 0x01,    // ld hl, $0170
+                       0xe5,                // push hl
+                       0xc9]),              // ret
+            (0x0170, &[0xc9]),              // ret
+        ]);
+        for _ in 0..4 {
+            cpu.step().unwrap();
+        }
+        // jump through stack
+        assert_eq!(cpu.pc, 0x0170);
+        assert_eq!(targets(&cpu), [(FrameKind::Call, 0x0160)]);
+        cpu.step().unwrap();
+        assert!(cpu.calls.frames.is_empty());
+        assert_eq!(cpu.pc, 0x0153);
+    }
+
+    #[test]
+    fn stack_reset_drops_frames() {
+        let mut calls = CallStack::default();
+        for sp in [0xfffc, 0xfffa, 0xfff8] {
+            calls.push(Frame { kind: FrameKind::Call, from: 0, to: 0, sp: sp });
+        }
+        // RET of the middle frame after SP is moved up
+        calls.ret(0xfffa);
+        assert_eq!(calls.frames.iter().map(|frame| frame.sp).collect::<Vec<_>>(), [0xfffc]);
+        calls.ret(0xdff0);
+        assert_eq!(calls.frames.len(), 1);
+        calls.ret(0xfffe);
+        assert!(calls.frames.is_empty());
+    }
+}
@@ -0,0 +1,113 @@
+use std::cell::RefCell;
+use std::fs;
+use std::path::Path;
+
+/// Flags of a ROM byte in code/data log, several may be set
+pub const CODE: u8 = 0x01;
+/// read as operand of an instruction
+pub const OPERAND: u8 = 0x02;
+/// read as data by CPU
+pub const DATA: u8 = 0x04;
+/// read by OAM DMA or HDMA
+pub const DMA: u8 = 0x08;
+
+/// Code/data log of catridge ROM, one byte of flags for each ROM byte
+/// like the CDL files of other emulators. Files are merged by OR, so
+/// coverage adds up over sessions.
+pub struct Coverage {
+    /// loads only borrow the bus
+    flags: RefCell<Vec<u8>>,
+    /// addresses of the instruction running, reads of them are not data
+    pub instruction: (u16, u16),
+}
+
+impl Coverage {
+    pub fn new(rom_size: usize) -> Self {
+        Self {
+            flags: RefCell::new(vec![0; rom_size]),
+            instruction: (0, 0),
+        }
+    }
+
+    /// start from log file if it exists, which must be of the same ROM size
+    pub fn load(path: &Path, rom_size: usize) -> Result<Self, String> {
+        let coverage = Self::new(rom_size);
+        if path.exists() {
+            let data = fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            if data.len() != rom_size {
+                return Err(format!("{}: size {} is not ROM size {}", path.display(), data.len(), rom_size));
+            }
+            coverage.flags.replace(data);
+        }
+        Ok(coverage)
+    }
+
+    /// merge with log file saved by another session and write it
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let mut flags = self.flags.borrow().clone();
+        if let Ok(saved) = fs::read(path) {
+            if saved.len() == flags.len() {
+                for (flag, saved) in flags.iter_mut().zip(saved) {
+                    *flag |= saved;
+                }
+            }
+        }
+        fs::write(path, flags).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn mark(&self, offset: usize, flag: u8) {
+        if let Some(flags) = self.flags.borrow_mut().get_mut(offset) {
+            *flags |= flag;
+        }
+    }
+
+    pub fn in_instruction(&self, addr: u16) -> bool {
+        let (start, end) = self.instruction;
+        addr.wrapping_sub(start) < end.wrapping_sub(start)
+    }
+
+    /// ROM bytes logged as code, data and not logged
+    pub fn summary(&self) -> String {
+        let flags = self.flags.borrow();
+        let amount = |count: usize| format!("{} bytes ({:.2}%)", count, count as f64 * 100.0 / flags.len().max(1) as f64);
+        let code = flags.iter().filter(|flag| *flag & (CODE | OPERAND) != 0).count();
+        let data = flags.iter().filter(|flag| *flag & (DATA | DMA) != 0).count();
+        let unused = flags.iter().filter(|flag| **flag == 0).count();
+        format!("code {}, data {}, not logged {}", amount(code), amount(data), amount(unused))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_merges_with_saved_log() {
+        let path = std::env::temp_dir().join(format!("ruGameboy-coverage-{}.cdl", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let first = Coverage::new(4);
+        first.mark(0, CODE);
+        first.mark(1, OPERAND);
+        first.save(&path).unwrap();
+
+        // another session which does not start from the saved log
+        let second = Coverage::new(4);
+        second.mark(1, DATA);
+        second.mark(3, DMA);
+        second.save(&path).unwrap();
+
+        let saved = fs::read(&path).unwrap();
+        let _ = fs::remove_file(&path);
+        assert_eq!(saved, vec![CODE, OPERAND | DATA, 0, DMA]);
+    }
+
+    #[test]
+    fn load_rejects_log_of_other_size() {
+        let path = std::env::temp_dir().join(format!("ruGameboy-coverage-size-{}.cdl", std::process::id()));
+        fs::write(&path, [0; 4]).unwrap();
+        let result = Coverage::load(&path, 8);
+        let _ = fs::remove_file(&path);
+        assert!(result.is_err());
+    }
+}
@@ -1,8 +1,15 @@
 use log::{debug, info};
 
-use crate::register::Register;
+use crate::register::{Register, FlagRegister};
 use crate::instruction::{Instruction, Target, Condition, CBInstruction};
 use crate::bus::Bus;
+use crate::boot::Model;
+use crate::disasm;
+use crate::callstack::{CallStack, Frame, FrameKind, Recent};
+use crate::savestate::{Snapshot, StateWriter, StateReader};
+
+/// Clocks of a memory access
+const M_CYCLE: u64 = 4;
 
 enum DataSize {
     Byte,
@@ -21,48 +28,140 @@ impl Default for InterruptState {
     fn default() -> Self { InterruptState::IDisable }
 }
 
+impl InterruptState {
+    fn to_u8(self) -> u8 {
+        match self {
+            InterruptState::IDisable     => 0,
+            InterruptState::IEnable      => 1,
+            InterruptState::IDisableNext => 2,
+            InterruptState::IEnableNext  => 3,
+        }
+    }
+
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => InterruptState::IEnable,
+            2 => InterruptState::IDisableNext,
+            3 => InterruptState::IEnableNext,
+            _ => InterruptState::IDisable,
+        }
+    }
+}
+
 pub struct Cpu {
-    regs: Register,
-    sp: u16,
+    pub regs: Register,
+    pub sp: u16,
     pub pc: u16,
     pub bus: Bus,
     interrupt_state: InterruptState,
+    /// STOP mode, CPU halts until a key is pressed
+    stopped: bool,
+    /// calls not returned yet, for backtrace
+    pub calls: CallStack,
+    pub recent: Recent,
+    /// clocks run since power on, for profiling
+    pub cycles: u64,
+    /// clocks of the running instruction already passed to devices
+    ticked: u64,
 }
 
 impl Cpu {
-    pub fn new(binary: Vec<u8>) -> Self {
+    /// Start from boot ROM if given,
+    /// otherwise from catridge with registers left by boot ROM of the model
+    pub fn new(binary: Vec<u8>, model: Model, boot_rom: Option<Vec<u8>>) -> Self {
+        let (regs, pc) = match boot_rom {
+            Some(_) => (Register::default(), 0x0000),
+            None => (model.registers(model.cgb_mode(&binary)), 0x0100), // Starting point of execution
+        };
         Self {
-            regs: Register::default(),
+            regs: regs,
             sp: 0xfffe,
-            pc: 0x0100, // Starting point of execution
-            bus: Bus::new(binary),
+            pc: pc,
+            bus: Bus::new(binary, model, boot_rom),
             interrupt_state: InterruptState::default(),
+            stopped: false,
+            calls: CallStack::default(),
+            recent: Recent::default(),
+            cycles: 0,
+            ticked: 0,
         }
     }
 
-    pub fn fetch(&mut self) -> Result<u16, ()> {
-        let byte = self.load(self.pc, DataSize::Word);
+    pub fn fetch(&mut self) -> Result<u8, ()> {
+        let byte = self.fetch8(self.pc)?;
         self.pc += 1;
-        byte
+        Ok(byte)
     }
 
-    fn load(&self, addr: u16, size: DataSize) -> Result<u16, ()> {
+    /// operand after the opcode, pc is moved past it when instruction runs
+    fn operand(&mut self, size: DataSize) -> Result<u16, ()> {
         match size {
-            DataSize::Byte => self.bus.load8(addr).map(|v| v as u16),
-            DataSize::Word => self.bus.load16(addr),
+            DataSize::Byte => self.fetch8(self.pc).map(|v| v as u16),
+            DataSize::Word => {
+                let lsb = self.fetch8(self.pc)?;
+                let msb = self.fetch8(self.pc.wrapping_add(1))?;
+                Ok(((msb as u16) << 8) | (lsb as u16))
+            },
+        }
+    }
+
+    fn load(&mut self, addr: u16, size: DataSize) -> Result<u16, ()> {
+        match size {
+            DataSize::Byte => self.load8(addr).map(|v| v as u16),
+            DataSize::Word => {
+                let lsb = self.load8(addr)?;
+                let msb = self.load8(addr.wrapping_add(1))?;
+                Ok(((msb as u16) << 8) | (lsb as u16))
+            },
         }
     }
 
     fn store(&mut self, addr: u16, size: DataSize, value: u16) -> Result<(), ()> {
+        self.recent.write(addr, value, matches!(size, DataSize::Word));
         match size {
-            DataSize::Byte => self.bus.store8(addr, value as u8),
-            DataSize::Word => self.bus.store16(addr, value),
+            DataSize::Byte => self.store8(addr, value as u8),
+            DataSize::Word => {
+                self.store8(addr, (value & 0xff) as u8)?;
+                self.store8(addr.wrapping_add(1), (value >> 8) as u8)
+            },
         }
     }
 
+    // each byte accessed takes an M-cycle, which devices run after the access,
+    // so the access sees devices at its own cycle instead of the instruction start
+    fn load8(&mut self, addr: u16) -> Result<u8, ()> {
+        let value = self.bus.load8(addr);
+        self.tick();
+        value
+    }
+
+    fn fetch8(&mut self, addr: u16) -> Result<u8, ()> {
+        let value = self.bus.fetch8(addr);
+        self.tick();
+        value
+    }
+
+    fn store8(&mut self, addr: u16, value: u8) -> Result<(), ()> {
+        let result = self.bus.store8(addr, value);
+        self.tick();
+        result
+    }
+
+    fn tick(&mut self) {
+        self.bus.update(M_CYCLE);
+        self.ticked += M_CYCLE;
+    }
+
+    /// pass clocks of instruction not run by devices yet
+    fn finish_clock(&mut self, clock: u64) {
+        self.bus.update(clock.saturating_sub(self.ticked));
+        self.ticked = 0;
+        self.cycles += clock;
+    }
+
     // helper function for command with operation on register
     // B, C, D, E, H, L, (HL), A, d8
-    fn get_r8(&self, target: &Target) -> Result<u8, ()> {
+    fn get_r8(&mut self, target: &Target) -> Result<u8, ()> {
         match target {
             Target::B  => Ok(self.regs.b),
             Target::C  => Ok(self.regs.c),
@@ -72,7 +171,7 @@ impl Cpu {
             Target::L  => Ok(self.regs.l),
             Target::HL => Ok(self.load(self.regs.get_hl(), DataSize::Byte)? as u8),
             Target::A  => Ok(self.regs.a),
-            Target::D8 => Ok(self.load(self.pc, DataSize::Byte)? as u8),
+            Target::D8 => Ok(self.operand(DataSize::Byte)? as u8),
             _ => {
                 info!("Invalid target for instruction {:?}", target);
                 return Err(());
@@ -111,18 +210,28 @@ impl Cpu {
 
     /// run single command in CPU return the clock length
     pub fn step(&mut self) -> Result<(), ()> {
+        if self.stopped {
+            // keep other devices running so that a frame is still finished
+            if self.bus.joypad.is_pressed() {
+                debug!("Wake up from STOP");
+                self.stopped = false;
+            }
+            self.bus.update(4);
+            self.cycles += 4;
+            return Ok(());
+        }
+
         debug!("{}", self.dump());
+        self.recent.exec(self.pc);
+        self.bus.cover_instruction(self.pc);
         let clock = self.exec_one_instruction()?;
-        self.bus.gpu.update(clock);
-        self.bus.timer.update(clock);
+        self.finish_clock(clock);
 
         // handle interrupt
         if self.interrupt_state == InterruptState::IEnable ||
            self.interrupt_state == InterruptState::IDisableNext {
             let clock = self.handle_interrupt()?;
-
-            self.bus.gpu.update(clock);
-            self.bus.timer.update(clock);
+            self.finish_clock(clock);
         }
 
         // update interrupt state
@@ -141,15 +250,47 @@ impl Cpu {
             debug!("VBlank Interrupt");
             self.bus.gpu.is_interrupt = false;
             self.interrupt_state = InterruptState::IDisable;
-            return self.execute(Instruction::RST(0x40))
+            return self.interrupt(0x40)
+        }
+        // LCD STAT, priority 2
+        if self.bus.interruptenb.lcdc && self.bus.gpu.is_stat_interrupt {
+            debug!("STAT Interrupt");
+            self.bus.gpu.is_stat_interrupt = false;
+            self.interrupt_state = InterruptState::IDisable;
+            return self.interrupt(0x48)
+        }
+        // Timer, priority 3
+        if self.bus.interruptenb.timer && self.bus.timer.is_interrupt {
+            debug!("Timer Interrupt");
+            self.bus.timer.is_interrupt = false;
+            self.interrupt_state = InterruptState::IDisable;
+            return self.interrupt(0x50)
+        }
+        // Joypad, priority 5, lowest
+        if self.bus.interruptenb.joypad && self.bus.joypad.is_interrupt {
+            debug!("Joypad Interrupt");
+            self.bus.joypad.is_interrupt = false;
+            self.interrupt_state = InterruptState::IDisable;
+            return self.interrupt(0x60)
         }
         Ok(0)
     }
 
+    /// jump to interrupt vector like RST
+    fn interrupt(&mut self, vector: u16) -> Result<u64, ()> {
+        let pc = self.pc;
+        let clock = self.execute(Instruction::RST(vector))?;
+        if let Some(frame) = self.calls.frames.back_mut() {
+            frame.kind = FrameKind::Interrupt;
+            frame.from = pc;
+        }
+        Ok(clock)
+    }
+
     fn exec_one_instruction(&mut self) -> Result<u64, ()> {
-        let byte = self.fetch()? as u8;
+        let byte = self.fetch()?;
         if byte == 0xcb {
-            let byte = self.fetch()? as u8;
+            let byte = self.fetch()?;
             // CB instruction is full, should not fail
             let inst = CBInstruction::from_byte(byte);
             self.execute_cb(inst)
@@ -169,9 +310,15 @@ impl Cpu {
         let clock = inst.clock();
         match inst {
             Instruction::NOP => {},
+            Instruction::STOP => {
+                if !self.bus.switch_speed() {
+                    debug!("Enter STOP mode");
+                    self.stopped = true;
+                }
+            },
             Instruction::JP(condition) => {
                 if self.check_condition(&condition) {
-                    let addr = self.load(self.pc, DataSize::Word)?;
+                    let addr = self.operand(DataSize::Word)?;
                     self.pc = addr;
                     return Ok(16);
                 }
@@ -187,7 +334,7 @@ impl Cpu {
                 self.interrupt_state = InterruptState::IEnableNext;
             }
             Instruction::LDIMM16(target) => {
-                let imm = self.load(self.pc, DataSize::Word)?;
+                let imm = self.operand(DataSize::Word)?;
                 match &target {
                     &Target::BC => self.regs.set_bc(imm),
                     &Target::DE => self.regs.set_de(imm),
@@ -200,30 +347,30 @@ impl Cpu {
                 }
             }
             Instruction::LD16A => {
-                let addr = self.load(self.pc, DataSize::Word)?;
+                let addr = self.operand(DataSize::Word)?;
                 self.store(addr, DataSize::Byte, self.regs.a as u16)?;
             }
             Instruction::LDA16 => {
-                let addr = self.load(self.pc, DataSize::Word)?;
+                let addr = self.operand(DataSize::Word)?;
                 self.regs.a = self.load(addr, DataSize::Byte)? as u8;
             }
             Instruction::LDA16SP => {
-                let addr = self.load(self.pc, DataSize::Word)?;
+                let addr = self.operand(DataSize::Word)?;
                 self.store(addr, DataSize::Word, self.sp)?;
             }
             Instruction::LDSPHL => {
                 self.sp = self.regs.get_hl();
             }
             Instruction::LDIMM8(target) => {
-                let imm = self.load(self.pc, DataSize::Byte)? as u8;
+                let imm = self.operand(DataSize::Byte)? as u8;
                 self.set_r8(&target, imm)?;
             }
             Instruction::LD8A => {
-                let addr = 0xff00 + (self.load(self.pc, DataSize::Byte)?);
+                let addr = 0xff00 + (self.operand(DataSize::Byte)?);
                 self.store(addr, DataSize::Byte, self.regs.a as u16)?;
             }
             Instruction::LDA8 => {
-                let addr = 0xff00 + (self.load(self.pc, DataSize::Byte)?);
+                let addr = 0xff00 + (self.operand(DataSize::Byte)?);
                 self.regs.a = self.load(addr, DataSize::Byte)? as u8;
             }
             Instruction::LDCA => {
@@ -331,15 +478,18 @@ impl Cpu {
             }
             Instruction::CALL(condition) => {
                 if self.check_condition(&condition) {
-                    let addr = self.load(self.pc, DataSize::Word)?;
+                    let addr = self.operand(DataSize::Word)?;
                     self.store(self.sp-1, DataSize::Word, self.pc + 2)?;
                     self.sp -= 2;
+                    // PC is after opcode
+                    self.calls.push(Frame { kind: FrameKind::Call, from: self.pc.wrapping_sub(1), to: addr, sp: self.sp });
                     self.pc = addr;
                     return Ok(24);
                 }
             }
             Instruction::RET(condition) => {
                 if self.check_condition(&condition) {
+                    self.calls.ret(self.sp);
                     self.pc = self.load(self.sp + 1, DataSize::Word)?;
                     self.sp += 2;
                     let clock = if condition == Condition::Always { 16 } else { 20 };
@@ -348,6 +498,7 @@ impl Cpu {
             }
             Instruction::RETI => {
                 self.interrupt_state = InterruptState::IEnable;
+                self.calls.ret(self.sp);
                 self.pc = self.load(self.sp + 1, DataSize::Word)?;
                 self.sp += 2;
                 return Ok(clock);
@@ -382,7 +533,7 @@ impl Cpu {
             }
             Instruction::JR(condition) => {
                 if self.check_condition(&condition) {
-                    let offset = self.load(self.pc, DataSize::Byte)? as i8;
+                    let offset = self.operand(DataSize::Byte)? as i8;
                     self.pc = self.pc.wrapping_add(offset as u16);
                     self.pc += len;
                     return Ok(12);
@@ -506,6 +657,7 @@ impl Cpu {
                 // so RST will store PC+1, instead of PC.
                 self.store(self.sp-1, DataSize::Word, self.pc)?;
                 self.sp -= 2;
+                self.calls.push(Frame { kind: FrameKind::Rst, from: self.pc.wrapping_sub(1), to: addr, sp: self.sp });
                 self.pc = addr;
             }
             Instruction::CPL => {
@@ -688,19 +840,148 @@ impl Cpu {
         Ok(clock)
     }
 
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
     pub fn dump(&self) -> String {
         let mut output = String::new();
         output.push_str(&format!("\tPC:{:04X} SP:{:04X}\t", self.pc, self.sp));
         output.push_str(&format!("{}\t", self.regs));
-        let byte = self.load(self.pc, DataSize::Byte).unwrap() as u8;
-        if byte == 0xcb {
-            let byte = self.load(self.pc+1, DataSize::Byte).unwrap() as u8;
-            output.push_str(&format!("byte:{:02X}\t", byte));
-            output.push_str(&format!("inst:{:?}", CBInstruction::from_byte(byte)));
-        } else {
-            output.push_str(&format!("byte:{:02X}\t", byte));
-            output.push_str(&format!("inst:{:?}", Instruction::from_byte(byte)));
-        }
+        let byte = self.bus.peek8(self.pc).unwrap_or(0xff);
+        output.push_str(&format!("byte:{:02X}\t", byte));
+        let line = disasm::decode(self.pc, |addr| self.bus.peek8(addr).unwrap_or(0xff));
+        output.push_str(&format!("inst:{}", line.text));
         output
     }
 }
+
+impl Snapshot for Cpu {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.chunk(b"REGS", |w| {
+            w.u8(self.regs.a);
+            w.u8(u8::from(&self.regs.f));
+            w.u8(self.regs.b);
+            w.u8(self.regs.c);
+            w.u8(self.regs.d);
+            w.u8(self.regs.e);
+            w.u8(self.regs.h);
+            w.u8(self.regs.l);
+            w.u16(self.sp);
+            w.u16(self.pc);
+            w.u8(self.interrupt_state.to_u8());
+            w.bool(self.stopped);
+        });
+        w.chunk(b"BUS ", |w| self.bus.save_state(w));
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        if let Some(mut r) = r.chunk(b"REGS") {
+            self.regs.a = r.u8();
+            self.regs.f = FlagRegister::from(r.u8());
+            self.regs.b = r.u8();
+            self.regs.c = r.u8();
+            self.regs.d = r.u8();
+            self.regs.e = r.u8();
+            self.regs.h = r.u8();
+            self.regs.l = r.u8();
+            self.sp = r.u16();
+            self.pc = r.u16();
+            self.interrupt_state = InterruptState::from_u8(r.u8());
+            self.stopped = r.bool();
+        }
+        if let Some(mut r) = r.chunk(b"BUS ") {
+            self.bus.load_state(&mut r);
+        }
+        // not saved, the stack is no longer that of these calls
+        self.calls.clear();
+        self.recent.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::symbols::Symbols;
+    use crate::watch::{Access, Watchpoint};
+
+    #[test]
+    fn read_watchpoint_skips_fetch() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x104].copy_from_slice(&[0x00, 0xc3, 0x50, 0x01]); // jp $0150
+        rom[0x150..0x158].copy_from_slice(&[
+            0x00,                   // nop
+            0x3e, 0x01,             // ld a, $01
+            0xfa, 0x60, 0x01,       // ld a, [$0160]
+            0x18, 0xfe,             // jr $0156
+        ]);
+        let mut cpu = Cpu::new(rom, Model::DMG, None);
+        let point = Watchpoint::parse("r", "0100-016f", None, &Symbols::default()).unwrap();
+        cpu.bus.watch.points.push(point);
+
+        for _ in 0..4 {
+            cpu.step().unwrap();
+            assert!(cpu.bus.watch.take_hit().is_none(), "read hit on fetch before {:04x}", cpu.pc);
+        }
+        cpu.step().unwrap();
+        let hit = cpu.bus.watch.take_hit().expect("no hit on ld a, [$0160]");
+        assert_eq!((hit.access, hit.addr), (Access::Read, 0x0160));
+    }
+
+    #[test]
+    fn boot_state_follows_cgb_flag() {
+        let mut rom = vec![0; 0x8000];
+        let dmg = Cpu::new(rom.clone(), Model::CGB, None);
+        rom[0x143] = 0x80;
+        let cgb = Cpu::new(rom, Model::CGB, None);
+
+        assert!(!dmg.bus.cgb);
+        assert_eq!((dmg.regs.a, dmg.regs.e, dmg.regs.l), (0x11, 0x08, 0x7c));
+        assert_eq!(dmg.bus.peek8(0xff4d), Ok(0xff)); // KEY1
+        assert_eq!(dmg.bus.peek8(0xff4f), Ok(0xff)); // VBK
+        assert!(cgb.bus.cgb);
+        assert_eq!((cgb.regs.a, cgb.regs.e, cgb.regs.l), (0x11, 0x56, 0x0d));
+        assert_eq!(cgb.bus.peek8(0xff4d), Ok(0x7e)); // KEY1
+        assert_eq!(cgb.bus.peek8(0xff4f), Ok(0xfe)); // VBK
+        assert_eq!(cgb.bus.peek8(0xff55), Ok(0xff)); // HDMA5
+        assert_eq!(cgb.bus.peek8(0xff68), Ok(0xc0)); // BCPS
+        for cpu in [&dmg, &cgb] {
+            assert_eq!(cpu.bus.peek8(0xff48), Ok(0xff)); // OBP0
+            assert_eq!(cpu.bus.peek8(0xff41).map(|stat| stat & 0x78), Ok(0x00)); // STAT
+        }
+    }
+
+    #[test]
+    fn stat_interrupt_on_lyc() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x48..0x4e].copy_from_slice(&[
+            0xf0, 0x44,             // ldh a, [LY]
+            0xe0, 0x80,             // ldh [$ff80], a
+            0x18, 0xfe,             // jr $004c
+        ]);
+        rom[0x100..0x104].copy_from_slice(&[0x00, 0xc3, 0x50, 0x01]); // jp $0150
+        rom[0x150..0x162].copy_from_slice(&[
+            0x3e, 0x02,             // ld a, $02
+            0xe0, 0x45,             // ldh [LYC], a
+            0x3e, 0x40,             // ld a, $40
+            0xe0, 0x41,             // ldh [STAT], a
+            0x3e, 0x02,             // ld a, $02
+            0xe0, 0xff,             // ldh [IE], a
+            0xaf,                   // xor a
+            0xe0, 0x0f,             // ldh [IF], a
+            0xfb,                   // ei
+            0x18, 0xfe,             // jr $0160
+        ]);
+        let mut cpu = Cpu::new(rom, Model::DMG, None);
+        for _ in 0..10000 {
+            if cpu.pc == 0x004c {
+                break;
+            }
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.pc, 0x004c);
+        assert_eq!(cpu.bus.peek8(0xff80), Ok(2));
+        assert_eq!(cpu.bus.peek8(0xff0f).map(|flag| flag & 0x02), Ok(0));
+    }
+}
+
@@ -0,0 +1,605 @@
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use log::{error, info};
+
+use crate::cpu::Cpu;
+use crate::disasm;
+use crate::gdb::GdbStub;
+use crate::register::FlagRegister;
+use crate::symbols::Symbols;
+use crate::trace::Trace;
+use crate::profiler::Profiler;
+use crate::vm::Vm;
+use crate::watch::{self, Access, Compare, Hit, Watchpoint};
+
+/// Executed instructions kept to list before PC
+const HISTORY_LEN: usize = 3;
+/// Instructions listed from PC
+const LIST_LEN: usize = 6;
+/// Bytes dumped by default
+const DUMP_LEN: u16 = 64;
+/// Registers and flags can be shown and set
+const REGISTERS: [&str; 18] = [
+    "a", "b", "c", "d", "e", "f", "h", "l", "af", "bc", "de", "hl", "sp", "pc",
+    "zf", "nf", "hf", "cf",
+];
+
+const HELP: &str = "\
+step [N]             execute N instructions, alias s
+next                 step over CALL and RST, alias n
+continue             run until a breakpoint, alias c
+break ADDR [if COND] break at ADDR, COND is like a==0x10 or zf!=0, alias b
+                     addresses may also be labels of the symbol file
+delete [N]           delete breakpoint N or all, alias d
+breaks               list breakpoints
+backtrace            show calls not returned yet, alias bt
+regs                 show registers and flags, alias r
+set REG VALUE        set register a-l, af, bc, de, hl, sp, pc or flag zf, nf, hf, cf
+x ADDR [LEN]         dump memory
+w ADDR VALUE...      write bytes to memory
+watch KIND RANGE [COND] watch r, w, rw or x access to ADDR[-END], which may
+                     be an IO register like LCDC, COND is like ==0x10
+unwatch [N]          delete watchpoint N or all
+watches              list watchpoints
+list [ADDR] [N]      disassemble around PC or from ADDR, alias l
+quit                 exit emulator, alias q
+Numbers are in hex, empty line repeats the last command";
+
+/// Condition on register value, e.g. a==0x10
+struct BreakCondition {
+    reg: String,
+    op: Compare,
+    value: u16,
+}
+
+impl BreakCondition {
+    fn parse(cond: &str) -> Result<Self, String> {
+        for (name, op) in Compare::OPERATORS.iter() {
+            if let Some(idx) = cond.find(name) {
+                let reg = cond[..idx].trim().to_lowercase();
+                if !REGISTERS.contains(&reg.as_str()) {
+                    return Err(format!("unknown register {}", reg));
+                }
+                let value = crate::parse_addr(cond[idx + name.len()..].trim())?;
+                return Ok(Self { reg: reg, op: *op, value: value });
+            }
+        }
+        Err(format!("expect condition like a==0x10, found {}", cond))
+    }
+
+    fn test(&self, cpu: &Cpu) -> bool {
+        get_reg(cpu, &self.reg).is_some_and(|value| self.op.test(value, self.value))
+    }
+}
+
+struct Breakpoint {
+    addr: u16,
+    cond: Option<BreakCondition>,
+}
+
+impl Breakpoint {
+    fn hit(&self, cpu: &Cpu) -> bool {
+        cpu.pc == self.addr && self.cond.as_ref().is_none_or(|cond| cond.test(cpu))
+    }
+}
+
+enum Mode {
+    /// run until a breakpoint
+    Running,
+    /// break before next instruction
+    Break,
+    /// instructions to execute before break
+    Step(usize),
+    /// break when returned to the instruction after a call
+    Next { ret: u16, sp: u16 },
+}
+
+/// Read commands from stdin when emulation breaks
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    mode: Mode,
+    /// PC of recently executed instructions
+    history: VecDeque<u16>,
+    /// breakpoint at PC is not checked right after resume
+    resumed: bool,
+    last_command: String,
+    /// watchpoint access which caused the last break
+    pub hit: Option<Hit>,
+    /// labels shown in disassembly and accepted as addresses
+    pub symbols: Symbols,
+    /// log of executed instructions
+    pub trace: Option<Trace>,
+    pub profiler: Option<Profiler>,
+    /// remote client which replaces the command line
+    pub gdb: Option<GdbStub>,
+    /// quit emulator
+    pub quit: bool,
+}
+
+impl Debugger {
+    /// break at the first instruction if enabled
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            breakpoints: Vec::new(),
+            mode: if enabled { Mode::Break } else { Mode::Running },
+            history: VecDeque::with_capacity(HISTORY_LEN),
+            resumed: false,
+            last_command: String::new(),
+            hit: None,
+            symbols: Symbols::default(),
+            trace: None,
+            profiler: None,
+            gdb: None,
+            quit: false,
+        }
+    }
+
+    /// break before next instruction
+    pub fn pause(&mut self) {
+        self.mode = Mode::Break;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        matches!(self.mode, Mode::Break)
+    }
+
+    /// run until a breakpoint
+    pub fn resume(&mut self) {
+        self.mode = Mode::Running;
+    }
+
+    /// execute one instruction and break
+    pub fn step(&mut self) {
+        self.mode = Mode::Step(1);
+    }
+
+    pub fn break_at(&mut self, addr: u16) {
+        self.breakpoints.push(Breakpoint { addr: addr, cond: None });
+    }
+
+    /// delete breakpoints at addr
+    pub fn clear_break(&mut self, addr: u16) {
+        self.breakpoints.retain(|b| b.addr != addr);
+    }
+
+    /// Called once a frame, break if remote client asks to,
+    /// run without debugger if it disconnects
+    pub fn poll(&mut self) {
+        if let Some(gdb) = self.gdb.as_mut() {
+            match gdb.interrupted() {
+                Ok(true) => self.mode = Mode::Break,
+                Ok(false) => {},
+                Err(e) => {
+                    info!("gdb: {}, detached", e);
+                    self.gdb = None;
+                },
+            }
+        }
+    }
+
+    /// Called before each instruction, return true to break into command line
+    pub fn should_break(&mut self, cpu: &Cpu) -> bool {
+        let resumed = std::mem::replace(&mut self.resumed, false);
+        // accesses of the last instruction
+        if let Some(hit) = cpu.bus.watch.take_hit() {
+            let pc = self.history.back().copied().unwrap_or(cpu.pc);
+            self.report(cpu, pc, &hit);
+            self.hit = Some(hit);
+            self.mode = Mode::Break;
+            return true;
+        }
+        if !resumed {
+            cpu.bus.watch.check(Access::Execute, cpu.pc, 0, 0);
+            if let Some(hit) = cpu.bus.watch.take_hit() {
+                self.report(cpu, cpu.pc, &hit);
+                self.hit = Some(hit);
+                self.mode = Mode::Break;
+                return true;
+            }
+        }
+        let stop = match self.mode {
+            Mode::Break => true,
+            Mode::Step(0) => true,
+            Mode::Next { ret, sp } if cpu.pc == ret && cpu.sp == sp => true,
+            _ => !resumed && self.breakpoints.iter().any(|b| b.hit(cpu)),
+        };
+        if stop {
+            self.hit = None;
+            self.mode = Mode::Break;
+            return true;
+        }
+        if let Mode::Step(n) = self.mode {
+            self.mode = Mode::Step(n - 1);
+        }
+        if self.history.len() == HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back(cpu.pc);
+        if let Some(trace) = self.trace.as_mut() {
+            if let Err(e) = trace.log(cpu) {
+                error!("trace: {}", e);
+                self.trace = None;
+            }
+        }
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.exec(cpu);
+        }
+        false
+    }
+
+    fn report(&self, cpu: &Cpu, pc: u16, hit: &Hit) {
+        let name = watch::io_name(hit.addr).map_or(String::new(), |name| format!(" ({})", name));
+        let value = match hit.access {
+            Access::Read => format!(" value {:02x}", hit.new),
+            Access::Write => format!(" {:02x} -> {:02x}", hit.old, hit.new),
+            Access::Execute => String::new(),
+        };
+        println!("watchpoint {}: {} {}{}{} by {}  {}",
+                 hit.index, hit.access.name(), self.location(cpu, hit.addr), name, value,
+                 self.location(cpu, pc), self.text(cpu, pc));
+    }
+
+    /// Run commands until emulation is resumed
+    pub fn repl(&mut self, vm: &mut Vm) {
+        if let Some(mut gdb) = self.gdb.take() {
+            match gdb.serve(self, vm) {
+                Ok(true) => self.gdb = Some(gdb),
+                Ok(false) => info!("gdb: detached"),
+                Err(e) => {
+                    error!("gdb: {}, detached", e);
+                    self.mode = Mode::Running;
+                },
+            }
+            self.resumed = true;
+            return;
+        }
+        self.list(&vm.cpu, None, LIST_LEN);
+        while let Mode::Break = self.mode {
+            print!("(gb) ");
+            let _ = io::stdout().flush();
+            let mut line = String::new();
+            match io::stdin().read_line(&mut line) {
+                // stdin closed
+                Ok(0) | Err(_) => {
+                    self.quit = true;
+                    break;
+                },
+                Ok(_) => {},
+            }
+            let line = line.trim();
+            let line = if line.is_empty() { self.last_command.clone() } else { line.to_string() };
+            if let Err(e) = self.execute(vm, &line) {
+                println!("error: {}", e);
+            }
+            self.last_command = line;
+            if self.quit {
+                break;
+            }
+        }
+        self.resumed = true;
+    }
+
+    fn execute(&mut self, vm: &mut Vm, line: &str) -> Result<(), String> {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let cpu = &mut vm.cpu;
+        let number = |idx: usize| -> Result<Option<u16>, String> {
+            args.get(idx).map(|arg| crate::parse_addr(arg)).transpose()
+        };
+        let symbols = &self.symbols;
+        let location = |idx: usize| -> Result<Option<u16>, String> {
+            args.get(idx).map(|arg| symbols.parse_location(arg)).transpose()
+        };
+        match args.as_slice() {
+            [] => {},
+            ["step"] | ["s"] => self.mode = Mode::Step(1),
+            ["step", _] | ["s", _] => {
+                let n = args[1].parse::<usize>().map_err(|_| format!("invalid count {}", args[1]))?;
+                self.mode = Mode::Step(n.max(1));
+            },
+            ["next"] | ["n"] => {
+                let line = decode(cpu, cpu.pc);
+                self.mode = if line.text.starts_with("call") || line.text.starts_with("rst") {
+                    Mode::Next { ret: cpu.pc.wrapping_add(line.len), sp: cpu.sp }
+                } else {
+                    Mode::Step(1)
+                };
+            },
+            ["continue"] | ["c"] => self.mode = Mode::Running,
+            ["break", ..] | ["b", ..] if args.len() >= 2 => {
+                let addr = self.symbols.parse_location(args[1])?;
+                let cond = match args.get(2) {
+                    Some(&"if") => Some(BreakCondition::parse(&args[3..].join(""))?),
+                    Some(arg) => return Err(format!("expect if, found {}", arg)),
+                    None => None,
+                };
+                self.breakpoints.push(Breakpoint { addr: addr, cond: cond });
+                println!("breakpoint {} at {}", self.breakpoints.len() - 1, self.location(cpu, addr));
+            },
+            ["delete"] | ["d"] => self.breakpoints.clear(),
+            ["delete", n] | ["d", n] => {
+                let n = n.parse::<usize>().ok()
+                    .filter(|n| *n < self.breakpoints.len())
+                    .ok_or(format!("no breakpoint {}", n))?;
+                self.breakpoints.remove(n);
+            },
+            ["breaks"] => {
+                for (i, b) in self.breakpoints.iter().enumerate() {
+                    let addr = self.location(cpu, b.addr);
+                    match &b.cond {
+                        Some(c) => println!("{}: {} if {}{}{:#x}", i, addr, c.reg, c.op.name(), c.value),
+                        None => println!("{}: {}", i, addr),
+                    }
+                }
+            },
+            ["backtrace"] | ["bt"] => {
+                for line in self.backtrace(cpu, cpu.pc) {
+                    println!("{}", line);
+                }
+            },
+            ["regs"] | ["r"] => {
+                println!("PC:{:04X} SP:{:04X}{}", cpu.pc, cpu.sp, cpu.regs);
+                println!("{}", cpu.regs.f);
+            },
+            ["set", reg, _] => {
+                let value = number(2)?.unwrap_or(0);
+                set_reg(cpu, &reg.to_lowercase(), value)?;
+            },
+            ["x", ..] if args.len() <= 3 && args.len() >= 2 => {
+                let addr = location(1)?.unwrap_or(0);
+                let len = number(2)?.unwrap_or(DUMP_LEN);
+                for row in (0..len).step_by(16) {
+                    let start = addr.wrapping_add(row);
+                    let bytes: Vec<String> = (0..(len - row).min(16))
+                        .map(|i| match cpu.bus.peek8(start.wrapping_add(i)) {
+                            Ok(byte) => format!("{:02x}", byte),
+                            Err(_) => "??".to_string(),
+                        })
+                        .collect();
+                    println!("{:04x}: {}", start, bytes.join(" "));
+                }
+            },
+            ["w", ..] if args.len() >= 3 => {
+                let addr = location(1)?.unwrap_or(0);
+                // written by user, not by the program, so neither blocked nor watched
+                for (i, arg) in args[2..].iter().enumerate() {
+                    let value = crate::parse_addr(arg)?;
+                    let addr = addr.wrapping_add(i as u16);
+                    cpu.bus.poke8(addr, value as u8).map_err(|_| format!("fail to write {:04x}", addr))?;
+                }
+            },
+            ["watch", kind, range] => {
+                cpu.bus.watch.points.push(Watchpoint::parse(kind, range, None, &self.symbols)?);
+                println!("watchpoint {}", cpu.bus.watch.points.len() - 1);
+            },
+            ["watch", kind, range, ..] => {
+                let cond = args[3..].join("");
+                cpu.bus.watch.points.push(Watchpoint::parse(kind, range, Some(&cond), &self.symbols)?);
+                println!("watchpoint {}", cpu.bus.watch.points.len() - 1);
+            },
+            ["unwatch"] => cpu.bus.watch.points.clear(),
+            ["unwatch", n] => {
+                let n = n.parse::<usize>().ok()
+                    .filter(|n| *n < cpu.bus.watch.points.len())
+                    .ok_or(format!("no watchpoint {}", n))?;
+                cpu.bus.watch.points.remove(n);
+            },
+            ["watches"] => {
+                for (i, w) in cpu.bus.watch.points.iter().enumerate() {
+                    let kind = format!("{}{}{}", if w.read { "r" } else { "" },
+                                       if w.write { "w" } else { "" }, if w.execute { "x" } else { "" });
+                    let cond = w.cond.map_or(String::new(), |(op, value)| format!(" {}{:#x}", op.name(), value));
+                    println!("{}: {} {:04x}-{:04x}{}", i, kind, w.start, w.end, cond);
+                }
+            },
+            ["list"] | ["l"] => self.list(cpu, None, LIST_LEN),
+            ["list", ..] | ["l", ..] if args.len() <= 3 => {
+                let addr = location(1)?;
+                let len = args.get(2).map_or(Ok(LIST_LEN), |n| n.parse::<usize>())
+                    .map_err(|_| format!("invalid count {}", args[2]))?;
+                self.list(cpu, addr, len);
+            },
+            ["quit"] | ["q"] => self.quit = true,
+            ["help"] | ["h"] => println!("{}", HELP),
+            _ => return Err(format!("unknown command {}, type help for commands", line)),
+        }
+        Ok(())
+    }
+
+    /// disassemble from addr, or recently executed instructions and those from PC
+    fn list(&self, cpu: &Cpu, addr: Option<u16>, len: usize) {
+        let mut addrs: Vec<u16> = match addr {
+            Some(_) => Vec::new(),
+            None => self.history.iter().copied().collect(),
+        };
+        let mut addr = addr.unwrap_or(cpu.pc);
+        for _ in 0..len {
+            addrs.push(addr);
+            addr = addr.wrapping_add(decode(cpu, addr).len);
+        }
+        for addr in addrs {
+            if let Some(label) = self.symbols.label(cpu.bus.bank(addr), addr) {
+                println!("{}:", label);
+            }
+            let mark = if addr == cpu.pc { "=>" } else { "  " };
+            println!("{} {:04x}  {}", mark, addr, self.text(cpu, addr));
+        }
+    }
+
+    /// frames of call stack from the innermost, pc is the current instruction
+    fn backtrace(&self, cpu: &Cpu, pc: u16) -> Vec<String> {
+        let mut lines = vec![format!("#0  {}", self.location(cpu, pc))];
+        for (i, frame) in cpu.calls.frames.iter().rev().enumerate() {
+            lines.push(format!("#{}  {}  {} {}", i + 1, self.location(cpu, frame.from),
+                               frame.kind.name(), self.location(cpu, frame.to)));
+        }
+        lines
+    }
+
+    /// Print how the program got to an instruction which fails: backtrace,
+    /// recently executed instructions and recent writes
+    pub fn report_crash(&self, cpu: &Cpu) {
+        let pc = cpu.recent.pcs.back().copied().unwrap_or(cpu.pc);
+        eprintln!("backtrace:");
+        for line in self.backtrace(cpu, pc) {
+            eprintln!("  {}", line);
+        }
+        eprintln!("last executed:");
+        for addr in cpu.recent.pcs.iter() {
+            let mark = if *addr == pc { "=>" } else { "  " };
+            eprintln!("{} {}  {}", mark, self.location(cpu, *addr), self.text(cpu, *addr));
+        }
+        eprintln!("recent writes:");
+        for write in cpu.recent.writes.iter() {
+            let value = if write.word { format!("{:04x}", write.value) } else { format!("{:02x}", write.value) };
+            eprintln!("  [{}] <- {} by {}", self.location(cpu, write.addr), value, self.location(cpu, write.pc));
+        }
+    }
+
+    /// disassembly of instruction at addr, with labels as operands
+    fn text(&self, cpu: &Cpu, addr: u16) -> String {
+        self.symbols.annotate(&decode(cpu, addr).text, |addr| cpu.bus.bank(addr))
+    }
+
+    /// address in hex, followed by label if any, e.g. 0153 <Main.loop+1>
+    pub fn location(&self, cpu: &Cpu, addr: u16) -> String {
+        match self.symbols.describe(cpu.bus.bank(addr), addr) {
+            Some(name) => format!("{:04x} <{}>", addr, name),
+            None => format!("{:04x}", addr),
+        }
+    }
+}
+
+fn decode(cpu: &Cpu, addr: u16) -> disasm::Line {
+    disasm::decode(addr, |addr| cpu.bus.peek8(addr).unwrap_or(0xff))
+}
+
+/// value of register or flag by name
+pub fn get_reg(cpu: &Cpu, name: &str) -> Option<u16> {
+    let regs = &cpu.regs;
+    Some(match name {
+        "a" => regs.a as u16,
+        "b" => regs.b as u16,
+        "c" => regs.c as u16,
+        "d" => regs.d as u16,
+        "e" => regs.e as u16,
+        "f" => u8::from(&regs.f) as u16,
+        "h" => regs.h as u16,
+        "l" => regs.l as u16,
+        "af" => regs.get_af(),
+        "bc" => regs.get_bc(),
+        "de" => regs.get_de(),
+        "hl" => regs.get_hl(),
+        "sp" => cpu.sp,
+        "pc" => cpu.pc,
+        "zf" => regs.f.zero as u16,
+        "nf" => regs.f.subtract as u16,
+        "hf" => regs.f.half_carry as u16,
+        "cf" => regs.f.carry as u16,
+        _ => return None,
+    })
+}
+
+pub fn set_reg(cpu: &mut Cpu, name: &str, value: u16) -> Result<(), String> {
+    let regs = &mut cpu.regs;
+    match name {
+        "a" => regs.a = value as u8,
+        "b" => regs.b = value as u8,
+        "c" => regs.c = value as u8,
+        "d" => regs.d = value as u8,
+        "e" => regs.e = value as u8,
+        "f" => regs.f = FlagRegister::from(value as u8),
+        "h" => regs.h = value as u8,
+        "l" => regs.l = value as u8,
+        "af" => regs.set_af(value),
+        "bc" => regs.set_bc(value),
+        "de" => regs.set_de(value),
+        "hl" => regs.set_hl(value),
+        "sp" => cpu.sp = value,
+        "pc" => cpu.pc = value,
+        "zf" => regs.f.zero = value != 0,
+        "nf" => regs.f.subtract = value != 0,
+        "hf" => regs.f.half_carry = value != 0,
+        "cf" => regs.f.carry = value != 0,
+        _ => return Err(format!("unknown register {}", name)),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::boot::Model;
+
+    fn vm() -> Vm {
+        Vm::new(vec![0; 0x8000], Model::DMG, None)
+    }
+
+    #[test]
+    fn break_condition_parse() {
+        let cases = [
+            ("a==10", "a", Compare::Eq, 0x10),
+            ("HL != 0xc000", "hl", Compare::Ne, 0xc000),
+            ("b<=$ff", "b", Compare::Le, 0xff),
+            ("sp>=dff0", "sp", Compare::Ge, 0xdff0),
+            ("c<3", "c", Compare::Lt, 0x3),
+            ("zf>0", "zf", Compare::Gt, 0x0),
+        ];
+        for (text, reg, op, value) in cases {
+            let cond = BreakCondition::parse(text).unwrap();
+            assert_eq!((cond.reg.as_str(), cond.op, cond.value), (reg, op, value), "{}", text);
+        }
+        assert!(BreakCondition::parse("x==1").is_err());
+        assert!(BreakCondition::parse("a==zz").is_err());
+        assert!(BreakCondition::parse("a").is_err());
+    }
+
+    #[test]
+    fn break_condition_test() {
+        let mut vm = vm();
+        vm.cpu.regs.a = 0x10;
+        assert!(BreakCondition::parse("a==10").unwrap().test(&vm.cpu));
+        assert!(!BreakCondition::parse("a!=10").unwrap().test(&vm.cpu));
+        assert!(BreakCondition::parse("a<=10").unwrap().test(&vm.cpu));
+        assert!(!BreakCondition::parse("a<10").unwrap().test(&vm.cpu));
+        assert!(BreakCondition::parse("a>f").unwrap().test(&vm.cpu));
+    }
+
+    #[test]
+    fn registers() {
+        let mut vm = vm();
+        for (i, reg) in ["a", "b", "c", "d", "e", "h", "l"].iter().enumerate() {
+            set_reg(&mut vm.cpu, reg, 0x10 + i as u16).unwrap();
+        }
+        assert_eq!(get_reg(&vm.cpu, "bc"), Some(0x1112));
+        assert_eq!(get_reg(&vm.cpu, "de"), Some(0x1314));
+        assert_eq!(get_reg(&vm.cpu, "hl"), Some(0x1516));
+
+        set_reg(&mut vm.cpu, "de", 0xbeef).unwrap();
+        assert_eq!((get_reg(&vm.cpu, "d"), get_reg(&vm.cpu, "e")), (Some(0xbe), Some(0xef)));
+        set_reg(&mut vm.cpu, "sp", 0xdff0).unwrap();
+        set_reg(&mut vm.cpu, "pc", 0x0150).unwrap();
+        assert_eq!((vm.cpu.sp, vm.cpu.pc), (0xdff0, 0x0150));
+
+        // lower 4 bits of F are always 0
+        set_reg(&mut vm.cpu, "af", 0x12ff).unwrap();
+        assert_eq!(get_reg(&vm.cpu, "af"), Some(0x12f0));
+        set_reg(&mut vm.cpu, "zf", 0).unwrap();
+        set_reg(&mut vm.cpu, "hf", 0).unwrap();
+        assert_eq!(get_reg(&vm.cpu, "f"), Some(0x50));
+        assert_eq!((get_reg(&vm.cpu, "nf"), get_reg(&vm.cpu, "cf")), (Some(1), Some(1)));
+
+        assert!(set_reg(&mut vm.cpu, "ix", 0).is_err());
+        assert_eq!(get_reg(&vm.cpu, "ix"), None);
+    }
+
+    #[test]
+    fn write_is_not_blocked_nor_watched() {
+        let (mut debugger, mut vm) = (Debugger::new(false), vm());
+        debugger.execute(&mut vm, "watch w c000").unwrap();
+        // CPU can only access HRAM during OAM DMA
+        vm.cpu.bus.store8(0xff46, 0xc1).unwrap();
+        debugger.execute(&mut vm, "w c000 5a a5").unwrap();
+        assert_eq!((vm.cpu.bus.peek8(0xc000), vm.cpu.bus.peek8(0xc001)), (Ok(0x5a), Ok(0xa5)));
+        assert!(vm.cpu.bus.watch.take_hit().is_none());
+    }
+}
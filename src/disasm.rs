@@ -0,0 +1,306 @@
+use crate::symbols::Symbols;
+
+/// Size of a ROM bank, bank 0 is mapped at 0x0000-0x3FFF
+/// and the others are switched in at 0x4000-0x7FFF
+pub const ROM_BANK_SIZE: usize = 0x4000;
+
+const R8: [&str; 8] = ["b", "c", "d", "e", "h", "l", "[hl]", "a"];
+const R16: [&str; 4] = ["bc", "de", "hl", "sp"];
+/// PUSH and POP use AF instead of SP
+const R16_STACK: [&str; 4] = ["bc", "de", "hl", "af"];
+const R16_MEM: [&str; 4] = ["[bc]", "[de]", "[hl+]", "[hl-]"];
+const CONDITION: [&str; 4] = ["nz", "z", "nc", "c"];
+const ALU: [&str; 8] = ["add a, ", "adc a, ", "sub ", "sbc a, ", "and ", "xor ", "or ", "cp "];
+const ACCUMULATOR: [&str; 8] = ["rlca", "rrca", "rla", "rra", "daa", "cpl", "scf", "ccf"];
+const ROTATE: [&str; 8] = ["rlc", "rrc", "rl", "rr", "sla", "sra", "swap", "srl"];
+
+/// A decoded instruction
+#[derive(Debug,Clone,PartialEq)]
+pub struct Line {
+    pub addr: u16,
+    /// bytes of opcode and operands
+    pub len: u16,
+    /// mnemonic in RGBDS syntax, e.g. "ld a, [$c000]"
+    pub text: String,
+}
+
+/// Decode the SM83 instruction at addr, read gives the byte at an address.
+/// Relative jumps show their target address, unused opcodes are shown as
+/// data bytes.
+pub fn decode<F: Fn(u16) -> u8>(addr: u16, read: F) -> Line {
+    let op = read(addr);
+    let n8 = || read(addr.wrapping_add(1));
+    let n16 = || read(addr.wrapping_add(1)) as u16 | (read(addr.wrapping_add(2)) as u16) << 8;
+    // target of relative jump, from the end of the instruction
+    let e8 = || addr.wrapping_add(2).wrapping_add(n8() as i8 as u16);
+    // signed offset added to SP
+    let sp_offset = || {
+        let e = n8() as i8;
+        if e < 0 { format!("-${:02x}", -(e as i16)) } else { format!("${:02x}", e) }
+    };
+
+    let x = op >> 6;
+    let y = ((op >> 3) & 0x7) as usize;
+    let z = (op & 0x7) as usize;
+    let p = y >> 1;
+    let q = y & 0x1;
+
+    let (len, text) = match (x, z) {
+        (0, 0) => match y {
+            0 => (1, "nop".to_string()),
+            1 => (3, format!("ld [${:04x}], sp", n16())),
+            // STOP is followed by a byte which is ignored
+            2 => (2, "stop".to_string()),
+            3 => (2, format!("jr ${:04x}", e8())),
+            _ => (2, format!("jr {}, ${:04x}", CONDITION[y - 4], e8())),
+        },
+        (0, 1) if q == 0 => (3, format!("ld {}, ${:04x}", R16[p], n16())),
+        (0, 1) => (1, format!("add hl, {}", R16[p])),
+        (0, 2) if q == 0 => (1, format!("ld {}, a", R16_MEM[p])),
+        (0, 2) => (1, format!("ld a, {}", R16_MEM[p])),
+        (0, 3) if q == 0 => (1, format!("inc {}", R16[p])),
+        (0, 3) => (1, format!("dec {}", R16[p])),
+        (0, 4) => (1, format!("inc {}", R8[y])),
+        (0, 5) => (1, format!("dec {}", R8[y])),
+        (0, 6) => (2, format!("ld {}, ${:02x}", R8[y], n8())),
+        (0, 7) => (1, ACCUMULATOR[y].to_string()),
+        (1, _) if op == 0x76 => (1, "halt".to_string()),
+        (1, _) => (1, format!("ld {}, {}", R8[y], R8[z])),
+        (2, _) => (1, format!("{}{}", ALU[y], R8[z])),
+        (3, 0) => match y {
+            0 ..= 3 => (1, format!("ret {}", CONDITION[y])),
+            4 => (2, format!("ldh [$ff{:02x}], a", n8())),
+            5 => (2, format!("add sp, {}", sp_offset())),
+            6 => (2, format!("ldh a, [$ff{:02x}]", n8())),
+            _ => (2, format!("ld hl, sp + {}", sp_offset())),
+        },
+        (3, 1) if q == 0 => (1, format!("pop {}", R16_STACK[p])),
+        (3, 1) => (1, ["ret", "reti", "jp hl", "ld sp, hl"][p].to_string()),
+        (3, 2) => match y {
+            0 ..= 3 => (3, format!("jp {}, ${:04x}", CONDITION[y], n16())),
+            4 => (1, "ldh [c], a".to_string()),
+            5 => (3, format!("ld [${:04x}], a", n16())),
+            6 => (1, "ldh a, [c]".to_string()),
+            _ => (3, format!("ld a, [${:04x}]", n16())),
+        },
+        (3, 3) => match y {
+            0 => (3, format!("jp ${:04x}", n16())),
+            1 => return decode_cb(addr, n8()),
+            6 => (1, "di".to_string()),
+            7 => (1, "ei".to_string()),
+            _ => (1, format!("db ${:02x}", op)),
+        },
+        (3, 4) if y < 4 => (3, format!("call {}, ${:04x}", CONDITION[y], n16())),
+        (3, 5) if q == 0 => (1, format!("push {}", R16_STACK[p])),
+        (3, 5) if p == 0 => (3, format!("call ${:04x}", n16())),
+        (3, 6) => (2, format!("{}${:02x}", ALU[y], n8())),
+        (3, 7) => (1, format!("rst ${:02x}", y * 8)),
+        _ => (1, format!("db ${:02x}", op)),
+    };
+    Line { addr: addr, len: len, text: text }
+}
+
+/// Decode instruction prefixed by 0xCB
+fn decode_cb(addr: u16, op: u8) -> Line {
+    let y = ((op >> 3) & 0x7) as usize;
+    let z = (op & 0x7) as usize;
+    let text = match op >> 6 {
+        0 => format!("{} {}", ROTATE[y], R8[z]),
+        1 => format!("bit {}, {}", y, R8[z]),
+        2 => format!("res {}, {}", y, R8[z]),
+        _ => format!("set {}, {}", y, R8[z]),
+    };
+    Line { addr: addr, len: 2, text: text }
+}
+
+/// Disassemble count instructions of a ROM bank from addr,
+/// stop at the end of the bank window. Each line shows bank, address,
+/// bytes and mnemonic, e.g. "00:0150  3e 01     ld a, $01". Labels of
+/// symbols are shown on their own line and replace operands.
+pub fn disassemble(rom: &[u8], bank: usize, addr: u16, count: usize,
+                   symbols: &Symbols) -> Result<Vec<String>, String> {
+    let (window, offset) = if bank == 0 { (0x0000, 0) } else { (ROM_BANK_SIZE, bank * ROM_BANK_SIZE) };
+    let end = window + ROM_BANK_SIZE;
+    if offset >= rom.len() {
+        return Err(format!("bank {} is out of ROM of {} banks", bank, rom.len().div_ceil(ROM_BANK_SIZE)));
+    }
+    if (addr as usize) < window || (addr as usize) >= end {
+        return Err(format!("address {:#06x} is out of bank {} at {:#06x}-{:#06x}", addr, bank, window, end - 1));
+    }
+    let read = |addr: u16| {
+        let idx = (addr as usize).wrapping_sub(window).wrapping_add(offset);
+        rom.get(idx).copied().unwrap_or(0xff)
+    };
+
+    // banks of operands, other ROM banks are not mapped
+    let bank_of = |addr: u16| if (addr as usize) < ROM_BANK_SIZE { 0 } else { bank as u16 };
+
+    let mut lines = Vec::new();
+    let mut decoded = 0;
+    let mut addr = addr as usize;
+    while decoded < count && addr < end && addr - window + offset < rom.len() {
+        let line = decode(addr as u16, read);
+        if let Some(label) = symbols.label(bank as u16, line.addr) {
+            lines.push(format!("{}:", label));
+        }
+        let bytes: Vec<String> = (0..line.len)
+            .map(|i| format!("{:02x}", read(line.addr.wrapping_add(i))))
+            .collect();
+        let text = symbols.annotate(&line.text, bank_of);
+        lines.push(format!("{:02x}:{:04x}  {:<10}{}", bank, line.addr, bytes.join(" "), text));
+        decoded += 1;
+        addr += line.len as usize;
+    }
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::{CBInstruction, Condition, Instruction, Target};
+
+    /// operand of CB instruction in RGBDS syntax
+    fn r8(target: &Target) -> &'static str {
+        match target {
+            Target::B => "b",
+            Target::C => "c",
+            Target::D => "d",
+            Target::E => "e",
+            Target::H => "h",
+            Target::L => "l",
+            Target::HL => "[hl]",
+            Target::A => "a",
+            _ => panic!("{:?} is not an operand of CB instruction", target),
+        }
+    }
+
+    fn cb_text(inst: &CBInstruction) -> String {
+        match inst {
+            CBInstruction::RLC(t) => format!("rlc {}", r8(t)),
+            CBInstruction::RRC(t) => format!("rrc {}", r8(t)),
+            CBInstruction::RL(t) => format!("rl {}", r8(t)),
+            CBInstruction::RR(t) => format!("rr {}", r8(t)),
+            CBInstruction::SLA(t) => format!("sla {}", r8(t)),
+            CBInstruction::SRA(t) => format!("sra {}", r8(t)),
+            CBInstruction::SWAP(t) => format!("swap {}", r8(t)),
+            CBInstruction::SRL(t) => format!("srl {}", r8(t)),
+            CBInstruction::BIT(t, bit) => format!("bit {}, {}", bit, r8(t)),
+            CBInstruction::RES(t, bit) => format!("res {}, {}", bit, r8(t)),
+            CBInstruction::SET(t, bit) => format!("set {}, {}", bit, r8(t)),
+        }
+    }
+
+    /// 8 bits operand of LD and ALU instructions, immediate is decoded as 0
+    fn operand(target: &Target) -> &'static str {
+        match target {
+            Target::BC => "[bc]",
+            Target::DE => "[de]",
+            Target::HLINC => "[hl+]",
+            Target::HLDEC => "[hl-]",
+            Target::D8 => "$00",
+            _ => r8(target),
+        }
+    }
+
+    fn r16(target: &Target) -> &'static str {
+        match target {
+            Target::AF => "af",
+            Target::BC => "bc",
+            Target::DE => "de",
+            Target::HL => "hl",
+            Target::SP => "sp",
+            _ => panic!("{:?} is not a 16 bits register", target),
+        }
+    }
+
+    /// condition followed by comma, empty if always taken
+    fn cc(condition: &Condition) -> &'static str {
+        match condition {
+            Condition::NotZero => "nz, ",
+            Condition::Zero => "z, ",
+            Condition::NotCarry => "nc, ",
+            Condition::Carry => "c, ",
+            Condition::Always => "",
+        }
+    }
+
+    /// mnemonic of instruction at $c000 with operand bytes of 0
+    fn text(inst: &Instruction) -> String {
+        match inst {
+            Instruction::NOP => "nop".to_string(),
+            Instruction::STOP => "stop".to_string(),
+            Instruction::JP(c) => format!("jp {}$0000", cc(c)),
+            Instruction::JPHL => "jp hl".to_string(),
+            Instruction::DI => "di".to_string(),
+            Instruction::EI => "ei".to_string(),
+            Instruction::LDIMM16(t) => format!("ld {}, $0000", r16(t)),
+            Instruction::LDIMM8(t) => format!("ld {}, $00", r8(t)),
+            Instruction::LD16A => "ld [$0000], a".to_string(),
+            Instruction::LDA16 => "ld a, [$0000]".to_string(),
+            Instruction::LD8A => "ldh [$ff00], a".to_string(),
+            Instruction::LDA8 => "ldh a, [$ff00]".to_string(),
+            Instruction::LDA16SP => "ld [$0000], sp".to_string(),
+            Instruction::LDSPHL => "ld sp, hl".to_string(),
+            Instruction::LDCA => "ldh [c], a".to_string(),
+            Instruction::LDAC => "ldh a, [c]".to_string(),
+            Instruction::LDRR(source, target) => format!("ld {}, {}", operand(target), operand(source)),
+            Instruction::CALL(c) => format!("call {}$0000", cc(c)),
+            Instruction::RET(Condition::Always) => "ret".to_string(),
+            Instruction::RET(c) => format!("ret {}", cc(c).trim_end_matches(", ")),
+            Instruction::RETI => "reti".to_string(),
+            Instruction::PUSH(t) => format!("push {}", r16(t)),
+            Instruction::POP(t) => format!("pop {}", r16(t)),
+            // relative to the end of instruction
+            Instruction::JR(c) => format!("jr {}$c002", cc(c)),
+            Instruction::INC16(t) => format!("inc {}", r16(t)),
+            Instruction::DEC16(t) => format!("dec {}", r16(t)),
+            Instruction::INC8(t) => format!("inc {}", r8(t)),
+            Instruction::DEC8(t) => format!("dec {}", r8(t)),
+            Instruction::ADD(t) => format!("add a, {}", operand(t)),
+            Instruction::ADDHL(t) => format!("add hl, {}", r16(t)),
+            Instruction::ADC(t) => format!("adc a, {}", operand(t)),
+            Instruction::SUB(t) => format!("sub {}", operand(t)),
+            Instruction::SBC(t) => format!("sbc a, {}", operand(t)),
+            Instruction::AND(t) => format!("and {}", operand(t)),
+            Instruction::XOR(t) => format!("xor {}", operand(t)),
+            Instruction::OR(t) => format!("or {}", operand(t)),
+            Instruction::CMP(t) => format!("cp {}", operand(t)),
+            Instruction::RST(vector) => format!("rst ${:02x}", vector),
+            Instruction::CPL => "cpl".to_string(),
+            Instruction::CCF => "ccf".to_string(),
+            Instruction::RRA => "rra".to_string(),
+            Instruction::DAA => "daa".to_string(),
+            Instruction::RLCA => "rlca".to_string(),
+        }
+    }
+
+    /// SM83 instructions the CPU does not run yet, decoded all the same
+    const NOT_RUN: [u8; 6] = [0x0f, 0x17, 0x37, 0x76, 0xe8, 0xf8];
+
+    #[test]
+    fn decode_matches_cpu_length() {
+        for op in 0x00..=0xffu8 {
+            if op == 0xcb {
+                continue;
+            }
+            let line = decode(0xc000, |addr| if addr == 0xc000 { op } else { 0x00 });
+            match Instruction::from_byte(op) {
+                Some(inst) => {
+                    assert_eq!(line.len, 1 + inst.len(), "length of {:02x} {}", op, line.text);
+                    assert_eq!(line.text, text(&inst), "{:02x}", op);
+                },
+                None if NOT_RUN.contains(&op) => assert!(!line.text.starts_with("db "), "{:02x} is an instruction", op),
+                None => assert_eq!(line.text, format!("db ${:02x}", op), "{:02x} is not an instruction", op),
+            }
+        }
+    }
+
+    #[test]
+    fn decode_cb_matches_cpu() {
+        for op in 0x00..=0xffu8 {
+            let line = decode(0xc000, |addr| if addr == 0xc000 { 0xcb } else { op });
+            assert_eq!(line.len, 2, "length of cb {:02x}", op);
+            assert_eq!(line.text, cb_text(&CBInstruction::from_byte(op)), "cb {:02x}", op);
+        }
+    }
+}
@@ -0,0 +1,225 @@
+use std::ops::Range;
+use crate::savestate::{Snapshot, StateWriter, StateReader};
+
+/// Bytes copied by OAM DMA, 40 sprites * 4 bytes
+pub const OAM_DMA_LEN: u16 = 40 * 4;
+/// Clock to copy a byte, 1 M-cycle
+const OAM_DMA_BYTE_CLOCK: u64 = 4;
+/// Clock from writing DMA register to the first byte copied
+const OAM_DMA_DELAY: u64 = 4;
+
+/// OAM DMA, copy 160 bytes to OAM 0xFE00-0xFE9F in 160 M-cycles
+/// the source address can be designated every 0x100 from 0x0000 to 0xDF00,
+/// depend on the value stored to dma IO line:
+/// 0x00 -> 0x0000
+/// 0x01 -> 0x0100
+/// ...
+/// source above 0xDFFF is mirrored to 0xC000-0xDFFF like echo RAM
+#[derive(Default)]
+pub struct OamDma {
+    /// last value written to DMA register
+    pub register: u8,
+    /// transfer is running, CPU can only access HRAM
+    active: bool,
+    /// bytes already copied
+    index: u16,
+    /// clock accumulated for next byte
+    clock: u64,
+    /// clock remaining before the first byte is copied
+    delay: u64,
+}
+
+impl OamDma {
+    pub fn start(&mut self, value: u8) {
+        self.register = value;
+        self.active = true;
+        self.index = 0;
+        self.clock = 0;
+        self.delay = OAM_DMA_DELAY;
+    }
+
+    /// whether CPU bus is taken by DMA
+    pub fn is_active(&self) -> bool {
+        self.active && self.delay == 0
+    }
+
+    /// source address of the byte in transfer
+    pub fn source(&self, idx: u16) -> u16 {
+        let addr = ((self.register as u16) << 8) + idx;
+        if addr >= 0xe000 {
+            addr - 0x2000
+        } else {
+            addr
+        }
+    }
+
+    /// pass clock, return index range of bytes should be copied
+    pub fn update(&mut self, clock: u64) -> Range<u16> {
+        let start = self.index;
+        if !self.active {
+            return start..start;
+        }
+
+        let mut clock = clock;
+        if self.delay > 0 {
+            let passed = clock.min(self.delay);
+            self.delay -= passed;
+            clock -= passed;
+        }
+
+        self.clock += clock;
+        let count = (self.clock / OAM_DMA_BYTE_CLOCK) as u16;
+        self.clock %= OAM_DMA_BYTE_CLOCK;
+        self.index = (self.index + count).min(OAM_DMA_LEN);
+        if self.index == OAM_DMA_LEN {
+            self.active = false;
+        }
+        start..self.index
+    }
+}
+
+/// Bytes copied by HDMA at a time
+pub const HDMA_BLOCK_LEN: u16 = 0x10;
+/// Clock CPU is stalled for a block in normal speed, doubled in double speed
+const HDMA_BLOCK_CLOCK: u64 = 32;
+
+/// CGB VRAM DMA, copy blocks of 16 bytes from ROM or RAM to VRAM
+/// HDMA1, HDMA2: source address, lower 4 bits ignored
+/// HDMA3, HDMA4: destination in VRAM, upper 3 bits and lower 4 bits ignored
+/// HDMA5: bit 7 selects general purpose (0) or HBlank (1) transfer,
+///        bit 0-6 are blocks to copy minus 1
+/// General purpose DMA copies all blocks at once and CPU is stalled,
+/// HBlank DMA copies a block on every HBlank
+#[derive(Default)]
+pub struct Hdma {
+    source: u16,
+    dest: u16,
+    /// blocks remaining
+    blocks: u8,
+    /// general purpose transfer is requested
+    general: bool,
+    /// HBlank transfer is running
+    hblank: bool,
+}
+
+impl Hdma {
+    pub fn store_source_high(&mut self, value: u8) {
+        self.source = (self.source & 0x00ff) | (value as u16) << 8;
+    }
+
+    pub fn store_source_low(&mut self, value: u8) {
+        self.source = (self.source & 0xff00) | (value & 0xf0) as u16;
+    }
+
+    pub fn store_dest_high(&mut self, value: u8) {
+        self.dest = (self.dest & 0x00ff) | ((value & 0x1f) as u16) << 8;
+    }
+
+    pub fn store_dest_low(&mut self, value: u8) {
+        self.dest = (self.dest & 0xff00) | (value & 0xf0) as u16;
+    }
+
+    /// HDMA5, bit 7 is 0 if HBlank transfer is running,
+    /// bit 0-6 are remaining blocks minus 1, 0xff when finished
+    pub fn load_control(&self) -> u8 {
+        let remain = self.blocks.wrapping_sub(1) & 0x7f;
+        if self.hblank {
+            remain
+        } else {
+            0x80 | remain
+        }
+    }
+
+    /// writing bit 7 = 0 during HBlank transfer cancels it
+    pub fn store_control(&mut self, value: u8) {
+        if self.hblank && value & 0x80 == 0 {
+            self.hblank = false;
+            return;
+        }
+        self.blocks = (value & 0x7f) + 1;
+        if value & 0x80 != 0 {
+            self.hblank = true;
+        } else {
+            self.general = true;
+        }
+    }
+
+    /// clock CPU is stalled for a block
+    pub fn block_clock(double_speed: bool) -> u64 {
+        if double_speed {
+            HDMA_BLOCK_CLOCK * 2
+        } else {
+            HDMA_BLOCK_CLOCK
+        }
+    }
+
+    /// blocks should be copied now, all blocks for general purpose transfer,
+    /// one block if HBlank is entered during HBlank transfer
+    pub fn pending_blocks(&mut self, hblank_entered: bool) -> u8 {
+        if self.general {
+            self.general = false;
+            self.blocks
+        } else if self.hblank && hblank_entered {
+            1
+        } else {
+            0
+        }
+    }
+
+    /// pop a block, return its source and destination address,
+    /// None if the transfer is finished
+    pub fn next_block(&mut self) -> Option<(u16, u16)> {
+        if self.blocks == 0 {
+            return None;
+        }
+        let block = (self.source, 0x8000 | self.dest);
+        self.source = self.source.wrapping_add(HDMA_BLOCK_LEN);
+        self.dest += HDMA_BLOCK_LEN;
+        self.blocks -= 1;
+        // transfer stops at the end of VRAM instead of wrapping to 0x8000
+        if self.dest > 0x1ff0 {
+            self.dest &= 0x1ff0;
+            self.blocks = 0;
+        }
+        if self.blocks == 0 {
+            self.hblank = false;
+        }
+        Some(block)
+    }
+}
+
+impl Snapshot for OamDma {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.register);
+        w.bool(self.active);
+        w.u16(self.index);
+        w.u64(self.clock);
+        w.u64(self.delay);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.register = r.u8();
+        self.active = r.bool();
+        self.index = r.u16();
+        self.clock = r.u64();
+        self.delay = r.u64();
+    }
+}
+
+impl Snapshot for Hdma {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u16(self.source);
+        w.u16(self.dest);
+        w.u8(self.blocks);
+        w.bool(self.general);
+        w.bool(self.hblank);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.source = r.u16();
+        self.dest = r.u16();
+        self.blocks = r.u8();
+        self.general = r.bool();
+        self.hblank = r.bool();
+    }
+}
@@ -0,0 +1,374 @@
+use crate::gpu::{LCDC, Sprite, BgPixel, ObjPixel, BgAttribute, decode_row, VRAM_BANK_SIZE};
+use crate::WIDTH;
+
+/// Dots to fetch a sprite, background fetcher is paused meanwhile
+const SPRITE_FETCH_DOTS: u8 = 6;
+/// Most dots a sprite waits for background fetcher, when it starts
+/// at the left edge of a tile
+const SPRITE_ALIGN_DOTS: u8 = 5;
+
+/// Registers used by fetcher, captured every dot so that
+/// writes in the middle of a scanline take effect immediately
+#[derive(Clone,Copy)]
+pub struct FetchContext {
+    pub lcdc: LCDC,
+    pub scx: u8,
+    pub scy: u8,
+    pub wx: u8,
+    /// current line, LY
+    pub line: u8,
+    /// internal line counter of window
+    pub window_line: u8,
+    /// WY matched LY in this frame
+    pub window_triggered: bool,
+    /// CGB mode, fetch map attributes from VRAM bank 1
+    pub cgb: bool,
+}
+
+/// Queue of 8 pixels
+#[derive(Default)]
+struct Queue<T> {
+    buf: [T; 8],
+    head: usize,
+    len: usize,
+}
+
+impl<T: Copy + Default> Queue<T> {
+    fn push(&mut self, pixel: T) {
+        self.buf[(self.head + self.len) % 8] = pixel;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+        let pixel = self.buf[self.head];
+        self.head = (self.head + 1) % 8;
+        self.len -= 1;
+        Some(pixel)
+    }
+
+    fn get_mut(&mut self, idx: usize) -> &mut T {
+        &mut self.buf[(self.head + idx) % 8]
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+    }
+}
+
+#[derive(Clone,Copy,PartialEq,Default)]
+enum FetchStep {
+    /// read tile index from tile map
+    #[default]
+    Tile,
+    /// read low byte of tile row
+    DataLow,
+    /// read high byte of tile row
+    DataHigh,
+    /// push 8 pixels once background FIFO is empty
+    Push,
+}
+
+/// Background / window fetcher
+#[derive(Default)]
+struct Fetcher {
+    step: FetchStep,
+    /// every step except push takes 2 dots
+    dot: u8,
+    /// tile column counter in current line
+    tile_x: u8,
+    /// fetch from window instead of background
+    window: bool,
+    tile_idx: u8,
+    attr: BgAttribute,
+    low: u8,
+    high: u8,
+}
+
+impl Fetcher {
+    fn restart(&mut self, window: bool) {
+        *self = Fetcher {
+            window: window,
+            ..Default::default()
+        };
+    }
+
+    /// row in tile map
+    fn row(&self, ctx: &FetchContext) -> u8 {
+        if self.window {
+            ctx.window_line
+        } else {
+            ctx.line.wrapping_add(ctx.scy)
+        }
+    }
+
+    fn map_addr(&self, ctx: &FetchContext) -> usize {
+        let (base, col) = if self.window {
+            (ctx.lcdc.window_map(), self.tile_x)
+        } else {
+            (ctx.lcdc.bg_map(), (ctx.scx / 8).wrapping_add(self.tile_x))
+        };
+        base + (self.row(ctx) as usize / 8) * 32 + (col & 31) as usize
+    }
+
+    fn data_addr(&self, ctx: &FetchContext) -> usize {
+        let row = self.row(ctx) % 8;
+        let row = if self.attr.flip_y { 7 - row } else { row };
+        let bank = if self.attr.bank { VRAM_BANK_SIZE } else { 0 };
+        bank + ctx.lcdc.tile_data(self.tile_idx) + row as usize * 2
+    }
+
+    /// run fetcher for a dot, return true if a tile is pushed
+    fn tick(&mut self, ctx: &FetchContext, vram: &[u8], fifo: &mut Queue<BgPixel>) -> bool {
+        if self.step == FetchStep::Push {
+            if !fifo.is_empty() {
+                return false;
+            }
+            let mut colors = decode_row(self.low, self.high);
+            if self.attr.flip_x {
+                colors.reverse();
+            }
+            for color in colors.iter() {
+                fifo.push(BgPixel {
+                    color: *color,
+                    palette: self.attr.palette,
+                    priority: self.attr.priority,
+                });
+            }
+            self.tile_x = self.tile_x.wrapping_add(1);
+            self.step = FetchStep::Tile;
+            return true;
+        }
+
+        self.dot += 1;
+        if self.dot < 2 {
+            return false;
+        }
+        self.dot = 0;
+        match self.step {
+            FetchStep::Tile => {
+                let addr = self.map_addr(ctx);
+                self.tile_idx = vram[addr];
+                if ctx.cgb {
+                    self.attr = BgAttribute::from_u8(vram[VRAM_BANK_SIZE + addr]);
+                }
+                self.step = FetchStep::DataLow;
+            },
+            FetchStep::DataLow => {
+                self.low = vram[self.data_addr(ctx)];
+                self.step = FetchStep::DataHigh;
+            },
+            FetchStep::DataHigh => {
+                self.high = vram[self.data_addr(ctx) + 1];
+                self.step = FetchStep::Push;
+            },
+            FetchStep::Push => {},
+        }
+        false
+    }
+}
+
+/// Pixel FIFO of mode 3, push one pixel to LCD each dot
+#[derive(Default)]
+pub struct PixelFifo {
+    bg: Queue<BgPixel>,
+    obj: Queue<ObjPixel>,
+    fetcher: Fetcher,
+    /// x of next pixel shown on LCD
+    x: u8,
+    /// pixels dropped at line start for SCX fine scroll
+    discard: u8,
+    /// first tile fetched in a line is thrown away
+    first_fetch: bool,
+    /// sprites already fetched, indexed as line sprites
+    fetched: [bool; 10],
+    /// line sprite being fetched and dots remaining
+    sprite_fetch: Option<(usize, u8)>,
+    /// background tile a sprite already waited for
+    aligned_tile: Option<isize>,
+    window_used: bool,
+}
+
+impl PixelFifo {
+    /// reset at the beginning of mode 3
+    pub fn start_line(&mut self, scx: u8) {
+        *self = PixelFifo {
+            discard: scx % 8,
+            first_fetch: true,
+            ..Default::default()
+        };
+    }
+
+    pub fn finished(&self) -> bool {
+        self.x as usize >= WIDTH
+    }
+
+    pub fn window_used(&self) -> bool {
+        self.window_used
+    }
+
+    /// run a dot, return x, background pixel and sprite pixel
+    /// if a pixel is shifted out to LCD
+    pub fn tick(&mut self, ctx: &FetchContext, vram: &[u8],
+                sprites: &[Sprite], line_sprites: &[usize])
+                -> Option<(usize, BgPixel, Option<ObjPixel>)> {
+        if self.finished() {
+            return None;
+        }
+
+        if ctx.lcdc.obj_display && !self.first_fetch && self.sprite_fetch.is_none() {
+            for (slot, idx) in line_sprites.iter().enumerate() {
+                // sprites at OAM X 0 are offscreen but fetched all the same
+                let x = sprites[*idx].x();
+                if !self.fetched[slot] && x <= self.x as isize {
+                    self.fetched[slot] = true;
+                    self.sprite_fetch = Some((slot, self.sprite_dots(x, ctx)));
+                    break;
+                }
+            }
+        }
+
+        if let Some((slot, remain)) = self.sprite_fetch {
+            if remain > 1 {
+                self.sprite_fetch = Some((slot, remain - 1));
+            } else {
+                self.sprite_fetch = None;
+                let idx = line_sprites[slot];
+                self.merge_sprite(&sprites[idx], idx as u8, ctx, vram);
+            }
+            return None;
+        }
+
+        if ctx.lcdc.window_display && ctx.window_triggered && !self.fetcher.window &&
+           !self.first_fetch && self.x as isize + 7 >= ctx.wx as isize {
+            // switch to window, pixels of background are dropped
+            self.bg.clear();
+            self.fetcher.restart(true);
+            self.aligned_tile = None;
+            self.window_used = true;
+        }
+
+        if self.fetcher.tick(ctx, vram, &mut self.bg) && self.first_fetch {
+            self.first_fetch = false;
+            self.bg.clear();
+            self.fetcher.restart(false);
+            return None;
+        }
+
+        let bg = self.bg.pop()?;
+        if self.discard > 0 {
+            self.discard -= 1;
+            return None;
+        }
+        let obj = self.obj.pop();
+        let x = self.x as usize;
+        self.x += 1;
+        Some((x, bg, obj))
+    }
+
+    /// dots a sprite at x stalls the line, the first sprite on a background
+    /// tile also waits for the tile fetch depending on where it starts in it
+    fn sprite_dots(&mut self, x: isize, ctx: &FetchContext) -> u8 {
+        let origin = if self.fetcher.window { 7 - ctx.wx as isize } else { ctx.scx as isize };
+        let pos = x + origin;
+        let tile = pos.div_euclid(8);
+        if self.aligned_tile == Some(tile) {
+            return SPRITE_FETCH_DOTS;
+        }
+        self.aligned_tile = Some(tile);
+        SPRITE_FETCH_DOTS + SPRITE_ALIGN_DOTS.saturating_sub(pos.rem_euclid(8) as u8)
+    }
+
+    /// merge sprite into sprite FIFO, existing opaque pixels have priority
+    /// in DMG mode, the one with smaller OAM index has priority in CGB mode
+    fn merge_sprite(&mut self, sprite: &Sprite, index: u8, ctx: &FetchContext, vram: &[u8]) {
+        let mut pixels = sprite.pixels(vram, ctx.line, ctx.lcdc.obj_height(), ctx.cgb);
+        for pixel in pixels.iter_mut() {
+            pixel.index = index;
+        }
+        // pixels left to the screen are not shown
+        let skip = if sprite.x() < 0 { (-sprite.x()) as usize } else { 0 };
+        for (i, pixel) in pixels.iter().skip(skip).enumerate() {
+            if i < self.obj.len() {
+                let slot = self.obj.get_mut(i);
+                if slot.color == 0 ||
+                   (ctx.cgb && pixel.color != 0 && pixel.index < slot.index) {
+                    *slot = *pixel;
+                }
+            } else {
+                self.obj.push(*pixel);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context(scx: u8) -> FetchContext {
+        FetchContext {
+            lcdc: LCDC::from_u8(0x93),
+            scx: scx,
+            scy: 0,
+            wx: 0,
+            line: 0,
+            window_line: 0,
+            window_triggered: false,
+            cgb: false,
+        }
+    }
+
+    /// dots of mode 3 for a line
+    fn transfer_dots(scx: u8, line_sprites: &[usize]) -> usize {
+        let ctx = context(scx);
+        let vram = vec![0; VRAM_BANK_SIZE * 2];
+        let sprites = [Sprite::default(); 10];
+        let mut fifo = PixelFifo::default();
+        fifo.start_line(scx);
+        let mut dots = 0;
+        while !fifo.finished() {
+            fifo.tick(&ctx, &vram, &sprites, line_sprites);
+            dots += 1;
+        }
+        dots
+    }
+
+    #[test]
+    fn sprite_dots_depend_on_alignment() {
+        let mut fifo = PixelFifo::default();
+        let ctx = context(0);
+        assert_eq!(fifo.sprite_dots(-8, &ctx), 11); // OAM X 0
+        assert_eq!(fifo.sprite_dots(-8, &ctx), 6);
+        assert_eq!(fifo.sprite_dots(0, &ctx), 11);
+        assert_eq!(fifo.sprite_dots(3, &ctx), 6);
+        assert_eq!(fifo.sprite_dots(7, &ctx), 6);
+        assert_eq!(fifo.sprite_dots(12, &ctx), 7);
+
+        let mut fifo = PixelFifo::default();
+        let ctx = context(3);
+        assert_eq!(fifo.sprite_dots(0, &ctx), 8);
+        assert_eq!(fifo.sprite_dots(5, &ctx), 11);
+        assert_eq!(fifo.sprite_dots(11, &ctx), 6);
+    }
+
+    #[test]
+    fn sprites_stall_transfer() {
+        let base = transfer_dots(0, &[]);
+        assert_eq!(transfer_dots(0, &[0]), base + 11);
+        assert_eq!(transfer_dots(0, &[0, 1]), base + 17);
+        assert_eq!(transfer_dots(3, &[]), base + 3);
+        assert_eq!(transfer_dots(3, &[0]), base + 3 + 8);
+    }
+}
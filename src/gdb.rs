@@ -0,0 +1,435 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use log::info;
+
+use crate::debugger::{self, Debugger};
+use crate::vm::Vm;
+use crate::watch::{Access, Watchpoint};
+
+/// Registers in order of the g packet, each 16 bits little endian
+const REGISTERS: [&str; 6] = ["af", "bc", "de", "hl", "sp", "pc"];
+
+/// Register layout sent to client, SM83 is known as gbz80 to GDB
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <architecture>gbz80</architecture>
+  <feature name="org.gnu.gdb.z80.cpu">
+    <reg name="af" bitsize="16" type="uint16" regnum="0"/>
+    <reg name="bc" bitsize="16" type="uint16"/>
+    <reg name="de" bitsize="16" type="uint16"/>
+    <reg name="hl" bitsize="16" type="uint16"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>"#;
+
+/// Ctrl-C sent by client to stop a running target
+const INTERRUPT: u8 = 0x03;
+
+/// Serve a GDB client over the remote serial protocol, in place of
+/// the command line of debugger
+pub struct GdbStub {
+    stream: TcpStream,
+    /// bytes received but not handled
+    pending: Vec<u8>,
+    /// target is resumed, a stop reply is due when it breaks
+    running: bool,
+}
+
+impl GdbStub {
+    /// wait for a client to connect to local port
+    pub fn listen(port: u16) -> Result<Self, String> {
+        let listener = TcpListener::bind(("127.0.0.1", port)).map_err(|e| format!("gdb: {}", e))?;
+        info!("gdb: waiting for connection on port {}", port);
+        let (stream, addr) = listener.accept().map_err(|e| format!("gdb: {}", e))?;
+        info!("gdb: connected from {}", addr);
+        let _ = stream.set_nodelay(true);
+        Ok(Self {
+            stream: stream,
+            pending: Vec::new(),
+            running: false,
+        })
+    }
+
+    /// whether client asked to stop the running target,
+    /// other bytes are kept for serve
+    pub fn interrupted(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut buf = [0; 256];
+        let result = match self.stream.read(&mut buf) {
+            Ok(0) => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed")),
+            Ok(n) => {
+                self.pending.extend_from_slice(&buf[..n]);
+                Ok(true)
+            },
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        };
+        self.stream.set_nonblocking(false)?;
+        let interrupted = result? && self.pending.contains(&INTERRUPT);
+        self.pending.retain(|byte| *byte != INTERRUPT);
+        Ok(interrupted)
+    }
+
+    /// Handle packets until the target is resumed, return false if
+    /// client detached
+    pub fn serve(&mut self, debugger: &mut Debugger, vm: &mut Vm) -> io::Result<bool> {
+        if self.running {
+            self.running = false;
+            let reply = stop_reply(debugger, vm);
+            self.send(&reply)?;
+        }
+        while debugger.is_paused() {
+            let packet = self.receive()?;
+            let reply = match packet.as_bytes() {
+                [b'c', ..] | [b's', ..] => {
+                    if packet.len() > 1 {
+                        vm.cpu.pc = hex(&packet[1..]).unwrap_or(vm.cpu.pc);
+                    }
+                    if packet.starts_with('c') { debugger.resume() } else { debugger.step() };
+                    self.running = true;
+                    return Ok(true);
+                },
+                [b'D', ..] => {
+                    self.send("OK")?;
+                    debugger.resume();
+                    return Ok(false);
+                },
+                [b'k', ..] => {
+                    debugger.quit = true;
+                    return Ok(false);
+                },
+                _ => command(&packet, debugger, vm),
+            };
+            self.send(&reply)?;
+        }
+        Ok(true)
+    }
+
+    fn read_byte(&mut self) -> io::Result<u8> {
+        if self.pending.is_empty() {
+            let mut buf = [0; 256];
+            let n = self.stream.read(&mut buf)?;
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed"));
+            }
+            self.pending.extend_from_slice(&buf[..n]);
+        }
+        Ok(self.pending.remove(0))
+    }
+
+    /// receive a packet $data#checksum and acknowledge it
+    fn receive(&mut self) -> io::Result<String> {
+        loop {
+            // skip acks and interrupts outside packet
+            while self.read_byte()? != b'$' {}
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    b'#' => break,
+                    byte => data.push(byte),
+                }
+            }
+            let checksum = [self.read_byte()?, self.read_byte()?];
+            let checksum = std::str::from_utf8(&checksum).ok().and_then(hex);
+            if checksum == Some(sum(&data) as u16) {
+                self.stream.write_all(b"+")?;
+                return Ok(String::from_utf8_lossy(&data).into_owned());
+            }
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    /// send a packet and wait for ack, resend if client asks
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, sum(data.as_bytes()));
+        loop {
+            self.stream.write_all(packet.as_bytes())?;
+            match self.read_byte()? {
+                b'-' => continue,
+                b'+' => return Ok(()),
+                // client without ack mode, keep the byte
+                byte => {
+                    self.pending.insert(0, byte);
+                    return Ok(());
+                },
+            }
+        }
+    }
+}
+
+/// reply to a packet which does not resume target, empty if unsupported
+fn command(packet: &str, debugger: &mut Debugger, vm: &mut Vm) -> String {
+    let cpu = &mut vm.cpu;
+    // an empty packet is valid, the client gets an empty reply
+    let mut chars = packet.chars();
+    let cmd = match chars.next() {
+        Some(cmd) => cmd,
+        None => return String::new(),
+    };
+    let args = chars.as_str();
+    match cmd {
+        '?' => stop_reply(debugger, vm),
+        'g' => REGISTERS.iter()
+                .map(|reg| debugger::get_reg(cpu, reg).unwrap_or(0))
+                .map(|value| format!("{:02x}{:02x}", value & 0xff, value >> 8))
+                .collect(),
+        'G' => {
+            let values = args.as_bytes().chunks(4)
+                .map(|value| std::str::from_utf8(value).ok().and_then(hex_le));
+            for (reg, value) in REGISTERS.iter().zip(values) {
+                match value {
+                    Some(value) => { let _ = debugger::set_reg(cpu, reg, value); },
+                    None => return "E01".to_string(),
+                }
+            }
+            "OK".to_string()
+        },
+        'p' => match hex(args).and_then(|n| REGISTERS.get(n as usize)) {
+            Some(reg) => {
+                let value = debugger::get_reg(cpu, reg).unwrap_or(0);
+                format!("{:02x}{:02x}", value & 0xff, value >> 8)
+            },
+            None => "E01".to_string(),
+        },
+        'P' => {
+            let mut parts = args.splitn(2, '=');
+            let reg = parts.next().and_then(hex).and_then(|n| REGISTERS.get(n as usize));
+            match (reg, parts.next().and_then(hex_le)) {
+                (Some(reg), Some(value)) => {
+                    let _ = debugger::set_reg(cpu, reg, value);
+                    "OK".to_string()
+                },
+                _ => "E01".to_string(),
+            }
+        },
+        'm' => {
+            let (addr, len) = match addr_len(args) {
+                Some(range) => range,
+                None => return "E01".to_string(),
+            };
+            let mut reply = String::new();
+            for i in 0..len {
+                match cpu.bus.peek8(addr.wrapping_add(i)) {
+                    Ok(byte) => reply.push_str(&format!("{:02x}", byte)),
+                    // partial read is allowed
+                    Err(_) if i > 0 => break,
+                    Err(_) => return "E14".to_string(),
+                }
+            }
+            reply
+        },
+        'M' => {
+            let mut parts = args.splitn(2, ':');
+            let range = parts.next().and_then(addr_len);
+            let data = parts.next().unwrap_or("");
+            // nothing is written unless all data is hex
+            let bytes: Option<Vec<u8>> = data.as_bytes().chunks(2)
+                .map(|pair| std::str::from_utf8(pair).ok()
+                    .filter(|pair| pair.len() == 2 && pair.bytes().all(|c| c.is_ascii_hexdigit()))
+                    .and_then(hex)
+                    .map(|byte| byte as u8))
+                .collect();
+            let (addr, bytes) = match (range, bytes) {
+                (Some((addr, len)), Some(bytes)) if bytes.len() == len as usize => (addr, bytes),
+                _ => return "E01".to_string(),
+            };
+            // written by client, not by the program, so neither blocked nor watched
+            for (i, byte) in bytes.into_iter().enumerate() {
+                if cpu.bus.poke8(addr.wrapping_add(i as u16), byte).is_err() {
+                    return "E14".to_string();
+                }
+            }
+            "OK".to_string()
+        },
+        'Z' | 'z' => {
+            let mut parts = args.split(',');
+            let kind = parts.next().unwrap_or("");
+            let addr = parts.next().and_then(hex);
+            let len = parts.next().and_then(hex).unwrap_or(1).max(1);
+            let addr = match addr {
+                Some(addr) => addr,
+                None => return "E01".to_string(),
+            };
+            let insert = cmd == 'Z';
+            let access = match kind {
+                // software and hardware breakpoints are the same to emulator
+                "0" | "1" => {
+                    if insert { debugger.break_at(addr) } else { debugger.clear_break(addr) }
+                    return "OK".to_string();
+                },
+                "2" => "w",
+                "3" => "r",
+                "4" => "rw",
+                _ => return String::new(),
+            };
+            let range = format!("{:x}-{:x}", addr, addr.saturating_add(len - 1));
+            let point = match Watchpoint::parse(access, &range, None, &debugger.symbols) {
+                Ok(point) => point,
+                Err(_) => return "E01".to_string(),
+            };
+            let points = &mut cpu.bus.watch.points;
+            if insert {
+                points.push(point);
+            } else {
+                points.retain(|p| (p.start, p.end, p.read, p.write, p.execute) !=
+                                  (point.start, point.end, point.read, point.write, point.execute));
+            }
+            "OK".to_string()
+        },
+        'H' => "OK".to_string(),
+        'q' if args.starts_with("Supported") => "PacketSize=1000;qXfer:features:read+".to_string(),
+        'q' if args == "Attached" => "1".to_string(),
+        'q' if args == "C" => "QC1".to_string(),
+        'q' if args == "fThreadInfo" => "m1".to_string(),
+        'q' if args == "sThreadInfo" => "l".to_string(),
+        'q' if args.starts_with("Xfer:features:read:target.xml:") => {
+            let range = &args["Xfer:features:read:target.xml:".len()..];
+            let mut parts = range.split(',');
+            let offset = parts.next().and_then(hex).unwrap_or(0) as usize;
+            let len = parts.next().and_then(hex).unwrap_or(0) as usize;
+            let xml = TARGET_XML.as_bytes();
+            let offset = offset.min(xml.len());
+            let end = (offset + len).min(xml.len());
+            // l marks the last part of document, m more to read
+            let more = if end < xml.len() { "m" } else { "l" };
+            format!("{}{}", more, escape(&TARGET_XML[offset..end]))
+        },
+        _ => String::new(),
+    }
+}
+
+/// reason of break, with address of access if stopped by watchpoint
+fn stop_reply(debugger: &Debugger, vm: &Vm) -> String {
+    match &debugger.hit {
+        Some(hit) if hit.access != Access::Execute => {
+            let both = vm.cpu.bus.watch.points.get(hit.index)
+                .is_some_and(|point| point.read && point.write);
+            let kind = match hit.access {
+                _ if both => "awatch",
+                Access::Write => "watch",
+                _ => "rwatch",
+            };
+            format!("T05{}:{:x};", kind, hit.addr)
+        },
+        _ => "S05".to_string(),
+    }
+}
+
+fn sum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn hex(arg: &str) -> Option<u16> {
+    u16::from_str_radix(arg, 16).ok()
+}
+
+/// 16 bits register value in target byte order
+fn hex_le(arg: &str) -> Option<u16> {
+    hex(arg).filter(|_| arg.len() == 4).map(u16::swap_bytes)
+}
+
+/// parse ADDR,LENGTH
+fn addr_len(arg: &str) -> Option<(u16, u16)> {
+    let mut parts = arg.splitn(2, ',');
+    Some((parts.next().and_then(hex)?, parts.next().and_then(hex)?))
+}
+
+/// escape bytes which have meaning in packet
+fn escape(data: &str) -> String {
+    let mut escaped = String::new();
+    for c in data.chars() {
+        match c {
+            '#' | '$' | '}' | '*' => {
+                escaped.push('}');
+                escaped.push((c as u8 ^ 0x20) as char);
+            },
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::boot::Model;
+
+    fn vm() -> Vm {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x104].copy_from_slice(&[0x00, 0xc3, 0x00, 0x01]); // jp $0100
+        Vm::new(rom, Model::DMG, None)
+    }
+
+    #[test]
+    fn packet_helpers() {
+        assert_eq!(sum(b"g"), 0x67);
+        assert_eq!(sum(b"OK"), 0x9a);
+        assert_eq!(hex_le("3412"), Some(0x1234));
+        assert_eq!(hex_le("12"), None);
+        assert_eq!(hex_le("12g4"), None);
+        assert_eq!(addr_len("c000,10"), Some((0xc000, 0x10)));
+        assert_eq!(addr_len("c000"), None);
+        assert_eq!(addr_len("c000,"), None);
+        assert_eq!(escape("a#b$c}d*e"), "a}\u{3}b}\u{4}c}]d}\ne");
+    }
+
+    #[test]
+    fn registers() {
+        let (mut debugger, mut vm) = (Debugger::new(false), vm());
+        assert_eq!(command("G34127856bc9af0de00d05001", &mut debugger, &mut vm), "OK");
+        assert_eq!((vm.cpu.regs.get_bc(), vm.cpu.sp, vm.cpu.pc), (0x5678, 0xd000, 0x0150));
+        assert_eq!(command("g", &mut debugger, &mut vm), "30127856bc9af0de00d05001");
+        assert_eq!(command("P3=cdab", &mut debugger, &mut vm), "OK");
+        assert_eq!(command("p3", &mut debugger, &mut vm), "cdab");
+        assert_eq!(vm.cpu.regs.get_hl(), 0xabcd);
+        assert_eq!(command("p6", &mut debugger, &mut vm), "E01");
+        assert_eq!(command("P3=12", &mut debugger, &mut vm), "E01");
+        assert_eq!(command("G3412", &mut debugger, &mut vm), "OK");
+        assert_eq!(command("Gzz", &mut debugger, &mut vm), "E01");
+    }
+
+    #[test]
+    fn memory() {
+        let (mut debugger, mut vm) = (Debugger::new(false), vm());
+        assert_eq!(command("m100,4", &mut debugger, &mut vm), "00c30001");
+        assert_eq!(command("Mc000,2:abcd", &mut debugger, &mut vm), "OK");
+        assert_eq!(command("mc000,3", &mut debugger, &mut vm), "abcd00");
+        assert_eq!(command("Mc000,2:ab", &mut debugger, &mut vm), "E01");
+        assert_eq!(command("Mc000,1:zz", &mut debugger, &mut vm), "E01");
+        assert_eq!(command("mc000", &mut debugger, &mut vm), "E01");
+    }
+
+    #[test]
+    fn memory_write_is_not_blocked_nor_watched() {
+        let (mut debugger, mut vm) = (Debugger::new(false), vm());
+        assert_eq!(command("Z2,c000,1", &mut debugger, &mut vm), "OK");
+        // CPU can only access HRAM during OAM DMA
+        vm.cpu.bus.store8(0xff46, 0xc1).unwrap();
+        assert_eq!(command("Mc000,1:5a", &mut debugger, &mut vm), "OK");
+        assert_eq!(command("mc000,1", &mut debugger, &mut vm), "5a");
+        assert!(vm.cpu.bus.watch.take_hit().is_none());
+    }
+
+    #[test]
+    fn breakpoints_and_watchpoints() {
+        let (mut debugger, mut vm) = (Debugger::new(false), vm());
+        assert_eq!(command("Z0,100,1", &mut debugger, &mut vm), "OK");
+        assert!(debugger.should_break(&vm.cpu));
+        debugger.resume();
+        assert_eq!(command("z0,100,1", &mut debugger, &mut vm), "OK");
+        assert!(!debugger.should_break(&vm.cpu));
+
+        assert_eq!(command("Z4,c000,2", &mut debugger, &mut vm), "OK");
+        let point = &vm.cpu.bus.watch.points[0];
+        assert_eq!((point.start, point.end, point.read, point.write), (0xc000, 0xc001, true, true));
+        assert_eq!(command("z2,c000,2", &mut debugger, &mut vm), "OK");
+        assert_eq!(vm.cpu.bus.watch.points.len(), 1);
+        assert_eq!(command("z4,c000,2", &mut debugger, &mut vm), "OK");
+        assert!(vm.cpu.bus.watch.is_empty());
+        assert_eq!(command("Z5,c000,1", &mut debugger, &mut vm), "");
+        assert_eq!(command("Z2", &mut debugger, &mut vm), "E01");
+    }
+}
@@ -1,17 +1,33 @@
 use crate::bus::{Device};
+use crate::fifo::{PixelFifo, FetchContext};
+use crate::palette::{Palette, PaletteSet, ColorPalette};
+use crate::savestate::{Snapshot, StateWriter, StateReader};
 use crate::{WIDTH, HEIGHT};
 
-const BLACK: u32 = 0x00000000u32;
-const DGRAY: u32 = 0x00555555u32;
-const LGRAY: u32 = 0x00AAAAAAu32;
-const WHITE: u32 = 0x00FFFFFFu32;
+/// Size of a VRAM bank, CGB has 2 banks
+pub const VRAM_BANK_SIZE: usize = 0x2000;
+/// Blank screen color of CGB
+const CGB_WHITE: u32 = 0x00FFFFFF;
 
 pub const VRAM_START:     u16 = 0x8000;
 pub const VRAM_END:       u16 = 0x9fff;
 pub const OAM_START:      u16 = 0xfe00;
 pub const OAM_END:        u16 = 0xfe9f;
 
-#[derive(PartialEq)]
+/// Clock of a full frame, 154 lines * 456 clocks
+const FRAME_CLOCK: u64 = 70224;
+/// Clock of a scanline
+const LINE_CLOCK: u64 = 456;
+/// Clock of OAM search (mode 2)
+const OAM_CLOCK: u64 = 80;
+/// Clock of pixel transfer (mode 3) for scanline renderer
+const VRAM_CLOCK: u64 = 172;
+/// Last line of frame, line 144 to 153 are VBlank
+const LAST_LINE: u8 = 153;
+/// At most 10 sprites are shown on a line
+const LINE_SPRITES: usize = 10;
+
+#[derive(PartialEq,Clone,Copy,Debug)]
 pub enum GpuMode {
     /// First scanline mode, render data from OAM memory
     ScanlineOAM,
@@ -23,40 +39,89 @@ pub enum GpuMode {
     VBlank,
 }
 
+/// Method to render the screen
+#[derive(PartialEq,Clone,Copy,Debug)]
+pub enum Renderer {
+    /// Render the whole line at the end of mode 3, fast but
+    /// ignore register writes in the middle of a line
+    Scanline,
+    /// Emulate background/sprite fetchers and pixel FIFO dot by dot,
+    /// mode 3 length varies with SCX, window and sprites
+    Fifo,
+}
+
+impl Renderer {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "scanline" => Some(Renderer::Scanline),
+            "fifo" => Some(Renderer::Fifo),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Renderer::Scanline => "scanline",
+            Renderer::Fifo => "fifo",
+        }
+    }
+}
+
+impl GpuMode {
+    /// mode number shown in bit 1-0 of STAT
+    fn to_u8(self) -> u8 {
+        match self {
+            GpuMode::HBlank       => 0,
+            GpuMode::VBlank       => 1,
+            GpuMode::ScanlineOAM  => 2,
+            GpuMode::ScanlineVRAM => 3,
+        }
+    }
+
+    fn from_u8(value: u8) -> Self {
+        match value & 0x3 {
+            0 => GpuMode::HBlank,
+            1 => GpuMode::VBlank,
+            2 => GpuMode::ScanlineOAM,
+            _ => GpuMode::ScanlineVRAM,
+        }
+    }
+}
+
 #[derive(Debug,Clone,Copy)]
 pub struct LCDC {
     /// LCD control operation
     /// false: stop
     /// true:  operation
-    operation: bool,
+    pub operation: bool,
     /// select tile map
     /// false: 0x9800-0x9bff
     /// true:  0x9c00-0x9fff
-    windows_tile_map: bool,
+    pub windows_tile_map: bool,
     /// window display
     /// false: off
     /// true:  on
-    window_display: bool,
+    pub window_display: bool,
     /// BG & window tile data select
     /// false: 0x8800-0x9cff
     /// true:  0x8000-0x8fff
-    bg_tile_data_select: bool,
+    pub bg_tile_data_select: bool,
     /// BG tile map display select
     /// false: 0x9800-0x9bff
     /// true:  0x9c00-0x9fff
-    bg_tile_map_select: bool,
+    pub bg_tile_map_select: bool,
     /// obj sprite size (width x height)
     /// false: 8x8
     /// true:  8x16
-    obj_size: bool,
+    pub obj_size: bool,
     /// obj (sprite) display
     /// false: off
     /// true:  on
-    obj_display: bool,
+    pub obj_display: bool,
     /// bg & window display
     /// false: off
     /// true:  on
-    bg_display: bool,
+    pub bg_display: bool,
 }
 
 impl LCDC {
@@ -83,6 +148,90 @@ impl LCDC {
             (self.obj_display as u8) << 1 |
             (self.bg_display as u8)
     }
+
+    /// offset of background tile map in vram
+    pub fn bg_map(&self) -> usize {
+        if self.bg_tile_map_select { 0x1c00 } else { 0x1800 }
+    }
+
+    /// offset of window tile map in vram
+    pub fn window_map(&self) -> usize {
+        if self.windows_tile_map { 0x1c00 } else { 0x1800 }
+    }
+
+    /// offset of background & window tile data in vram
+    /// 0x8800 addressing use signed tile index based on 0x9000
+    pub fn tile_data(&self, tile_idx: u8) -> usize {
+        if self.bg_tile_data_select {
+            tile_idx as usize * 16
+        } else {
+            (0x1000 + (tile_idx as i8 as isize) * 16) as usize
+        }
+    }
+
+    pub fn obj_height(&self) -> isize {
+        if self.obj_size { 16 } else { 8 }
+    }
+}
+
+/// decode a tile row from its low and high byte, leftmost pixel first
+pub fn decode_row(low: u8, high: u8) -> [u8; 8] {
+    let mut pixels = [0; 8];
+    for (i, pixel) in pixels.iter_mut().enumerate() {
+        let shift = 7 - i;
+        *pixel = ((high >> shift) & 0x1) << 1 | ((low >> shift) & 0x1);
+    }
+    pixels
+}
+
+/// CGB background map attribute, stored in VRAM bank 1
+#[derive(Default,Clone,Copy,Debug)]
+pub struct BgAttribute {
+    /// background has priority over sprites
+    pub priority: bool,
+    pub flip_y: bool,
+    pub flip_x: bool,
+    /// tile data in VRAM bank 1
+    pub bank: bool,
+    /// background palette number 0-7
+    pub palette: u8,
+}
+
+impl BgAttribute {
+    pub fn from_u8(byte: u8) -> Self {
+        Self {
+            priority: byte & 0b10000000 != 0,
+            flip_y:   byte & 0b01000000 != 0,
+            flip_x:   byte & 0b00100000 != 0,
+            bank:     byte & 0b00001000 != 0,
+            palette:  byte & 0b00000111,
+        }
+    }
+}
+
+/// Background or window pixel before mixed with sprite
+#[derive(Default,Clone,Copy,Debug)]
+pub struct BgPixel {
+    /// color number 0-3
+    pub color: u8,
+    /// CGB background palette number
+    pub palette: u8,
+    /// CGB background priority from map attribute
+    pub priority: bool,
+}
+
+/// Sprite pixel before mixed with background
+#[derive(Default,Clone,Copy,Debug)]
+pub struct ObjPixel {
+    /// color number 0-3, 0 is transparent
+    pub color: u8,
+    /// DMG: 0 for OBJ0PAL, 1 for OBJ1PAL
+    /// CGB: object palette number 0-7
+    pub palette: u8,
+    /// behind background color 1-3 if true
+    pub priority: bool,
+    /// index in OAM, decide priority between sprites in CGB mode
+    pub index: u8,
 }
 
 #[derive(Default,Clone,Copy,Debug)]
@@ -104,84 +253,288 @@ pub struct Sprite {
     /// palette_number:
     /// 0: from OBJ0PAL
     /// 1: from OBJ1PAL
-    palette_number: bool
+    palette_number: bool,
+    /// CGB: tile data in VRAM bank 1
+    bank: bool,
+    /// CGB: object palette number 0-7
+    cgb_palette: u8,
+}
+
+impl Sprite {
+    pub fn x(&self) -> isize {
+        self.x
+    }
+
+    pub fn on_line(&self, line: u8, height: isize) -> bool {
+        let line = line as isize;
+        line >= self.y && line < self.y + height
+    }
+
+    /// pixels of the sprite on given line, flip applied
+    pub fn pixels(&self, vram: &[u8], line: u8, height: isize, cgb: bool) -> [ObjPixel; 8] {
+        let mut row = line as isize - self.y;
+        if self.flip_y {
+            row = height - 1 - row;
+        }
+        // 8x16 sprite ignore bit 0 of tile index
+        let tile = if height == 16 { self.tile_idx & 0xfe } else { self.tile_idx };
+        let bank = if cgb && self.bank { VRAM_BANK_SIZE } else { 0 };
+        let addr = bank + tile as usize * 16 + row as usize * 2;
+        let colors = decode_row(vram[addr], vram[addr + 1]);
+
+        let mut pixels = [ObjPixel::default(); 8];
+        for (i, pixel) in pixels.iter_mut().enumerate() {
+            pixel.color = if self.flip_x { colors[7 - i] } else { colors[i] };
+            pixel.palette = if cgb { self.cgb_palette } else { self.palette_number as u8 };
+            pixel.priority = self.priority;
+        }
+        pixels
+    }
 }
 
 pub struct Gpu {
     /// Clock to switch mode
     clock: u64,
+    /// Dot in current line, used by FIFO renderer
+    dot: u64,
     /// current display line number
     pub line: u8,
+    /// LYC: line compared with LY for coincidence flag
+    pub lyc: u8,
     /// lcdc, LCD control line
     pub lcdc: LCDC,
+    /// STAT bit 6-3, LCD status interrupt selection
+    stat_select: u8,
     /// background & window palette data
     pub bg_palette: u8,
     /// object palette 0
     pub ob0_palette: u8,
     /// object palette 1
     pub ob1_palette: u8,
+    /// colors of background, object 0 and object 1 shades
+    pub palette: PaletteSet,
+    /// CGB mode, color palettes and VRAM bank 1 are used
+    pub cgb: bool,
+    /// CGB background palette RAM, BCPS/BCPD
+    pub bg_colors: ColorPalette,
+    /// CGB object palette RAM, OCPS/OCPD
+    pub obj_colors: ColorPalette,
     /// current display mode
     pub mode: GpuMode,
     /// SCY: background Y position
     pub scy: u8,
     /// SCX: background X position
     pub scx: u8,
-    /// vram: 0x8000-0x9BFF 6144 bytes
+    /// WY: window Y position
+    pub wy: u8,
+    /// WX: window X position + 7
+    pub wx: u8,
+    /// WY matched LY in this frame, window can be shown
+    window_triggered: bool,
+    /// internal line counter of window
+    window_line: u8,
+    /// vram: 0x8000-0x9FFF 8192 bytes, 2 banks in CGB
     vram: Vec<u8>,
+    /// VBK: VRAM bank mapped to 0x8000-0x9FFF
+    vram_bank: usize,
     /// oam: 0xFE00-0xFE9F 160 bytes
     oam: Vec<u8>,
 
     /// sprite
     sprite: [Sprite;40],
+    /// index of sprites shown on current line
+    line_sprites: Vec<usize>,
+    /// rendered screen
+    frame: Vec<u32>,
+    /// renderer in use, switched only at the start of a frame
+    renderer: Renderer,
+    next_renderer: Renderer,
+    fifo: PixelFifo,
     // whether vblank interrupt is occured
-    pub is_interrupt: bool
+    pub is_interrupt: bool,
+    /// whether STAT interrupt is occured
+    pub is_stat_interrupt: bool,
+    /// STAT interrupt line, sources selected in STAT combined
+    stat_signal: bool,
+    /// whether a frame is finished and ready to be shown
+    pub frame_ready: bool,
+    /// mode 0 is just entered, HBlank DMA copies a block
+    pub hblank_entered: bool,
+    /// first frame after LCD turned on is not displayed
+    first_frame: bool,
+    /// block CPU access to VRAM in mode 3 and OAM in mode 2, 3
+    /// false to disable blocking for debugging
+    pub access_block: bool,
 }
 
 impl Gpu {
-    pub fn new() -> Self {
-        let ram = vec![0; (VRAM_END - VRAM_START + 1) as usize];
+    pub fn new(cgb: bool) -> Self {
+        let ram = vec![0; VRAM_BANK_SIZE * 2];
         let oam = vec![0; (OAM_END - OAM_START + 1) as usize];
         Self {
             clock: 0,
+            dot: 0,
             line: 0,
+            lyc: 0,
             lcdc: LCDC::from_u8(0x91),
+            stat_select: 0,
             bg_palette: 0xfc,
             ob0_palette: 0xff,
             ob1_palette: 0xff,
+            palette: Default::default(),
+            cgb: cgb,
+            bg_colors: ColorPalette::new(),
+            obj_colors: ColorPalette::new(),
             mode: GpuMode::ScanlineOAM,
             scy: 0,
             scx: 0,
+            wy: 0,
+            wx: 0,
+            window_triggered: false,
+            window_line: 0,
             vram: ram,
+            vram_bank: 0,
             oam: oam,
             sprite: [Default::default();40],
-            is_interrupt: false
+            line_sprites: Vec::with_capacity(LINE_SPRITES),
+            frame: vec![0; WIDTH * HEIGHT],
+            renderer: Renderer::Scanline,
+            next_renderer: Renderer::Scanline,
+            fifo: Default::default(),
+            is_interrupt: false,
+            is_stat_interrupt: false,
+            stat_signal: false,
+            frame_ready: false,
+            hblank_entered: false,
+            first_frame: false,
+            access_block: true,
+        }
+    }
+
+    pub fn set_lcdc(&mut self, value: u8) {
+        let lcdc = LCDC::from_u8(value);
+        if self.lcdc.operation && !lcdc.operation {
+            // LCD off: LY is reset and stay in mode 0
+            self.line = 0;
+            self.clock = 0;
+            self.dot = 0;
+            self.mode = GpuMode::HBlank;
+        } else if !self.lcdc.operation && lcdc.operation {
+            // LCD on: restart from the first line, and
+            // the first frame is not displayed
+            self.line = 0;
+            self.clock = 0;
+            self.dot = 0;
+            self.mode = GpuMode::ScanlineOAM;
+            self.first_frame = true;
+            self.start_frame();
         }
+        self.lcdc = lcdc;
+        self.update_stat();
+    }
+
+    /// renderer selected, which may start from next frame
+    pub fn next_renderer(&self) -> Renderer {
+        self.next_renderer
+    }
+
+    /// select renderer, take effect from next frame
+    pub fn set_renderer(&mut self, renderer: Renderer) {
+        self.next_renderer = renderer;
+        if !self.lcdc.operation {
+            self.renderer = renderer;
+        }
+    }
+
+    pub fn load_stat(&self) -> u8 {
+        0x80 | self.stat_select |
+            (if self.line == self.lyc { 1 << 2 } else { 0 }) |
+            self.mode.to_u8()
+    }
+
+    pub fn store_stat(&mut self, value: u8) {
+        self.stat_select = value & 0x78;
+        self.update_stat();
+    }
+
+    pub fn store_lyc(&mut self, value: u8) {
+        self.lyc = value;
+        self.update_stat();
+    }
+
+    /// interrupt is requested on rising edge of STAT line, so a source
+    /// does not request it while another selected one is active
+    fn update_stat(&mut self) {
+        let mode = match self.mode {
+            GpuMode::HBlank => 0x08,
+            GpuMode::VBlank => 0x10,
+            GpuMode::ScanlineOAM => 0x20,
+            GpuMode::ScanlineVRAM => 0x00,
+        };
+        let lyc = if self.line == self.lyc { 0x40 } else { 0x00 };
+        let signal = self.lcdc.operation && self.stat_select & (mode | lyc) != 0;
+        if signal && !self.stat_signal {
+            self.is_stat_interrupt = true;
+        }
+        self.stat_signal = signal;
+    }
+
+    pub fn load_vram_bank(&self) -> u8 {
+        0xfe | self.vram_bank as u8
+    }
+
+    pub fn store_vram_bank(&mut self, value: u8) {
+        self.vram_bank = (value & 0x1) as usize;
+    }
+
+    fn vram_accessible(&self) -> bool {
+        !self.access_block || self.mode != GpuMode::ScanlineVRAM
+    }
+
+    fn oam_accessible(&self) -> bool {
+        !self.access_block ||
+            (self.mode != GpuMode::ScanlineOAM && self.mode != GpuMode::ScanlineVRAM)
     }
 
-    pub fn get_tile_line(&self, tile_idx: usize, line_idx: usize) -> Vec<u8> {
-        assert!(line_idx < 8);
-        let addr = (tile_idx * 8 + line_idx) * 2;
+    /// write OAM without access check, used by DMA
+    pub fn write_oam(&mut self, idx: usize, value: u8) {
+        if let Some(elem) = self.oam.get_mut(idx) {
+            *elem = value;
+            self.update_sprite(idx);
+        }
+    }
 
-        let byte1 = self.vram[addr];
-        let byte2 = self.vram[addr+1];
+    /// write VRAM of current bank without access check, used by HDMA
+    pub fn write_vram(&mut self, addr: u16, value: u8) {
+        let addr = self.vram_bank * VRAM_BANK_SIZE + (addr - VRAM_START) as usize;
+        if let Some(elem) = self.vram.get_mut(addr) {
+            *elem = value;
+        }
+    }
 
-        let mut pxs = Vec::with_capacity(8);
+    /// read VRAM of current bank or OAM without access check, used by debugger
+    pub fn peek(&self, addr: u16) -> Result<u8, ()> {
+        let elem = match addr {
+            VRAM_START ..= VRAM_END => self.vram.get(self.vram_bank * VRAM_BANK_SIZE + (addr - VRAM_START) as usize),
+            OAM_START ..= OAM_END => self.oam.get((addr - OAM_START) as usize),
+            _ => None,
+        };
+        elem.copied().ok_or(())
+    }
 
-        for j in (0..8).rev() {
-            let bit1 = (byte1 >> j) & 0x1;
-            let bit2 = (byte2 >> j) & 0x1;
-            let pixel = bit1 << 1 | bit2;
-            pxs.push(pixel);
+    /// write VRAM of current bank or OAM without access check, used by debugger
+    pub fn poke(&mut self, addr: u16, value: u8) -> Result<(), ()> {
+        match addr {
+            VRAM_START ..= VRAM_END => self.write_vram(addr, value),
+            OAM_START ..= OAM_END => self.write_oam((addr - OAM_START) as usize, value),
+            _ => return Err(()),
         }
-        pxs
+        Ok(())
     }
 
-    fn pixel_to_color(&self, pixel: u8) -> u32 {
+    fn pixel_to_color(&self, palette: &Palette, pixel: u8) -> u32 {
         match pixel {
-            3 => BLACK,
-            2 => DGRAY,
-            1 => LGRAY,
-            0 => WHITE,
+            0 ..= 3 => palette.color(pixel),
             _ => panic!("Invalid value in u8_to_grayscale"),
         }
     }
@@ -196,107 +549,288 @@ impl Gpu {
         }
     }
 
-    fn build_background(&self, buffer: &mut Vec<u32>) {
-        for row in 0..HEIGHT {
-            let offset_row = row + self.scy as usize;
-            let tile_row = offset_row / 8;
-            let line_idx = offset_row % 8;
-            for col in 0..(WIDTH/8) {
-                let offset_col = col + self.scx as usize;
-                let tile_addr = tile_row * 32 + offset_col + (0x9800 - 0x8000);
-                let tile_idx = self.vram[tile_addr] as usize;
-                let pixels = self.get_tile_line(tile_idx, line_idx);
+    /// background or window pixel at (x, y) of tile map
+    fn map_pixel(&self, map: usize, x: u8, y: u8) -> BgPixel {
+        let offset = map + (y as usize / 8) * 32 + x as usize / 8;
+        let tile_idx = self.vram[offset];
+        let attr = if self.cgb {
+            BgAttribute::from_u8(self.vram[VRAM_BANK_SIZE + offset])
+        } else {
+            Default::default()
+        };
+        let row = if attr.flip_y { 7 - y % 8 } else { y % 8 };
+        let col = if attr.flip_x { 7 - x % 8 } else { x % 8 };
+        let bank = if attr.bank { VRAM_BANK_SIZE } else { 0 };
+        let addr = bank + self.lcdc.tile_data(tile_idx) + row as usize * 2;
+        BgPixel {
+            color: decode_row(self.vram[addr], self.vram[addr + 1])[col as usize],
+            palette: attr.palette,
+            priority: attr.priority,
+        }
+    }
+
+    fn blank_color(&self) -> u32 {
+        if self.cgb {
+            CGB_WHITE
+        } else {
+            self.pixel_to_color(&self.palette.bg, 0)
+        }
+    }
+
+    /// mix background and sprite pixel into color
+    fn mix_pixel(&self, bg: BgPixel, obj: Option<ObjPixel>) -> u32 {
+        if self.cgb {
+            return self.mix_cgb_pixel(bg, obj);
+        }
+        // background and window are white when disabled
+        let bg = if self.lcdc.bg_display { bg.color } else { 0 };
+        if let Some(obj) = obj {
+            if obj.color != 0 && self.lcdc.obj_display && !(obj.priority && bg != 0) {
+                let (palette, colors) = if obj.palette != 0 {
+                    (self.ob1_palette, &self.palette.obp1)
+                } else {
+                    (self.ob0_palette, &self.palette.obp0)
+                };
+                return self.pixel_to_color(colors, self.pixel_map_by_palette(palette, obj.color));
+            }
+        }
+        if self.lcdc.bg_display {
+            self.pixel_to_color(&self.palette.bg, self.pixel_map_by_palette(self.bg_palette, bg))
+        } else {
+            self.pixel_to_color(&self.palette.bg, 0)
+        }
+    }
 
-                let pixel_start = row * WIDTH + col * 8;
-                buffer.splice(pixel_start..(pixel_start + 8),
-                      pixels.iter()
-                            .map(|p| self.pixel_map_by_palette(self.bg_palette, *p))
-                            .map(|p| self.pixel_to_color(p)));
+    fn mix_cgb_pixel(&self, bg: BgPixel, obj: Option<ObjPixel>) -> u32 {
+        if let Some(obj) = obj {
+            // LCDC bit 0 is master priority in CGB mode,
+            // sprites are always on top when it is cleared
+            let bg_on_top = self.lcdc.bg_display && bg.color != 0 &&
+                            (bg.priority || obj.priority);
+            if obj.color != 0 && self.lcdc.obj_display && !bg_on_top {
+                return self.obj_colors.color(obj.palette, obj.color);
             }
         }
+        self.bg_colors.color(bg.palette, bg.color)
     }
 
-    fn build_sprite(&self, buffer: &mut Vec<u32>) {
-        for sprite in self.sprite.iter() {
-            // check sprite intersect with screen
-            if sprite.y + 8 <= 0 || sprite.x + 9 <= 0 ||
-               (sprite.x as usize) > WIDTH || (sprite.y as usize) > HEIGHT {
-                continue;
+    /// search sprites shown on current line, in OAM order
+    fn scan_oam(&mut self) {
+        self.line_sprites.clear();
+        let height = self.lcdc.obj_height();
+        for (idx, sprite) in self.sprite.iter().enumerate() {
+            if self.line_sprites.len() >= LINE_SPRITES {
+                break;
+            }
+            if sprite.on_line(self.line, height) {
+                self.line_sprites.push(idx);
             }
+        }
+    }
 
-            let palette = if sprite.palette_number {
-                self.ob1_palette
-            } else {
-                self.ob0_palette
-            };
+    /// render current line at once, return whether window is drawn
+    fn render_line(&mut self) -> bool {
+        let line = self.line;
+        let mut bg = [BgPixel::default(); WIDTH];
+        let mut obj: [Option<ObjPixel>; WIDTH] = [None; WIDTH];
 
-            for row_idx in 0..8 {
-                let y = sprite.y + row_idx as isize;
-                if y < 0 || (y as usize) > HEIGHT {
-                    continue;
-                }
-                let y_idx = if sprite.flip_y { 7-row_idx } else { row_idx };
-                let pixels = self.get_tile_line(sprite.tile_idx as usize, y_idx);
-                for col_idx in 0..8 {
-                    let x = sprite.x + col_idx as isize;
-                    if x < 0 || (x as usize) > WIDTH {
+        let y = line.wrapping_add(self.scy);
+        for (x, pixel) in bg.iter_mut().enumerate() {
+            *pixel = self.map_pixel(self.lcdc.bg_map(), (x as u8).wrapping_add(self.scx), y);
+        }
+
+        let window_drawn = self.lcdc.window_display && self.window_triggered && self.wx <= 166;
+        if window_drawn {
+            let start = self.wx as isize - 7;
+            for x in start.max(0)..(WIDTH as isize) {
+                bg[x as usize] = self.map_pixel(self.lcdc.window_map(),
+                                                (x - start) as u8, self.window_line);
+            }
+        }
+
+        if self.lcdc.obj_display {
+            // DMG: sprite with smaller x has higher priority,
+            // then the one comes first in OAM
+            // CGB: only the order in OAM matters
+            let mut sprites = self.line_sprites.clone();
+            if !self.cgb {
+                sprites.sort_by_key(|idx| self.sprite[*idx].x);
+            }
+            let height = self.lcdc.obj_height();
+            for idx in sprites.iter() {
+                let sprite = &self.sprite[*idx];
+                let mut pixels = sprite.pixels(&self.vram, line, height, self.cgb);
+                for (i, pixel) in pixels.iter_mut().enumerate() {
+                    pixel.index = *idx as u8;
+                    let x = sprite.x + i as isize;
+                    if x < 0 || x >= WIDTH as isize || pixel.color == 0 {
                         continue;
                     }
-                    let x_idx = if sprite.flip_x { 7-col_idx } else { col_idx };
-                    // fill the buffer
-                    let dibit = self.pixel_map_by_palette(palette, pixels[x_idx]);
-                    if dibit != 0 {
-                        let color = self.pixel_to_color(dibit);
-                        buffer[y as usize * WIDTH + x as usize] = color;
+                    let slot = &mut obj[x as usize];
+                    if slot.is_none() {
+                        *slot = Some(*pixel);
                     }
                 }
             }
         }
-    }
 
-    pub fn build_screen(&self, buffer: &mut Vec<u32>) {
-        if self.lcdc.bg_display {
-            self.build_background(buffer);
+        let start = line as usize * WIDTH;
+        for x in 0..WIDTH {
+            self.frame[start + x] = self.mix_pixel(bg[x], obj[x]);
         }
+        window_drawn
+    }
 
-        if self.lcdc.obj_display {
-            self.build_sprite(buffer);
+    pub fn build_screen(&self, buffer: &mut Vec<u32>) {
+        if !self.lcdc.operation || self.first_frame {
+            let color = self.blank_color();
+            for pixel in buffer.iter_mut() {
+                *pixel = color;
+            }
+            return;
         }
+        buffer.copy_from_slice(&self.frame);
     }
 
     pub fn update(&mut self, clock: u64) {
         // switch state
         self.clock = self.clock.wrapping_add(clock);
+        if !self.lcdc.operation {
+            // LCD off, keep mode 0 and only count frame timing
+            if self.clock >= FRAME_CLOCK {
+                self.clock -= FRAME_CLOCK;
+                self.frame_ready = true;
+            }
+            return;
+        }
+        match self.renderer {
+            Renderer::Scanline => self.update_scanline(),
+            Renderer::Fifo => self.update_fifo(),
+        }
+    }
+
+    fn update_scanline(&mut self) {
+        // clock may cover several modes, e.g. after CPU is stalled by DMA
+        while self.renderer == Renderer::Scanline {
+            match self.mode {
+                GpuMode::ScanlineOAM if self.clock >= OAM_CLOCK => {
+                    self.clock -= OAM_CLOCK;
+                    self.start_transfer();
+                },
+                GpuMode::ScanlineVRAM if self.clock >= VRAM_CLOCK => {
+                    self.clock -= VRAM_CLOCK;
+                    let window_drawn = self.render_line();
+                    self.end_transfer(window_drawn);
+                },
+                GpuMode::HBlank if self.clock >= LINE_CLOCK - OAM_CLOCK - VRAM_CLOCK => {
+                    self.clock -= LINE_CLOCK - OAM_CLOCK - VRAM_CLOCK;
+                    self.next_line();
+                },
+                GpuMode::VBlank if self.clock >= LINE_CLOCK => {
+                    self.clock -= LINE_CLOCK;
+                    self.next_line();
+                },
+                _ => break,
+            }
+        }
+    }
+
+    fn update_fifo(&mut self) {
+        // run dot by dot, mode 3 ends when 160 pixels are pushed
+        while self.clock > 0 && self.renderer == Renderer::Fifo {
+            self.clock -= 1;
+            self.dot += 1;
+            match self.mode {
+                GpuMode::ScanlineOAM if self.dot >= OAM_CLOCK => {
+                    self.start_transfer();
+                },
+                GpuMode::ScanlineVRAM => {
+                    let ctx = self.fetch_context();
+                    let output = self.fifo.tick(&ctx, &self.vram, &self.sprite, &self.line_sprites);
+                    if let Some((x, bg, obj)) = output {
+                        self.frame[self.line as usize * WIDTH + x] = self.mix_pixel(bg, obj);
+                    }
+                    if self.fifo.finished() {
+                        let window_drawn = self.fifo.window_used();
+                        self.end_transfer(window_drawn);
+                    }
+                },
+                GpuMode::HBlank | GpuMode::VBlank if self.dot >= LINE_CLOCK => {
+                    self.dot = 0;
+                    self.next_line();
+                },
+                _ => {},
+            }
+        }
+    }
+
+    fn fetch_context(&self) -> FetchContext {
+        FetchContext {
+            lcdc: self.lcdc,
+            scx: self.scx,
+            scy: self.scy,
+            wx: self.wx,
+            line: self.line,
+            window_line: self.window_line,
+            window_triggered: self.window_triggered,
+            cgb: self.cgb,
+        }
+    }
+
+    /// mode 2 to mode 3
+    fn start_transfer(&mut self) {
+        if self.line == self.wy {
+            self.window_triggered = true;
+        }
+        self.scan_oam();
+        if self.renderer == Renderer::Fifo {
+            self.fifo.start_line(self.scx);
+        }
+        self.mode = GpuMode::ScanlineVRAM;
+        self.update_stat();
+    }
+
+    /// mode 3 to mode 0
+    fn end_transfer(&mut self, window_drawn: bool) {
+        if window_drawn {
+            self.window_line = self.window_line.wrapping_add(1);
+        }
+        self.mode = GpuMode::HBlank;
+        self.hblank_entered = true;
+        self.update_stat();
+    }
+
+    fn start_frame(&mut self) {
+        self.window_triggered = false;
+        self.window_line = 0;
+        if self.renderer != self.next_renderer {
+            self.renderer = self.next_renderer;
+            self.dot = 0;
+        }
+    }
+
+    /// end of mode 0 or a line in mode 1
+    fn next_line(&mut self) {
+        self.line += 1;
         match self.mode {
-            GpuMode::ScanlineOAM if self.clock >= 80 => {
-                self.clock -= 80;
-                self.mode = GpuMode::ScanlineVRAM;
+            GpuMode::HBlank if self.line as usize >= HEIGHT => {
+                self.mode = GpuMode::VBlank;
+                // enable vblank interrupt
+                self.is_interrupt = true;
+                self.frame_ready = true;
             },
-            GpuMode::ScanlineVRAM if self.clock >= 172 => {
-                self.clock -= 172;
-                self.mode = GpuMode::HBlank;
+            GpuMode::HBlank => {
+                self.mode = GpuMode::ScanlineOAM;
             },
-            GpuMode::HBlank if self.clock >= 204 => {
-                self.clock -= 204;
-                if self.line >= 143 {
-                    self.mode = GpuMode::VBlank;
-                    // enable vblank interrupt
-                    self.is_interrupt = true;
-                } else {
-                    self.mode = GpuMode::ScanlineOAM;
-                }
-                self.line += 1;
-            },
-            GpuMode::VBlank if self.clock >= 456 => {
-                self.clock -= 456;
-                self.line += 1;
-                if self.line >= 153 {
-                    self.line = 0;
-                    self.mode = GpuMode::ScanlineOAM;
-                }
+            GpuMode::VBlank if self.line > LAST_LINE => {
+                self.line = 0;
+                self.mode = GpuMode::ScanlineOAM;
+                self.first_frame = false;
+                self.start_frame();
             },
             _ => {},
         }
+        self.update_stat();
     }
 
     fn update_sprite(&mut self, addr: usize) {
@@ -311,6 +845,8 @@ impl Gpu {
                 self.sprite[sprite_idx].flip_y         = ((value >> 0x6) & 0x1) != 0;
                 self.sprite[sprite_idx].flip_x         = ((value >> 0x5) & 0x1) != 0;
                 self.sprite[sprite_idx].palette_number = ((value >> 0x4) & 0x1) != 0;
+                self.sprite[sprite_idx].bank           = ((value >> 0x3) & 0x1) != 0;
+                self.sprite[sprite_idx].cgb_palette    = value & 0x7;
             }
             _ => {},
         }
@@ -321,13 +857,19 @@ impl Device for Gpu {
     fn load(&self, addr: u16) -> Result<u8, ()> {
         match addr {
             VRAM_START ..= VRAM_END => {
-                let addr = (addr - VRAM_START) as usize;
+                if !self.vram_accessible() {
+                    return Ok(0xff);
+                }
+                let addr = self.vram_bank * VRAM_BANK_SIZE + (addr - VRAM_START) as usize;
                 match self.vram.get(addr) {
                     Some(elem) => Ok(*elem),
                     None => Err(()),
                 }
             }
             OAM_START ..= OAM_END => {
+                if !self.oam_accessible() {
+                    return Ok(0xff);
+                }
                 let addr = (addr - OAM_START) as usize;
                 match self.oam.get(addr) {
                     Some(elem) => Ok(*elem),
@@ -341,8 +883,11 @@ impl Device for Gpu {
     fn store(&mut self, addr: u16, value: u8) -> Result<(), ()> {
         match addr {
             VRAM_START ..= VRAM_END => {
-                let addr = (addr - VRAM_START) as usize;
-                match self.vram.get_mut(addr as usize) {
+                if !self.vram_accessible() {
+                    return Ok(());
+                }
+                let addr = self.vram_bank * VRAM_BANK_SIZE + (addr - VRAM_START) as usize;
+                match self.vram.get_mut(addr) {
                     Some(elem) => {
                         *elem = value;
                         Ok(())
@@ -351,6 +896,9 @@ impl Device for Gpu {
                 }
             }
             OAM_START ..= OAM_END => {
+                if !self.oam_accessible() {
+                    return Ok(());
+                }
                 let addr = (addr - OAM_START) as usize;
                 match self.oam.get_mut(addr as usize) {
                     Some(elem) => {
@@ -365,3 +913,77 @@ impl Device for Gpu {
         }
     }
 }
+
+/// Colors, renderer and debug settings are not saved, they are given by user
+impl Snapshot for Gpu {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u64(self.clock);
+        w.u64(self.dot);
+        w.u8(self.line);
+        w.u8(self.lyc);
+        w.u8(self.lcdc.to_u8());
+        w.u8(self.stat_select);
+        w.u8(self.bg_palette);
+        w.u8(self.ob0_palette);
+        w.u8(self.ob1_palette);
+        w.u8(self.mode.to_u8());
+        w.u8(self.scy);
+        w.u8(self.scx);
+        w.u8(self.wy);
+        w.u8(self.wx);
+        w.bool(self.window_triggered);
+        w.u8(self.window_line);
+        w.bytes(&self.vram);
+        w.u8(self.vram_bank as u8);
+        w.bytes(&self.oam);
+        let line_sprites: Vec<u8> = self.line_sprites.iter().map(|idx| *idx as u8).collect();
+        w.bytes(&line_sprites);
+        w.bool(self.is_interrupt);
+        w.bool(self.frame_ready);
+        w.bool(self.hblank_entered);
+        w.bool(self.first_frame);
+        self.bg_colors.save_state(w);
+        self.obj_colors.save_state(w);
+        w.bool(self.is_stat_interrupt);
+        w.bool(self.stat_signal);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.clock = r.u64();
+        self.dot = r.u64();
+        self.line = r.u8();
+        self.lyc = r.u8();
+        self.lcdc = LCDC::from_u8(r.u8());
+        self.stat_select = r.u8();
+        self.bg_palette = r.u8();
+        self.ob0_palette = r.u8();
+        self.ob1_palette = r.u8();
+        self.mode = GpuMode::from_u8(r.u8());
+        self.scy = r.u8();
+        self.scx = r.u8();
+        self.wy = r.u8();
+        self.wx = r.u8();
+        self.window_triggered = r.bool();
+        self.window_line = r.u8();
+        r.bytes_into(&mut self.vram);
+        self.vram_bank = (r.u8() & 0x1) as usize;
+        r.bytes_into(&mut self.oam);
+        for addr in 0..self.oam.len() {
+            self.update_sprite(addr);
+        }
+        self.line_sprites = r.bytes().iter()
+            .map(|idx| *idx as usize)
+            .filter(|idx| *idx < self.sprite.len())
+            .collect();
+        self.is_interrupt = r.bool();
+        self.frame_ready = r.bool();
+        self.hblank_entered = r.bool();
+        self.first_frame = r.bool();
+        self.bg_colors.load_state(r);
+        self.obj_colors.load_state(r);
+        self.is_stat_interrupt = r.bool();
+        self.stat_signal = r.bool();
+        // states are taken between frames, pixel FIFO is idle
+        self.fifo = Default::default();
+    }
+}
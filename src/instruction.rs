@@ -31,6 +31,7 @@ pub enum Condition {
 #[derive(Debug)]
 pub enum Instruction {
     NOP,
+    STOP,
     JP(Condition),
     JPHL,
     DI,
@@ -92,6 +93,7 @@ impl Instruction {
     pub fn from_byte(byte: u8) -> Option<Instruction> {
         match byte {
             0x00 => Some(Instruction::NOP),
+            0x10 => Some(Instruction::STOP),
             0xc2 => Some(Instruction::JP(Condition::NotZero)),
             0xc3 => Some(Instruction::JP(Condition::Always)),
             0xca => Some(Instruction::JP(Condition::Zero)),
@@ -342,6 +344,7 @@ impl Instruction {
             Instruction::LDA16SP => 2,
             Instruction::LD8A => 1,
             Instruction::LDA8 => 1,
+            Instruction::STOP => 1,
             Instruction::CALL(_) => 2,
             Instruction::JR(_) => 1,
             Instruction::ADD(Target::D8) => 1,
@@ -360,6 +363,7 @@ impl Instruction {
         // return clock of instruction, default non-taken action
         match self {
             Instruction::NOP => 4,
+            Instruction::STOP => 4,
             Instruction::JP(_) => 12,
             Instruction::JPHL => 4,
             Instruction::DI => 4,
@@ -1,7 +1,9 @@
 use crate::bus::Device;
+use crate::savestate::{Snapshot, StateWriter, StateReader};
 
 pub const JOYPAD_ADDR: u16 = 0xff00;
 
+#[derive(Debug,Clone,Copy,PartialEq)]
 pub enum JoypadKey {
     RIGHT,
     LEFT,
@@ -13,10 +15,42 @@ pub enum JoypadKey {
     START,
 }
 
+impl JoypadKey {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "right"  => Some(JoypadKey::RIGHT),
+            "left"   => Some(JoypadKey::LEFT),
+            "up"     => Some(JoypadKey::UP),
+            "down"   => Some(JoypadKey::DOWN),
+            "a"      => Some(JoypadKey::A),
+            "b"      => Some(JoypadKey::B),
+            "select" => Some(JoypadKey::SELECT),
+            "start"  => Some(JoypadKey::START),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            JoypadKey::RIGHT  => "right",
+            JoypadKey::LEFT   => "left",
+            JoypadKey::UP     => "up",
+            JoypadKey::DOWN   => "down",
+            JoypadKey::A      => "a",
+            JoypadKey::B      => "b",
+            JoypadKey::SELECT => "select",
+            JoypadKey::START  => "start",
+        }
+    }
+}
+
 pub struct Joypad {
     p14: u8,
     p15: u8,
-    mask: u8
+    /// P1 bit 4-5, 0 selects P14 (directions) and P15 (buttons)
+    mask: u8,
+    /// whether joypad interrupt is occured
+    pub is_interrupt: bool,
 }
 
 impl Joypad {
@@ -25,10 +59,36 @@ impl Joypad {
             p14: 0x0F,
             p15: 0x0F,
             mask: 0x30,
+            is_interrupt: false,
+        }
+    }
+
+    /// input lines P10-P13 of selected groups, 0 means pressed
+    fn lines(&self) -> u8 {
+        let mut lines = 0x0F;
+        if self.mask & 0x10 == 0 {
+            lines &= self.p14;
         }
+        if self.mask & 0x20 == 0 {
+            lines &= self.p15;
+        }
+        lines
+    }
+
+    /// interrupt on any line going from high to low
+    fn check_interrupt(&mut self, before: u8) {
+        if before & !self.lines() != 0 {
+            self.is_interrupt = true;
+        }
+    }
+
+    /// any key in selected groups is pressed, which wakes CPU from STOP
+    pub fn is_pressed(&self) -> bool {
+        self.lines() != 0x0F
     }
 
     pub fn presskey(&mut self, key: JoypadKey) {
+        let before = self.lines();
         match key {
             JoypadKey::RIGHT  => self.p14 &= !0x01,
             JoypadKey::LEFT   => self.p14 &= !0x02,
@@ -39,6 +99,19 @@ impl Joypad {
             JoypadKey::SELECT => self.p15 &= !0x04,
             JoypadKey::START  => self.p15 &= !0x08,
         }
+        self.check_interrupt(before);
+    }
+
+    /// state of all keys, P15 lines in the high nibble, 0 means pressed
+    pub fn keys(&self) -> u8 {
+        self.p15 << 4 | self.p14
+    }
+
+    pub fn set_keys(&mut self, keys: u8) {
+        let before = self.lines();
+        self.p14 = keys & 0x0F;
+        self.p15 = keys >> 4;
+        self.check_interrupt(before);
     }
 
     pub fn releasekey(&mut self, key: JoypadKey) {
@@ -56,16 +129,86 @@ impl Joypad {
 }
 
 impl Device for Joypad {
+    /// bit 6-7 are unused and read as 1, bit 0-3 combine selected groups
     fn load(&self, _addr: u16) -> Result<u8, ()> {
-        match self.mask {
-            0x20 => Ok(self.p14), // read P14: Left, Right, Up, Down
-            0x10 => Ok(self.p15), // read P15: A, B, Select, Start
-            _ => Ok(0x0F)     // other value just read nothing
-        }
+        Ok(0xC0 | self.mask | self.lines())
     }
 
     fn store(&mut self, _addr: u16, value: u8) -> Result<(), ()> {
-        self.mask = value;
+        let before = self.lines();
+        self.mask = value & 0x30;
+        self.check_interrupt(before);
         Ok(())
     }
 }
+
+impl Snapshot for Joypad {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.p14);
+        w.u8(self.p15);
+        w.u8(self.mask);
+        w.bool(self.is_interrupt);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.p14 = r.u8();
+        self.p15 = r.u8();
+        self.mask = r.u8();
+        self.is_interrupt = r.bool();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn p1(joypad: &Joypad) -> u8 {
+        joypad.load(JOYPAD_ADDR).unwrap()
+    }
+
+    #[test]
+    fn p1_reads_selected_groups() {
+        let mut joypad = Joypad::new();
+        joypad.presskey(JoypadKey::RIGHT);
+        joypad.presskey(JoypadKey::B);
+        assert_eq!(p1(&joypad), 0xff);
+
+        joypad.store(JOYPAD_ADDR, 0x20).unwrap();
+        assert_eq!(p1(&joypad), 0xe0 | 0x0e);
+        joypad.store(JOYPAD_ADDR, 0x10).unwrap();
+        assert_eq!(p1(&joypad), 0xd0 | 0x0d);
+        // both groups selected, lines are combined
+        joypad.store(JOYPAD_ADDR, 0x00).unwrap();
+        assert_eq!(p1(&joypad), 0xc0 | 0x0c);
+
+        joypad.releasekey(JoypadKey::RIGHT);
+        assert_eq!(p1(&joypad), 0xc0 | 0x0d);
+    }
+
+    #[test]
+    fn interrupt_on_high_to_low() {
+        let mut joypad = Joypad::new();
+        joypad.store(JOYPAD_ADDR, 0x20).unwrap();
+
+        // group not selected
+        joypad.presskey(JoypadKey::A);
+        assert!(!joypad.is_interrupt);
+        joypad.presskey(JoypadKey::DOWN);
+        assert!(joypad.is_interrupt);
+
+        // release is low to high
+        joypad.is_interrupt = false;
+        joypad.releasekey(JoypadKey::DOWN);
+        assert!(!joypad.is_interrupt);
+
+        // selecting a group with A held pulls P10 low
+        joypad.store(JOYPAD_ADDR, 0x10).unwrap();
+        assert!(joypad.is_interrupt);
+
+        // line already low
+        joypad.is_interrupt = false;
+        joypad.store(JOYPAD_ADDR, 0x00).unwrap();
+        joypad.presskey(JoypadKey::RIGHT);
+        assert!(!joypad.is_interrupt);
+    }
+}
@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use minifb::Key;
+use serde::Deserialize;
+
+use crate::joypad::JoypadKey;
+
+/// Emulator functions triggered by keyboard
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Hotkey {
+    /// switch to next color palette
+    Palette,
+    Pause,
+    /// pause and run a single frame
+    FrameAdvance,
+    Reset,
+    /// run faster while held
+    FastForward,
+    /// run slower while held
+    SlowMotion,
+    /// step back in time while held
+    Rewind,
+    Screenshot,
+    /// save state to current slot
+    SaveState,
+    /// load state from current slot
+    LoadState,
+    /// select next save slot
+    NextSlot,
+    /// break into debugger
+    Debug,
+    /// open or close memory viewer window
+    MemoryViewer,
+    /// switch between scanline and FIFO renderer
+    Renderer,
+}
+
+/// What a host key does
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Action {
+    Joypad(JoypadKey),
+    Hotkey(Hotkey),
+}
+
+impl Action {
+    pub fn from_name(name: &str) -> Option<Self> {
+        if let Some(key) = JoypadKey::from_name(name) {
+            return Some(Action::Joypad(key));
+        }
+        match name {
+            "palette"       => Some(Action::Hotkey(Hotkey::Palette)),
+            "pause"         => Some(Action::Hotkey(Hotkey::Pause)),
+            "frame-advance" => Some(Action::Hotkey(Hotkey::FrameAdvance)),
+            "reset"         => Some(Action::Hotkey(Hotkey::Reset)),
+            "fast-forward"  => Some(Action::Hotkey(Hotkey::FastForward)),
+            "slow-motion"   => Some(Action::Hotkey(Hotkey::SlowMotion)),
+            "rewind"        => Some(Action::Hotkey(Hotkey::Rewind)),
+            "screenshot"    => Some(Action::Hotkey(Hotkey::Screenshot)),
+            "save-state"    => Some(Action::Hotkey(Hotkey::SaveState)),
+            "load-state"    => Some(Action::Hotkey(Hotkey::LoadState)),
+            "next-slot"     => Some(Action::Hotkey(Hotkey::NextSlot)),
+            "debug"         => Some(Action::Hotkey(Hotkey::Debug)),
+            "memory-viewer" => Some(Action::Hotkey(Hotkey::MemoryViewer)),
+            "renderer"      => Some(Action::Hotkey(Hotkey::Renderer)),
+            _ => None,
+        }
+    }
+}
+
+/// Keys can be bound, named as minifb::Key in case insensitive
+const KEYS: [Key; 97] = [
+    Key::Key0, Key::Key1, Key::Key2, Key::Key3, Key::Key4,
+    Key::Key5, Key::Key6, Key::Key7, Key::Key8, Key::Key9,
+    Key::A, Key::B, Key::C, Key::D, Key::E, Key::F, Key::G, Key::H, Key::I,
+    Key::J, Key::K, Key::L, Key::M, Key::N, Key::O, Key::P, Key::Q, Key::R,
+    Key::S, Key::T, Key::U, Key::V, Key::W, Key::X, Key::Y, Key::Z,
+    Key::F1, Key::F2, Key::F3, Key::F4, Key::F5, Key::F6, Key::F7, Key::F8,
+    Key::F9, Key::F10, Key::F11, Key::F12, Key::F13, Key::F14, Key::F15,
+    Key::Down, Key::Left, Key::Right, Key::Up,
+    Key::Apostrophe, Key::Backquote, Key::Backslash, Key::Comma, Key::Equal,
+    Key::LeftBracket, Key::Minus, Key::Period, Key::RightBracket,
+    Key::Semicolon, Key::Slash, Key::Backspace, Key::Delete, Key::End,
+    Key::Enter, Key::Home, Key::Insert, Key::Menu, Key::PageDown, Key::PageUp,
+    Key::Pause, Key::Space, Key::Tab, Key::LeftShift, Key::RightShift,
+    Key::LeftCtrl, Key::RightCtrl, Key::LeftAlt, Key::RightAlt,
+    Key::NumPad0, Key::NumPad1, Key::NumPad2, Key::NumPad3, Key::NumPad4,
+    Key::NumPad5, Key::NumPad6, Key::NumPad7, Key::NumPad8, Key::NumPad9,
+    Key::NumPadDot, Key::NumPadPlus, Key::NumPadMinus,
+];
+
+fn key_from_name(name: &str) -> Option<Key> {
+    KEYS.iter()
+        .find(|key| format!("{:?}", key).eq_ignore_ascii_case(name))
+        .copied()
+}
+
+/// Content of config file
+#[derive(Deserialize,Default)]
+struct Config {
+    /// action name to key names
+    #[serde(default)]
+    keys: HashMap<String, Vec<String>>,
+}
+
+/// Bindings from host keys to actions, a key may trigger several actions
+/// and an action may be bound to several keys
+pub struct KeyMap {
+    bindings: Vec<(Key, Action)>,
+}
+
+impl KeyMap {
+    /// config file in user's config directory, e.g. ~/.config/ruGameboy/config.toml
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("ruGameboy").join("config.toml"))
+    }
+
+    /// Load bindings from TOML config file, replacing bindings of
+    /// actions given in file. Escape is reserved to quit.
+    ///
+    ///     [keys]
+    ///     up = ["Up", "K"]
+    ///     start = ["Enter"]
+    ///     fast-forward = ["Tab"]
+    pub fn load_file(&mut self, path: &Path) -> Result<(), String> {
+        let content = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let config: Config = toml::from_str(&content).map_err(|e| format!("{}: {}", path.display(), e))?;
+        for (action, keys) in config.keys.iter() {
+            self.bind_names(action, keys).map_err(|e| format!("{}: {}", path.display(), e))?;
+        }
+        Ok(())
+    }
+
+    /// Parse binding in form of action=key[,key...]
+    pub fn parse_binding(&mut self, binding: &str) -> Result<(), String> {
+        let mut kv = binding.splitn(2, '=');
+        let action = kv.next().unwrap_or("").trim();
+        let keys = kv.next().ok_or(format!("expect action=keys, found {}", binding))?;
+        let keys: Vec<String> = keys.split(',').map(|key| key.trim().to_string()).collect();
+        self.bind_names(action, &keys)
+    }
+
+    fn bind_names(&mut self, action: &str, keys: &[String]) -> Result<(), String> {
+        let action = Action::from_name(action).ok_or(format!("unknown action {}", action))?;
+        let keys = keys.iter()
+            .map(|name| key_from_name(name).ok_or(format!("unknown key {}", name)))
+            .collect::<Result<Vec<Key>, String>>()?;
+        self.bind(action, &keys);
+        Ok(())
+    }
+
+    /// replace keys bound to action
+    pub fn bind(&mut self, action: Action, keys: &[Key]) {
+        self.bindings.retain(|(_, a)| *a != action);
+        for key in keys {
+            self.bindings.push((*key, action));
+        }
+    }
+
+    /// actions triggered by key
+    pub fn actions(&self, key: Key) -> Vec<Action> {
+        self.bindings.iter()
+            .filter(|(k, _)| *k == key)
+            .map(|(_, action)| *action)
+            .collect()
+    }
+
+    /// keys bound to action
+    pub fn keys(&self, action: Action) -> Vec<Key> {
+        self.bindings.iter()
+            .filter(|(_, a)| *a == action)
+            .map(|(key, _)| *key)
+            .collect()
+    }
+}
+
+impl Default for KeyMap {
+    fn default() -> Self {
+        let mut keymap = KeyMap { bindings: Vec::new() };
+        keymap.bind(Action::Joypad(JoypadKey::UP),     &[Key::Up]);
+        keymap.bind(Action::Joypad(JoypadKey::DOWN),   &[Key::Down]);
+        keymap.bind(Action::Joypad(JoypadKey::LEFT),   &[Key::Left]);
+        keymap.bind(Action::Joypad(JoypadKey::RIGHT),  &[Key::Right]);
+        keymap.bind(Action::Joypad(JoypadKey::START),  &[Key::A]);
+        keymap.bind(Action::Joypad(JoypadKey::SELECT), &[Key::S]);
+        keymap.bind(Action::Joypad(JoypadKey::A),      &[Key::Z]);
+        keymap.bind(Action::Joypad(JoypadKey::B),      &[Key::X]);
+        keymap.bind(Action::Hotkey(Hotkey::Palette),      &[Key::P]);
+        keymap.bind(Action::Hotkey(Hotkey::Pause),        &[Key::Space]);
+        keymap.bind(Action::Hotkey(Hotkey::FrameAdvance), &[Key::N]);
+        keymap.bind(Action::Hotkey(Hotkey::Reset),        &[Key::R]);
+        keymap.bind(Action::Hotkey(Hotkey::FastForward),  &[Key::Tab]);
+        keymap.bind(Action::Hotkey(Hotkey::SlowMotion),   &[Key::LeftShift]);
+        keymap.bind(Action::Hotkey(Hotkey::Rewind),       &[Key::Backspace]);
+        keymap.bind(Action::Hotkey(Hotkey::Screenshot),   &[Key::F12]);
+        keymap.bind(Action::Hotkey(Hotkey::SaveState),    &[Key::F5]);
+        keymap.bind(Action::Hotkey(Hotkey::NextSlot),     &[Key::F6]);
+        keymap.bind(Action::Hotkey(Hotkey::LoadState),    &[Key::F7]);
+        keymap.bind(Action::Hotkey(Hotkey::Debug),        &[Key::F9]);
+        keymap.bind(Action::Hotkey(Hotkey::MemoryViewer), &[Key::F10]);
+        keymap.bind(Action::Hotkey(Hotkey::Renderer),     &[Key::F8]);
+        keymap
+    }
+}
@@ -1,9 +1,13 @@
+// fields are initialized as `field: field`, and registers, opcodes and
+// models keep the upper case names of the hardware docs
+#![allow(clippy::redundant_field_names, clippy::upper_case_acronyms)]
+
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io;
 use std::io::prelude::*;
-use log::{error, debug};
-use clap::{App, Arg};
+use log::{error, debug, info};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 
 #[macro_use]
 extern crate num_derive;
@@ -11,18 +15,59 @@ use minifb::{Key, Window, WindowOptions, KeyRepeat};
 
 mod cpu;
 mod gpu;
+mod fifo;
 mod register;
 mod instruction;
 mod bus;
+mod dma;
 mod memory;
 mod vm;
 mod timer;
 mod joypad;
+mod palette;
+mod boot;
+mod keymap;
+mod screenshot;
+mod checksum;
+mod movie;
+mod savestate;
+mod rewind;
+mod pacer;
+mod disasm;
+mod debugger;
+mod watch;
+mod gdb;
+mod symbols;
+mod trace;
+mod callstack;
+mod profiler;
+mod coverage;
+mod memview;
 
 use vm::{Vm, WIDTH, HEIGHT};
-use joypad::{JoypadKey};
+use gpu::Renderer;
+use palette::PaletteSet;
+use boot::Model;
+use keymap::{KeyMap, Action, Hotkey};
+use movie::MoviePlayer;
+use savestate::{slot_path, STATE_SLOTS};
+use rewind::Rewind;
+use pacer::{FramePacer, Speed};
+use debugger::Debugger;
+use gdb::GdbStub;
+use symbols::Symbols;
+use trace::Trace;
+use profiler::Profiler;
+use coverage::Coverage;
+use memview::MemoryViewer;
+use std::path::{Path, PathBuf};
 
+const TITLE: &str = "rust Gameboy";
 const MAX_ENLARGE_SCALE: usize = 5;
+/// Maximum factor of fast forward and slow motion
+const MAX_SPEED_FACTOR: u32 = 16;
+/// Maximum memory for rewind in MiB
+const MAX_REWIND_SIZE: usize = 4096;
 
 fn arg_check_range<T>(arg: &str, range: (T, T)) -> Result<T, String>
     where T: Ord + std::str::FromStr + std::fmt::Display
@@ -35,20 +80,437 @@ fn arg_check_range<T>(arg: &str, range: (T, T)) -> Result<T, String>
     }
 }
 
+/// run a frame with inputs from movie, enter debugger when it breaks
+fn run_frame(vm: &mut Vm, movie: &mut MoviePlayer, debugger: &mut Debugger) -> Result<(), String> {
+    let frame = vm.frame;
+    for (key, pressed) in movie.inputs(frame) {
+        if pressed {
+            vm.cpu.bus.joypad.presskey(key);
+        } else {
+            vm.cpu.bus.joypad.releasekey(key);
+        }
+    }
+    debugger.poll();
+    while vm.run_until(|cpu| debugger.should_break(cpu)).map_err(|_| {
+                debugger.report_crash(&vm.cpu);
+                format!("emulation stopped at frame {}", frame)
+            })? {
+        debugger.repl(vm);
+        if debugger.quit {
+            return Ok(());
+        }
+    }
+    movie.end_frame(frame, vm.checksum())
+}
+
+/// run without window for given frames or until movie ends
+fn run_headless(vm: &mut Vm, movie: &mut MoviePlayer, debugger: &mut Debugger,
+                frames: Option<u64>) -> Result<(), String> {
+    while !frames.map_or(movie.is_finished(), |n| vm.frame >= n) && !debugger.quit {
+        run_frame(vm, movie, debugger)?;
+    }
+    info!("frame {} state {:08x}", vm.frame, vm.checksum());
+    Ok(())
+}
+
+/// save state of vm to slot file
+fn save_state(vm: &Vm, path: &Path) -> Result<(), String> {
+    fs::write(path, vm.save_state()).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// load state of vm from slot file
+fn load_state(vm: &mut Vm, path: &Path) -> Result<(), String> {
+    let data = fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    vm.load_state(&data).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// options of the window given on command line
+struct Frontend<'a> {
+    keymap: &'a KeyMap,
+    /// slot files are saved next to the ROM
+    rom_path: &'a str,
+    palettes: &'a [PaletteSet],
+    /// palette selected at start
+    palette_idx: usize,
+    scale: usize,
+    fast_speed: Speed,
+    slow_speed: Speed,
+    /// power on a new vm with palette, on reset
+    new_vm: &'a dyn Fn(&PaletteSet) -> Vm,
+}
+
+fn run_window(vm: &mut Vm, movie: &mut MoviePlayer, debugger: &mut Debugger, rewind: &mut Rewind,
+              frontend: Frontend) -> Result<(), String> {
+    let Frontend { keymap, rom_path, palettes, mut palette_idx, scale, fast_speed, slow_speed, new_vm } = frontend;
+    let mut paused = false;
+    let mut advance = false;
+    let mut slot = 0;
+    let mut pacer = FramePacer::new();
+    let mut viewer: Option<MemoryViewer> = None;
+    let mut window = Window::new(
+        TITLE,
+        WIDTH * scale,
+        HEIGHT * scale,
+        WindowOptions::default(),
+    ).unwrap_or_else(|e| { panic!("{}", e); });
+    // frames are paced by FramePacer instead of window refresh
+    window.limit_update_rate(None);
+
+    while window.is_open() && !window.is_key_down(Key::Escape) && !debugger.quit {
+        let refreshed = std::time::Instant::now();
+
+        // check key press
+        for key in window.get_keys_pressed(KeyRepeat::No).unwrap_or_default() {
+            for action in keymap.actions(key) {
+                match action {
+                    Action::Joypad(key) => {
+                        if movie.input(vm.frame, key, true) {
+                            vm.cpu.bus.joypad.presskey(key);
+                        }
+                    },
+                    Action::Hotkey(Hotkey::Palette) => {
+                        palette_idx = (palette_idx + 1) % palettes.len();
+                        info!("palette: {}", palettes[palette_idx].name);
+                        vm.cpu.bus.gpu.palette = palettes[palette_idx].clone();
+                    },
+                    Action::Hotkey(Hotkey::Pause) => {
+                        paused = !paused;
+                        info!("{}", if paused { "pause" } else { "resume" });
+                    },
+                    Action::Hotkey(Hotkey::FrameAdvance) => {
+                        paused = true;
+                        advance = true;
+                    },
+                    Action::Hotkey(Hotkey::Debug) => {
+                        paused = false;
+                        debugger.pause();
+                    },
+                    Action::Hotkey(Hotkey::MemoryViewer) if viewer.is_some() => viewer = None,
+                    Action::Hotkey(Hotkey::MemoryViewer) => {
+                        match MemoryViewer::new() {
+                            Ok(v) => viewer = Some(v),
+                            Err(e) => error!("{}", e),
+                        }
+                    },
+                    Action::Hotkey(Hotkey::Reset) if movie.is_active() => {
+                        info!("reset is disabled with movie");
+                    },
+                    Action::Hotkey(Hotkey::Reset) => {
+                        info!("reset");
+                        // code/data log and renderer switched by user are kept over reset
+                        let coverage = vm.cpu.bus.coverage.take();
+                        let renderer = vm.cpu.bus.gpu.next_renderer();
+                        *vm = new_vm(&palettes[palette_idx]);
+                        vm.cpu.bus.coverage = coverage;
+                        vm.cpu.bus.gpu.set_renderer(renderer);
+                        rewind.clear();
+                    },
+                    // movie is played with the renderer it is recorded
+                    Action::Hotkey(Hotkey::Renderer) if movie.is_active() => {
+                        info!("renderer switch is disabled with movie");
+                    },
+                    Action::Hotkey(Hotkey::Renderer) => {
+                        let renderer = match vm.cpu.bus.gpu.next_renderer() {
+                            Renderer::Scanline => Renderer::Fifo,
+                            Renderer::Fifo => Renderer::Scanline,
+                        };
+                        info!("renderer: {} from next frame", renderer.name());
+                        vm.cpu.bus.gpu.set_renderer(renderer);
+                        // frames before would be run again with another renderer
+                        rewind.clear();
+                    },
+                    Action::Hotkey(Hotkey::Screenshot) => {
+                        let secs = std::time::SystemTime::now()
+                            .duration_since(std::time::UNIX_EPOCH)
+                            .map_or(0, |d| d.as_secs());
+                        let path = format!("screenshot-{}.png", secs);
+                        match screenshot::save(&path, &vm.buffer, WIDTH, HEIGHT) {
+                            Ok(_) => info!("screenshot saved to {}", path),
+                            Err(e) => error!("screenshot: {}", e),
+                        }
+                    },
+                    Action::Hotkey(Hotkey::NextSlot) => {
+                        slot = (slot + 1) % STATE_SLOTS;
+                        info!("slot: {}", slot);
+                    },
+                    Action::Hotkey(Hotkey::SaveState) => {
+                        let path = slot_path(rom_path, slot);
+                        match save_state(vm, &path) {
+                            Ok(_) => info!("state saved to {}", path.display()),
+                            Err(e) => error!("save state: {}", e),
+                        }
+                    },
+                    Action::Hotkey(Hotkey::LoadState) if movie.is_active() => {
+                        info!("load state is disabled with movie");
+                    },
+                    Action::Hotkey(Hotkey::LoadState) => {
+                        let path = slot_path(rom_path, slot);
+                        match load_state(vm, &path) {
+                            Ok(_) => {
+                                info!("state loaded from {}", path.display());
+                                rewind.clear();
+                            },
+                            Err(e) => error!("load state: {}", e),
+                        }
+                    },
+                    // checked when running frames
+                    Action::Hotkey(Hotkey::FastForward) | Action::Hotkey(Hotkey::SlowMotion) |
+                    Action::Hotkey(Hotkey::Rewind) => {},
+                }
+            }
+        }
+
+        // check key release
+        for key in window.get_keys_released().unwrap_or_default() {
+            for action in keymap.actions(key) {
+                if let Action::Joypad(key) = action {
+                    if movie.input(vm.frame, key, false) {
+                        vm.cpu.bus.joypad.releasekey(key);
+                    }
+                }
+            }
+        }
+
+        let held = |hotkey| keymap.keys(Action::Hotkey(hotkey))
+                                  .iter()
+                                  .any(|key| window.is_key_down(*key));
+        let speed = match (paused, held(Hotkey::FastForward), held(Hotkey::SlowMotion)) {
+            (true, _, _) => Speed::Paused,
+            (false, true, _) => fast_speed,
+            (false, false, true) => slow_speed,
+            (false, false, false) => Speed::Normal,
+        };
+        // movie inputs are bound to frames, rewind would break them
+        if held(Hotkey::Rewind) && !movie.is_active() {
+            pacer.run(Speed::Normal, || rewind.step_back(vm))?;
+        } else if advance {
+            run_frame(vm, movie, debugger)?;
+            rewind.push(vm);
+        } else {
+            pacer.run(speed, || {
+                run_frame(vm, movie, debugger)?;
+                rewind.push(vm);
+                Ok(!debugger.quit)
+            })?;
+        }
+        advance = false;
+
+        if let Some((fps, ratio)) = pacer.report() {
+            let title = if paused {
+                format!("{} - paused", TITLE)
+            } else {
+                format!("{} - {:.0}% {:.1} fps", TITLE, ratio * 100.0, fps)
+            };
+            window.set_title(&title);
+        }
+        window.update_with_buffer(&vm.buffer, WIDTH, HEIGHT).unwrap();
+        if let Some(v) = viewer.as_mut() {
+            v.update(&mut vm.cpu.bus);
+            if !v.is_open() {
+                viewer = None;
+            }
+        }
+        pacer.wait(refreshed);
+    }
+    Ok(())
+}
+
+/// parse address in hex, optionally prefixed by "0x" or '$'
+fn parse_addr(arg: &str) -> Result<u16, String> {
+    let hex = arg.trim_start_matches("0x").trim_start_matches('$');
+    u16::from_str_radix(hex, 16).map_err(|_| format!("Invalid address {}", arg))
+}
+
+/// load symbols from file, or from ROM path with extension .sym if it exists
+fn load_symbols(path: Option<&str>, rom_path: &str) -> Result<Symbols, String> {
+    let symbols = match path {
+        Some(path) => Symbols::load(Path::new(path))?,
+        None => Symbols::for_rom(Path::new(rom_path))?,
+    };
+    if symbols.len() > 0 {
+        info!("symbols: {} labels", symbols.len());
+    }
+    Ok(symbols)
+}
+
+/// disasm subcommand, print instructions of a ROM bank
+fn run_disasm(args: &ArgMatches) -> io::Result<()> {
+    let bin_name = args.value_of("binary").unwrap();
+    let bank = args.value_of("bank").unwrap().parse::<usize>().unwrap_or_else(|_| {
+                    error!("bank: Please select an integer as argument");
+                    std::process::exit(1);
+                });
+    let symbols = load_symbols(args.value_of("sym"), bin_name).unwrap_or_else(|e| {
+                    error!("sym: {}", e);
+                    std::process::exit(1);
+                });
+    let from = match args.value_of("from") {
+        Some(addr) => symbols.parse_location(addr).unwrap_or_else(|e| {
+                    error!("from: {}", e);
+                    std::process::exit(1);
+                }),
+        None if bank == 0 => 0x0000,
+        None => disasm::ROM_BANK_SIZE as u16,
+    };
+    let count = args.value_of("count").unwrap().parse::<usize>().unwrap_or_else(|_| {
+                    error!("count: Please select an integer as argument");
+                    std::process::exit(1);
+                });
+
+    let binary = fs::read(bin_name)?;
+    let lines = disasm::disassemble(&binary, bank, from, count, &symbols).unwrap_or_else(|e| {
+                    error!("disasm: {}", e);
+                    std::process::exit(1);
+                });
+    for line in lines {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
 fn main() -> io::Result<()> {
     env_logger::init();
 
     let prog = App::new("ruGameboy")
+                    .setting(AppSettings::SubcommandsNegateReqs)
+                    .subcommand(SubCommand::with_name("disasm")
+                            .about("Disassemble instructions of a ROM bank")
+                            .arg(Arg::with_name("bank")
+                                    .help("Set the ROM bank, bank 0 is at 0x0000 and the others at 0x4000")
+                                    .short("b")
+                                    .long("bank")
+                                    .default_value("0"))
+                            .arg(Arg::with_name("from")
+                                    .help("Set the address to start in hex or a label, the start of the bank by default")
+                                    .short("f")
+                                    .long("from")
+                                    .takes_value(true))
+                            .arg(Arg::with_name("count")
+                                    .help("Set the number of instructions to print")
+                                    .short("n")
+                                    .long("count")
+                                    .default_value("32"))
+                            .arg(Arg::with_name("sym")
+                                    .help("Set the symbol file, the binary with extension .sym by default")
+                                    .long("sym")
+                                    .takes_value(true))
+                            .arg(Arg::with_name("binary")
+                                    .help("Set the binary file to disassemble")
+                                    .required(true)))
                     .arg(Arg::with_name("scale")
                             .help("Set the scale of enlarge in range [1-5]")
                             .short("s")
                             .long("scale")
                             .default_value("1"))
+                    .arg(Arg::with_name("no-access-block")
+                            .help("Allow CPU access VRAM and OAM in any LCD mode")
+                            .long("no-access-block"))
+                    .arg(Arg::with_name("renderer")
+                            .help("Set the renderer, fifo is slower but cycle accurate")
+                            .short("r")
+                            .long("renderer")
+                            .possible_values(&["scanline", "fifo"])
+                            .default_value("scanline"))
+                    .arg(Arg::with_name("palette")
+                            .help("Set the color palette: gray, green, pocket, light or one in palette file")
+                            .short("p")
+                            .long("palette")
+                            .default_value("gray"))
+                    .arg(Arg::with_name("palette-file")
+                            .help("Load custom palettes from file")
+                            .long("palette-file")
+                            .takes_value(true))
+                    .arg(Arg::with_name("boot-rom")
+                            .help("Run the boot ROM before catridge")
+                            .long("boot-rom")
+                            .takes_value(true))
+                    .arg(Arg::with_name("model")
+                            .help("Set the hardware model, detected from catridge header by default")
+                            .short("m")
+                            .long("model")
+                            .possible_values(&["dmg0", "dmg", "mgb", "sgb", "cgb", "agb"])
+                            .takes_value(true))
+                    .arg(Arg::with_name("config")
+                            .help("Load key bindings from config file instead of the one in config directory")
+                            .short("c")
+                            .long("config")
+                            .takes_value(true))
+                    .arg(Arg::with_name("bind")
+                            .help("Bind keys to an action, e.g. --bind start=Enter,Space")
+                            .long("bind")
+                            .takes_value(true)
+                            .multiple(true)
+                            .number_of_values(1))
+                    .arg(Arg::with_name("record")
+                            .help("Record inputs to movie file")
+                            .long("record")
+                            .takes_value(true)
+                            .conflicts_with("play"))
+                    .arg(Arg::with_name("play")
+                            .help("Play inputs from movie file, exit with error if it desyncs")
+                            .long("play")
+                            .takes_value(true))
+                    .arg(Arg::with_name("headless")
+                            .help("Run without window, until the movie ends or frames are run")
+                            .long("headless"))
+                    .arg(Arg::with_name("frames")
+                            .help("Set the number of frames to run in headless mode")
+                            .long("frames")
+                            .takes_value(true))
+                    .arg(Arg::with_name("debug")
+                            .help("Start in debugger, which reads commands from stdin")
+                            .short("d")
+                            .long("debug"))
+                    .arg(Arg::with_name("gdb")
+                            .help("Wait for a GDB client on local port, which replaces the debugger command line")
+                            .long("gdb")
+                            .takes_value(true))
+                    .arg(Arg::with_name("sym")
+                            .help("Set the symbol file for debugger, the binary with extension .sym by default")
+                            .long("sym")
+                            .takes_value(true))
+                    .arg(Arg::with_name("trace")
+                            .help("Write CPU state before each instruction to file in gameboy-doctor format")
+                            .long("trace")
+                            .takes_value(true))
+                    .arg(Arg::with_name("trace-range")
+                            .help("Set the range of PC traced as START-END, in hex or labels")
+                            .long("trace-range")
+                            .takes_value(true)
+                            .requires("trace"))
+                    .arg(Arg::with_name("trace-limit")
+                            .help("Set the number of instructions traced")
+                            .long("trace-limit")
+                            .takes_value(true)
+                            .requires("trace"))
+                    .arg(Arg::with_name("profile")
+                            .help("Write cycles by instruction, loop and function to file, and folded stacks to file.folded")
+                            .long("profile")
+                            .takes_value(true))
+                    .arg(Arg::with_name("cdl")
+                            .help("Log ROM bytes run as code, read as data or by DMA to file, merged with its log if it exists")
+                            .long("cdl")
+                            .takes_value(true))
+                    .arg(Arg::with_name("fast-forward")
+                            .help("Set the speed of fast forward in times, 0 for uncapped")
+                            .long("fast-forward")
+                            .default_value("4"))
+                    .arg(Arg::with_name("slow-motion")
+                            .help("Set the times slow motion is slower than normal")
+                            .long("slow-motion")
+                            .default_value("4"))
+                    .arg(Arg::with_name("rewind-size")
+                            .help("Set the memory in MiB kept for rewind, 0 to disable")
+                            .long("rewind-size")
+                            .default_value("32"))
                     .arg(Arg::with_name("binary")
                             .help("Set the binary file to run")
                             .required(true))
                     .get_matches();
 
+    if let Some(args) = prog.subcommand_matches("disasm") {
+        return run_disasm(args);
+    }
     let bin_name = prog.value_of("binary").unwrap();
 
     let scale = prog.value_of("scale").unwrap();
@@ -57,60 +519,194 @@ fn main() -> io::Result<()> {
                     std::process::exit(1);
                 });
 
+    let fast_forward = prog.value_of("fast-forward").unwrap();
+    let fast_speed = match arg_check_range(fast_forward, (0, MAX_SPEED_FACTOR)) {
+        Ok(0) => Speed::Uncapped,
+        Ok(n) => Speed::Fast(n),
+        Err(e) => {
+            error!("fast-forward: {}", e);
+            std::process::exit(1);
+        },
+    };
+    let slow_motion = prog.value_of("slow-motion").unwrap();
+    let slow_speed = arg_check_range(slow_motion, (1, MAX_SPEED_FACTOR)).map(Speed::Slow).unwrap_or_else(|e| {
+                    error!("slow-motion: {}", e);
+                    std::process::exit(1);
+                });
+
+    let rewind_size = prog.value_of("rewind-size").unwrap();
+    let rewind_size = arg_check_range(rewind_size, (0, MAX_REWIND_SIZE)).unwrap_or_else(|e| {
+                    error!("rewind-size: {}", e);
+                    std::process::exit(1);
+                });
+
+    let mut palettes = PaletteSet::presets();
+    if let Some(path) = prog.value_of("palette-file") {
+        let custom = PaletteSet::load_file(path).unwrap_or_else(|e| {
+                    error!("palette-file: {}", e);
+                    std::process::exit(1);
+                });
+        palettes.extend(custom);
+    }
+    let palette_name = prog.value_of("palette").unwrap();
+    let palette_idx = palettes.iter().position(|p| p.name == palette_name).unwrap_or_else(|| {
+                    error!("palette: {} not found", palette_name);
+                    std::process::exit(1);
+                });
+
+    let mut keymap = KeyMap::default();
+    let config = match prog.value_of("config") {
+        Some(path) => Some(PathBuf::from(path)),
+        // default config file is optional
+        None => KeyMap::default_path().filter(|path| path.exists()),
+    };
+    if let Some(path) = config {
+        keymap.load_file(&path).unwrap_or_else(|e| {
+                    error!("config: {}", e);
+                    std::process::exit(1);
+                });
+    }
+    for binding in prog.values_of("bind").into_iter().flatten() {
+        keymap.parse_binding(binding).unwrap_or_else(|e| {
+                    error!("bind: {}", e);
+                    std::process::exit(1);
+                });
+    }
+
     let mut file = File::open(bin_name)?;
     let mut binary = Vec::new();
     file.read_to_end(&mut binary)?;
 
-    let mut vm = Vm::new(binary);
-    let mut window = Window::new(
-        "rust Gameboy",
-        WIDTH * scale,
-        HEIGHT * scale,
-        WindowOptions::default(),
-    ).unwrap_or_else(|e| { panic!("{}", e); });
-    window.limit_update_rate(Some(std::time::Duration::from_micros(16600)));
-
-    while window.is_open() && !window.is_key_down(Key::Escape) {
+    let boot_rom = match prog.value_of("boot-rom") {
+        Some(path) => {
+            let mut rom = Vec::new();
+            File::open(path)?.read_to_end(&mut rom)?;
+            Some(rom)
+        },
+        None => None,
+    };
+    let renderer = prog.value_of("renderer").and_then(Renderer::from_name).unwrap();
+    let access_block = !prog.is_present("no-access-block");
+    let mut movie = match (prog.value_of("record"), prog.value_of("play")) {
+        (Some(path), _) => MoviePlayer::record(path, &binary, boot_rom.as_deref(),
+                                               prog.value_of("model")
+                                                   .and_then(Model::from_name)
+                                                   .unwrap_or_else(|| Model::detect(&binary)),
+                                               renderer, access_block),
+        (_, Some(path)) => MoviePlayer::play(path, &binary, boot_rom.as_deref()).unwrap_or_else(|e| {
+                    error!("play: {}", e);
+                    std::process::exit(1);
+                }),
+        _ => MoviePlayer::Idle,
+    };
+    // movie is played with the model and settings it is recorded
+    let model = movie.model()
+                    .or_else(|| prog.value_of("model").and_then(Model::from_name))
+                    .unwrap_or_else(|| Model::detect(&binary));
+    info!("model: {:?}", model);
+    let renderer = movie.renderer().unwrap_or(renderer);
+    let access_block = movie.access_block().unwrap_or(access_block);
 
-        // check key press
-        window.get_keys_pressed(KeyRepeat::No).map(|keys| {
-            for key in keys {
-                match key {
-                    Key::Up    => vm.cpu.bus.joypad.presskey(JoypadKey::UP),
-                    Key::Down  => vm.cpu.bus.joypad.presskey(JoypadKey::DOWN),
-                    Key::Left  => vm.cpu.bus.joypad.presskey(JoypadKey::LEFT),
-                    Key::Right => vm.cpu.bus.joypad.presskey(JoypadKey::RIGHT),
-                    Key::A     => vm.cpu.bus.joypad.presskey(JoypadKey::START),
-                    Key::S     => vm.cpu.bus.joypad.presskey(JoypadKey::SELECT),
-                    Key::Z     => vm.cpu.bus.joypad.presskey(JoypadKey::A),
-                    Key::X     => vm.cpu.bus.joypad.presskey(JoypadKey::B),
-                    _ => (),
-                }
-            }
-        });
+    let frames = prog.value_of("frames").map(|frames| frames.parse::<u64>().unwrap_or_else(|_| {
+                    error!("frames: Please select an integer as argument");
+                    std::process::exit(1);
+                }));
+    let headless = prog.is_present("headless");
+    if headless && frames.is_none() && prog.value_of("play").is_none() {
+        error!("headless: Please set frames to run or a movie to play");
+        std::process::exit(1);
+    }
 
-        // check key release
-        window.get_keys_released().map(|keys| {
-            for key in keys {
-                match key {
-                    Key::Up    => vm.cpu.bus.joypad.releasekey(JoypadKey::UP),
-                    Key::Down  => vm.cpu.bus.joypad.releasekey(JoypadKey::DOWN),
-                    Key::Left  => vm.cpu.bus.joypad.releasekey(JoypadKey::LEFT),
-                    Key::Right => vm.cpu.bus.joypad.releasekey(JoypadKey::RIGHT),
-                    Key::A     => vm.cpu.bus.joypad.releasekey(JoypadKey::START),
-                    Key::S     => vm.cpu.bus.joypad.releasekey(JoypadKey::SELECT),
-                    Key::Z     => vm.cpu.bus.joypad.releasekey(JoypadKey::A),
-                    Key::X     => vm.cpu.bus.joypad.releasekey(JoypadKey::B),
-                    _ => (),
-                }
-            }
-        });
+    let new_vm = |palette: &PaletteSet| {
+        let mut vm = Vm::new(binary.clone(), model, boot_rom.clone());
+        vm.cpu.bus.gpu.access_block = access_block;
+        vm.cpu.bus.gpu.set_renderer(renderer);
+        vm.cpu.bus.gpu.palette = palette.clone();
+        vm
+    };
+    let mut vm = new_vm(&palettes[palette_idx]);
+    if let Some(path) = prog.value_of("cdl") {
+        vm.cpu.bus.coverage = Some(Coverage::load(Path::new(path), binary.len()).unwrap_or_else(|e| {
+                    error!("cdl: {}", e);
+                    std::process::exit(1);
+                }));
+    }
 
-        if vm.run().is_err() {
-            break;
+    let mut debugger = Debugger::new(prog.is_present("debug"));
+    debugger.symbols = load_symbols(prog.value_of("sym"), bin_name).unwrap_or_else(|e| {
+                    error!("sym: {}", e);
+                    std::process::exit(1);
+                });
+    if let Some(path) = prog.value_of("trace") {
+        let range = prog.value_of("trace-range").unwrap_or("0-ffff");
+        let mut bounds = range.splitn(2, '-');
+        let start = debugger.symbols.parse_location(bounds.next().unwrap_or(""));
+        let end = bounds.next().map_or(Ok(0xffff), |end| debugger.symbols.parse_location(end));
+        let (start, end) = match (start, end) {
+            (Ok(start), Ok(end)) if start <= end => (start, end),
+            _ => {
+                error!("trace-range: Please select a range like 0150-7fff");
+                std::process::exit(1);
+            },
+        };
+        let limit = prog.value_of("trace-limit").map(|limit| limit.parse::<u64>().unwrap_or_else(|_| {
+                    error!("trace-limit: Please select an integer as argument");
+                    std::process::exit(1);
+                }));
+        debugger.trace = Some(Trace::create(Path::new(path), start, end, limit).unwrap_or_else(|e| {
+                    error!("trace: {}", e);
+                    std::process::exit(1);
+                }));
+    }
+    if let Some(path) = prog.value_of("profile") {
+        debugger.profiler = Some(Profiler::new(Path::new(path)));
+    }
+    if let Some(port) = prog.value_of("gdb") {
+        let port = port.parse::<u16>().unwrap_or_else(|_| {
+                    error!("gdb: Please select a port number as argument");
+                    std::process::exit(1);
+                });
+        debugger.gdb = Some(GdbStub::listen(port).unwrap_or_else(|e| {
+                    error!("{}", e);
+                    std::process::exit(1);
+                }));
+        debugger.pause();
+    }
+    let result = if headless {
+        run_headless(&mut vm, &mut movie, &mut debugger, frames)
+    } else {
+        let mut rewind = Rewind::new(rewind_size * 1024 * 1024);
+        let frontend = Frontend {
+            keymap: &keymap,
+            rom_path: bin_name,
+            palettes: &palettes,
+            palette_idx: palette_idx,
+            scale: scale,
+            fast_speed: fast_speed,
+            slow_speed: slow_speed,
+            new_vm: &new_vm,
+        };
+        run_window(&mut vm, &mut movie, &mut debugger, &mut rewind, frontend)
+    };
+    if let Err(e) = movie.finish() {
+        error!("record: {}", e);
+    }
+    if let Some(Err(e)) = debugger.trace.take().map(Trace::finish) {
+        error!("trace: {}", e);
+    }
+    if let (Some(coverage), Some(path)) = (&vm.cpu.bus.coverage, prog.value_of("cdl")) {
+        info!("cdl: {}", coverage.summary());
+        if let Err(e) = coverage.save(Path::new(path)) {
+            error!("cdl: {}", e);
         }
-        window.update_with_buffer(&vm.buffer, WIDTH, HEIGHT).unwrap();
+    }
+    if let Some(Err(e)) = debugger.profiler.as_ref().map(|profiler| profiler.finish(&debugger.symbols)) {
+        error!("profile: {}", e);
     }
     vm.dump();
+    if let Err(e) = result {
+        error!("{}", e);
+        std::process::exit(1);
+    }
     Ok(())
 }
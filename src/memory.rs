@@ -1,5 +1,6 @@
 use crate::bus::Device;
 use log::info;
+use crate::savestate::{Snapshot, StateWriter, StateReader};
 
 pub enum Permission {
     Normal,
@@ -31,6 +32,9 @@ impl Memory {
         }
     }
 
+    pub fn size(&self) -> usize {
+        self.memory.len()
+    }
 }
 
 impl Device for Memory {
@@ -72,3 +76,89 @@ impl Device for Memory {
         }
     }
 }
+
+/// Size of a work RAM bank
+const WRAM_BANK_SIZE: usize = 0x1000;
+/// CGB has 8 banks of work RAM
+const WRAM_BANKS: usize = 8;
+
+/// Work RAM, 0xC000-0xCFFF is always bank 0,
+/// 0xD000-0xDFFF is bank 1-7 selected by SVBK in CGB mode
+pub struct Wram {
+    base: usize,
+    memory: Vec<u8>,
+    bank: usize,
+}
+
+impl Wram {
+    pub fn new(base: usize) -> Self {
+        Self {
+            base: base,
+            memory: vec![0; WRAM_BANK_SIZE * WRAM_BANKS],
+            bank: 1,
+        }
+    }
+
+    /// SVBK, bit 0-2 select bank, bank 0 selects bank 1
+    pub fn load_bank(&self) -> u8 {
+        0xf8 | self.bank as u8
+    }
+
+    pub fn store_bank(&mut self, value: u8) {
+        self.bank = match (value & 0x7) as usize {
+            0 => 1,
+            bank => bank,
+        };
+    }
+
+    fn offset(&self, addr: u16) -> usize {
+        let addr = (addr as usize) - self.base;
+        if addr < WRAM_BANK_SIZE {
+            addr
+        } else {
+            self.bank * WRAM_BANK_SIZE + (addr - WRAM_BANK_SIZE)
+        }
+    }
+}
+
+impl Device for Wram {
+    fn load(&self, addr: u16) -> Result<u8, ()> {
+        match self.memory.get(self.offset(addr)) {
+            Some(elem) => Ok(*elem),
+            None => Err(()),
+        }
+    }
+
+    fn store(&mut self, addr: u16, value: u8) -> Result<(), ()> {
+        let offset = self.offset(addr);
+        match self.memory.get_mut(offset) {
+            Some(elem) => {
+                *elem = value;
+                Ok(())
+            },
+            None => Err(()),
+        }
+    }
+}
+
+impl Snapshot for Memory {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.memory);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        r.bytes_into(&mut self.memory);
+    }
+}
+
+impl Snapshot for Wram {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.bank as u8);
+        w.bytes(&self.memory);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.store_bank(r.u8());
+        r.bytes_into(&mut self.memory);
+    }
+}
@@ -0,0 +1,367 @@
+use minifb::{Key, KeyRepeat, Window, WindowOptions};
+
+use crate::bus::Bus;
+use crate::watch;
+
+/// Rows of 16 bytes shown
+const ROWS: usize = 32;
+const ROW_LEN: u16 = 16;
+const PAGE_LEN: u16 = ROWS as u16 * ROW_LEN;
+/// Address of the last page
+const LAST_PAGE: u16 = 0u16.wrapping_sub(PAGE_LEN);
+/// Text is drawn in cells of 4x7 pixels, glyphs are 3x5
+const CHAR_WIDTH: usize = 4;
+const CHAR_HEIGHT: usize = 7;
+const SCALE: usize = 2;
+/// "C000  00 01 .. 0F", a header line, rows, an empty line and status line
+const COLUMNS: usize = 6 + 16 * 3;
+const LINES: usize = ROWS + 3;
+const WIDTH: usize = COLUMNS * CHAR_WIDTH * SCALE;
+const HEIGHT: usize = LINES * CHAR_HEIGHT * SCALE;
+/// Updates a changed byte is highlighted, fading out
+const HIGHLIGHT_LEN: u8 = 30;
+
+const BACKGROUND: u32 = 0x101010;
+const TEXT: u32 = 0xc0c0c0;
+const DIM: u32 = 0x707070;
+const CHANGED: u32 = 0xff4040;
+const CURSOR: u32 = 0x3050a0;
+
+/// Glyph of 5 rows of 3 pixels, bit 2 is the left pixel,
+/// unknown characters are blank
+fn glyph(c: char) -> [u8; 5] {
+    match c.to_ascii_uppercase() {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b001, 0b001, 0b001],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'G' => [0b011, 0b100, 0b101, 0b101, 0b011],
+        'H' => [0b101, 0b101, 0b111, 0b101, 0b101],
+        'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'J' => [0b001, 0b001, 0b001, 0b101, 0b010],
+        'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'Q' => [0b010, 0b101, 0b101, 0b110, 0b011],
+        'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'V' => [0b101, 0b101, 0b101, 0b101, 0b010],
+        'W' => [0b101, 0b101, 0b111, 0b111, 0b101],
+        'X' => [0b101, 0b101, 0b010, 0b101, 0b101],
+        'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        'Z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        '/' => [0b001, 0b001, 0b010, 0b100, 0b100],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '=' => [0b000, 0b111, 0b000, 0b111, 0b000],
+        '?' => [0b111, 0b001, 0b010, 0b000, 0b010],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        '_' => [0b000, 0b000, 0b000, 0b000, 0b111],
+        '(' => [0b001, 0b010, 0b010, 0b010, 0b001],
+        ')' => [0b100, 0b010, 0b010, 0b010, 0b100],
+        _ => [0; 5],
+    }
+}
+
+/// value of a hex digit key
+fn hex_digit(key: Key) -> Option<u8> {
+    const DIGITS: [Key; 16] = [
+        Key::Key0, Key::Key1, Key::Key2, Key::Key3, Key::Key4, Key::Key5, Key::Key6, Key::Key7,
+        Key::Key8, Key::Key9, Key::A, Key::B, Key::C, Key::D, Key::E, Key::F,
+    ];
+    const NUMPAD: [Key; 10] = [
+        Key::NumPad0, Key::NumPad1, Key::NumPad2, Key::NumPad3, Key::NumPad4,
+        Key::NumPad5, Key::NumPad6, Key::NumPad7, Key::NumPad8, Key::NumPad9,
+    ];
+    DIGITS.iter().chain(NUMPAD.iter())
+        .position(|k| *k == key)
+        .map(|idx| (idx % 16) as u8)
+}
+
+/// Text typed in status line
+enum Input {
+    None,
+    /// address to go to
+    Goto(String),
+    /// bytes to search for, in hex
+    Find(String),
+}
+
+/// Window showing a live hex dump of the address space. Arrows and page
+/// keys move the cursor, hex digits write the byte at cursor, G goes to
+/// an address, / searches for bytes and N finds the next match.
+pub struct MemoryViewer {
+    window: Window,
+    buffer: Vec<u32>,
+    /// first address shown, at start of a row
+    top: u16,
+    cursor: u16,
+    /// bytes shown at last update and the top they were read from,
+    /// None if not readable
+    shown: Vec<Option<u8>>,
+    shown_top: Option<u16>,
+    /// updates left to highlight each byte shown
+    changed: Vec<u8>,
+    input: Input,
+    /// high nibble typed at cursor, written with the low nibble
+    nibble: Option<u8>,
+    /// last bytes searched for
+    pattern: Vec<u8>,
+    status: String,
+}
+
+impl MemoryViewer {
+    pub fn new() -> Result<Self, String> {
+        let window = Window::new("rust Gameboy - memory", WIDTH, HEIGHT, WindowOptions::default())
+            .map_err(|e| format!("memory viewer: {}", e))?;
+        Ok(Self {
+            window: window,
+            buffer: vec![BACKGROUND; WIDTH * HEIGHT],
+            top: 0xc000,
+            cursor: 0xc000,
+            shown: vec![None; PAGE_LEN as usize],
+            shown_top: None,
+            changed: vec![0; PAGE_LEN as usize],
+            input: Input::None,
+            nibble: None,
+            pattern: Vec::new(),
+            status: String::new(),
+        })
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.window.is_open()
+    }
+
+    /// handle keys, edit memory and redraw, called once a window refresh
+    pub fn update(&mut self, bus: &mut Bus) {
+        for key in self.window.get_keys_pressed(KeyRepeat::Yes).unwrap_or_default() {
+            self.key_pressed(key, bus);
+        }
+        self.scroll();
+        self.read(bus);
+        self.draw();
+        let _ = self.window.update_with_buffer(&self.buffer, WIDTH, HEIGHT);
+    }
+
+    fn key_pressed(&mut self, key: Key, bus: &mut Bus) {
+        if let Input::Goto(text) | Input::Find(text) = &mut self.input {
+            match key {
+                Key::Escape => self.input = Input::None,
+                Key::Backspace => { text.pop(); },
+                Key::Enter | Key::NumPadEnter => {
+                    let input = std::mem::replace(&mut self.input, Input::None);
+                    self.submit(input, bus);
+                },
+                _ => if let Some(digit) = hex_digit(key) {
+                    text.push(std::char::from_digit(digit as u32, 16).unwrap_or('0'));
+                },
+            }
+            return;
+        }
+        let cursor = self.cursor;
+        self.cursor = match key {
+            Key::Up => cursor.wrapping_sub(ROW_LEN),
+            Key::Down => cursor.wrapping_add(ROW_LEN),
+            Key::Left => cursor.wrapping_sub(1),
+            Key::Right => cursor.wrapping_add(1),
+            Key::PageUp => cursor.wrapping_sub(PAGE_LEN),
+            Key::PageDown => cursor.wrapping_add(PAGE_LEN),
+            Key::Home => 0x0000,
+            Key::End => 0xffff,
+            _ => cursor,
+        };
+        if self.cursor != cursor {
+            self.nibble = None;
+            return;
+        }
+        match key {
+            Key::G => self.input = Input::Goto(String::new()),
+            Key::Slash => self.input = Input::Find(String::new()),
+            Key::N => self.find(bus),
+            Key::Escape => self.nibble = None,
+            _ => if let Some(digit) = hex_digit(key) {
+                self.edit(digit, bus);
+            },
+        }
+    }
+
+    fn submit(&mut self, input: Input, bus: &Bus) {
+        match input {
+            Input::Goto(text) => match u16::from_str_radix(&text, 16) {
+                Ok(addr) => {
+                    self.cursor = addr;
+                    self.nibble = None;
+                },
+                Err(_) => self.status = format!("INVALID ADDRESS {}", text),
+            },
+            Input::Find(text) => {
+                let bytes: Option<Vec<u8>> = (0..text.len()).step_by(2)
+                    .map(|i| text.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+                    .collect();
+                match bytes {
+                    Some(bytes) if !bytes.is_empty() => {
+                        self.pattern = bytes;
+                        self.find(bus);
+                    },
+                    _ => self.status = "EXPECT BYTES LIKE 3E01".to_string(),
+                }
+            },
+            Input::None => {},
+        }
+    }
+
+    /// search for pattern after cursor, wrapping around the address space
+    fn find(&mut self, bus: &Bus) {
+        if self.pattern.is_empty() {
+            self.status = "/ TO FIND BYTES".to_string();
+            return;
+        }
+        self.nibble = None;
+        let pattern = &self.pattern;
+        let found = (1..=0x10000u32)
+            .map(|offset| self.cursor.wrapping_add(offset as u16))
+            .find(|addr| pattern.iter().enumerate().all(|(i, byte)| {
+                bus.peek8(addr.wrapping_add(i as u16)).ok() == Some(*byte)
+            }));
+        self.status = match found {
+            Some(addr) => {
+                self.cursor = addr;
+                format!("FOUND AT {:04X}", addr)
+            },
+            None => "NOT FOUND".to_string(),
+        };
+    }
+
+    /// type a hex digit at cursor, the byte is written when both are typed
+    fn edit(&mut self, digit: u8, bus: &mut Bus) {
+        let high = match self.nibble.take() {
+            Some(high) => high,
+            None => {
+                self.nibble = Some(digit);
+                return;
+            },
+        };
+        let value = high << 4 | digit;
+        // written by user, not by the program, so neither blocked nor watched.
+        // ROM and some registers keep their value, which is read back
+        self.status = match bus.poke8(self.cursor, value).and_then(|_| bus.peek8(self.cursor)) {
+            Ok(read) if read == value => format!("WROTE {:02X} TO {:04X}", value, self.cursor),
+            Ok(read) => format!("WROTE {:02X} READS {:02X}", value, read),
+            Err(_) => format!("CANNOT WRITE {:04X}", self.cursor),
+        };
+        self.cursor = self.cursor.wrapping_add(1);
+    }
+
+    /// move page to show cursor
+    fn scroll(&mut self) {
+        let row = self.cursor & !(ROW_LEN - 1);
+        if row < self.top {
+            self.top = row;
+        } else if row - self.top >= PAGE_LEN {
+            self.top = row - (PAGE_LEN - ROW_LEN);
+        }
+        self.top = self.top.min(LAST_PAGE);
+    }
+
+    /// read bytes shown, those changed since last update are highlighted
+    fn read(&mut self, bus: &Bus) {
+        let scrolled = self.shown_top != Some(self.top);
+        for i in 0..PAGE_LEN {
+            let value = bus.peek8(self.top.wrapping_add(i)).ok();
+            let idx = i as usize;
+            self.changed[idx] = match self.changed[idx] {
+                _ if scrolled => 0,
+                _ if value != self.shown[idx] => HIGHLIGHT_LEN,
+                n => n.saturating_sub(1),
+            };
+            self.shown[idx] = value;
+        }
+        self.shown_top = Some(self.top);
+    }
+
+    fn draw(&mut self) {
+        for pixel in self.buffer.iter_mut() {
+            *pixel = BACKGROUND;
+        }
+        let header: String = (0..ROW_LEN).map(|i| format!(" {:02X}", i)).collect();
+        self.text(5, 0, &header, DIM, None);
+        for row in 0..ROWS {
+            let addr = self.top.wrapping_add(row as u16 * ROW_LEN);
+            self.text(0, row + 1, &format!("{:04X}", addr), DIM, None);
+            for i in 0..ROW_LEN {
+                let idx = row * ROW_LEN as usize + i as usize;
+                let addr = addr.wrapping_add(i);
+                let text = match (self.shown[idx], self.nibble) {
+                    (_, Some(high)) if addr == self.cursor => format!("{:X}_", high),
+                    (Some(value), _) => format!("{:02X}", value),
+                    (None, _) => "??".to_string(),
+                };
+                let color = match self.shown[idx] {
+                    None => DIM,
+                    Some(_) => fade(TEXT, CHANGED, self.changed[idx] as u32, HIGHLIGHT_LEN as u32),
+                };
+                let background = if addr == self.cursor { Some(CURSOR) } else { None };
+                self.text(6 + i as usize * 3, row + 1, &text, color, background);
+            }
+        }
+
+        let status = match &self.input {
+            Input::Goto(text) => format!("GOTO: {}_", text),
+            Input::Find(text) => format!("FIND: {}_", text),
+            Input::None => {
+                let name = watch::io_name(self.cursor).map_or(String::new(), |name| format!(" ({})", name));
+                format!("{:04X}{}  G:GOTO /:FIND N:NEXT  {}", self.cursor, name, self.status)
+            },
+        };
+        self.text(0, LINES - 1, &status, TEXT, None);
+    }
+
+    /// draw text from column and line of character cells
+    fn text(&mut self, column: usize, line: usize, text: &str, color: u32, background: Option<u32>) {
+        for (i, c) in text.chars().enumerate().take(COLUMNS.saturating_sub(column)) {
+            let left = (column + i) * CHAR_WIDTH * SCALE;
+            let top = line * CHAR_HEIGHT * SCALE;
+            let rows = glyph(c);
+            for y in 0..CHAR_HEIGHT * SCALE {
+                for x in 0..CHAR_WIDTH * SCALE {
+                    let (gx, gy) = (x / SCALE, y / SCALE);
+                    // glyph is drawn from the second row, other pixels are spacing
+                    let lit = gx < 3 && (1..=5).contains(&gy) && rows[gy - 1] & (0b100 >> gx) != 0;
+                    let pixel = &mut self.buffer[(top + y) * WIDTH + left + x];
+                    if lit {
+                        *pixel = color;
+                    } else if let Some(background) = background {
+                        *pixel = background;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// color from `from` to `to` by n of len steps, per channel
+fn fade(from: u32, to: u32, n: u32, len: u32) -> u32 {
+    (0..3).map(|i| {
+        let shift = i * 8;
+        let (a, b) = ((from >> shift) & 0xff, (to >> shift) & 0xff);
+        ((a * (len - n) + b * n) / len) << shift
+    }).sum()
+}
@@ -0,0 +1,270 @@
+use std::fs;
+use log::info;
+
+use crate::boot::Model;
+use crate::checksum::crc32;
+use crate::gpu::Renderer;
+use crate::joypad::JoypadKey;
+
+const MOVIE_MAGIC: &str = "ruGameboy movie";
+const MOVIE_VERSION: u32 = 1;
+/// Frames between two sync points
+const SYNC_INTERVAL: u64 = 60;
+
+/// Inputs of a run from power on, with the start condition to replay it.
+/// Saved as text, one record a line, event lines start with frame number
+/// and inputs are applied before the frame runs:
+///
+///     ruGameboy movie 1
+///     rom 3c5a1e2f
+///     boot none
+///     model dmg
+///     renderer scanline
+///     access-block on
+///     120 press start
+///     125 release start
+///     180 sync 9e0d4c21
+#[derive(Default)]
+pub struct Movie {
+    /// CRC-32 of catridge
+    rom: u32,
+    /// CRC-32 of boot ROM if used
+    boot: Option<u32>,
+    model: Option<Model>,
+    /// settings changing emulation the program can see
+    renderer: Option<Renderer>,
+    access_block: Option<bool>,
+    events: Vec<(u64, Event)>,
+}
+
+#[derive(Clone,Copy,PartialEq)]
+enum Event {
+    Press(JoypadKey),
+    Release(JoypadKey),
+    /// checksum of machine state after the frame
+    Sync(u32),
+}
+
+impl Movie {
+    fn new(rom: &[u8], boot_rom: Option<&[u8]>, model: Model, renderer: Renderer, access_block: bool) -> Self {
+        Self {
+            rom: crc32(rom),
+            boot: boot_rom.map(crc32),
+            model: Some(model),
+            renderer: Some(renderer),
+            access_block: Some(access_block),
+            events: Vec::new(),
+        }
+    }
+
+    fn load_file(path: &str) -> Result<Self, String> {
+        let content = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        Self::parse(&content).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    fn parse(content: &str) -> Result<Self, String> {
+        let mut lines = content.lines().enumerate();
+        match lines.next() {
+            Some((_, line)) if line == format!("{} {}", MOVIE_MAGIC, MOVIE_VERSION) => {},
+            _ => return Err(format!("not a movie of version {}", MOVIE_VERSION)),
+        }
+
+        let mut movie = Movie::default();
+        for (num, line) in lines {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let err = || format!("line {}: invalid record {}", num + 1, line);
+            let hex = |s: &str| u32::from_str_radix(s, 16).map_err(|_| err());
+            match fields.as_slice() {
+                [] => {},
+                ["rom", crc] => movie.rom = hex(crc)?,
+                ["boot", "none"] => movie.boot = None,
+                ["boot", crc] => movie.boot = Some(hex(crc)?),
+                ["model", name] => movie.model = Some(Model::from_name(name).ok_or_else(err)?),
+                ["renderer", name] => movie.renderer = Some(Renderer::from_name(name).ok_or_else(err)?),
+                ["access-block", "on"] => movie.access_block = Some(true),
+                ["access-block", "off"] => movie.access_block = Some(false),
+                [frame, kind, value] => {
+                    let frame = frame.parse::<u64>().map_err(|_| err())?;
+                    let event = match *kind {
+                        "press" => Event::Press(JoypadKey::from_name(value).ok_or_else(err)?),
+                        "release" => Event::Release(JoypadKey::from_name(value).ok_or_else(err)?),
+                        "sync" => Event::Sync(hex(value)?),
+                        _ => return Err(err()),
+                    };
+                    movie.events.push((frame, event));
+                },
+                _ => return Err(err()),
+            }
+        }
+        // allow events edited by hand out of order, events of a frame keep their order
+        movie.events.sort_by_key(|(frame, _)| *frame);
+        Ok(movie)
+    }
+
+    fn save_file(&self, path: &str) -> Result<(), String> {
+        let mut content = format!("{} {}\n", MOVIE_MAGIC, MOVIE_VERSION);
+        content += &format!("rom {:08x}\n", self.rom);
+        match self.boot {
+            Some(crc) => content += &format!("boot {:08x}\n", crc),
+            None => content += "boot none\n",
+        }
+        if let Some(model) = self.model {
+            content += &format!("model {}\n", model.name());
+        }
+        if let Some(renderer) = self.renderer {
+            content += &format!("renderer {}\n", renderer.name());
+        }
+        if let Some(access_block) = self.access_block {
+            content += &format!("access-block {}\n", if access_block { "on" } else { "off" });
+        }
+        for (frame, event) in self.events.iter() {
+            content += &match event {
+                Event::Press(key) => format!("{} press {}\n", frame, key.name()),
+                Event::Release(key) => format!("{} release {}\n", frame, key.name()),
+                Event::Sync(crc) => format!("{} sync {:08x}\n", frame, crc),
+            };
+        }
+        fs::write(path, content).map_err(|e| format!("{}: {}", path, e))
+    }
+}
+
+/// Movie attached to emulation
+pub enum MoviePlayer {
+    Idle,
+    Recording {
+        path: String,
+        movie: Movie,
+    },
+    Playing {
+        movie: Movie,
+        /// index of next event
+        next: usize,
+    },
+}
+
+impl MoviePlayer {
+    pub fn record(path: &str, rom: &[u8], boot_rom: Option<&[u8]>, model: Model,
+                  renderer: Renderer, access_block: bool) -> Self {
+        MoviePlayer::Recording {
+            path: path.to_string(),
+            movie: Movie::new(rom, boot_rom, model, renderer, access_block),
+        }
+    }
+
+    /// Load movie to play, catridge and boot ROM must be the same as recorded
+    pub fn play(path: &str, rom: &[u8], boot_rom: Option<&[u8]>) -> Result<Self, String> {
+        let movie = Movie::load_file(path)?;
+        if movie.rom != crc32(rom) {
+            return Err(format!("{}: recorded with another catridge", path));
+        }
+        if movie.boot != boot_rom.map(crc32) {
+            return Err(format!("{}: recorded with different boot ROM", path));
+        }
+        Ok(MoviePlayer::Playing { movie: movie, next: 0 })
+    }
+
+    /// model the movie is recorded with
+    pub fn model(&self) -> Option<Model> {
+        match self {
+            MoviePlayer::Playing { movie, .. } => movie.model,
+            _ => None,
+        }
+    }
+
+    /// renderer the movie is recorded with
+    pub fn renderer(&self) -> Option<Renderer> {
+        match self {
+            MoviePlayer::Playing { movie, .. } => movie.renderer,
+            _ => None,
+        }
+    }
+
+    /// whether VRAM and OAM access is blocked in the recording
+    pub fn access_block(&self) -> Option<bool> {
+        match self {
+            MoviePlayer::Playing { movie, .. } => movie.access_block,
+            _ => None,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        !matches!(self, MoviePlayer::Idle)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        match self {
+            MoviePlayer::Playing { movie, next } => *next >= movie.events.len(),
+            _ => false,
+        }
+    }
+
+    /// key changed by user before frame runs,
+    /// return false if the input should be ignored since a movie is playing
+    pub fn input(&mut self, frame: u64, key: JoypadKey, pressed: bool) -> bool {
+        match self {
+            MoviePlayer::Idle => true,
+            MoviePlayer::Recording { movie, .. } => {
+                let event = if pressed { Event::Press(key) } else { Event::Release(key) };
+                movie.events.push((frame, event));
+                true
+            },
+            MoviePlayer::Playing { .. } => false,
+        }
+    }
+
+    /// recorded inputs should be applied before frame runs, true if pressed
+    pub fn inputs(&mut self, frame: u64) -> Vec<(JoypadKey, bool)> {
+        let mut inputs = Vec::new();
+        if let MoviePlayer::Playing { movie, next } = self {
+            while let Some((f, event)) = movie.events.get(*next) {
+                match event {
+                    Event::Press(key) if *f == frame => inputs.push((*key, true)),
+                    Event::Release(key) if *f == frame => inputs.push((*key, false)),
+                    _ => break,
+                }
+                *next += 1;
+            }
+        }
+        inputs
+    }
+
+    /// called after frame runs with checksum of machine state,
+    /// playback fails if it differs from the recording
+    pub fn end_frame(&mut self, frame: u64, checksum: u32) -> Result<(), String> {
+        match self {
+            MoviePlayer::Recording { movie, .. } if (frame + 1).is_multiple_of(SYNC_INTERVAL) => {
+                movie.events.push((frame, Event::Sync(checksum)));
+            },
+            MoviePlayer::Playing { movie, next } => {
+                if let Some((f, Event::Sync(expect))) = movie.events.get(*next) {
+                    if *f == frame {
+                        *next += 1;
+                        if *expect != checksum {
+                            return Err(format!("movie desync at frame {}: expect state {:08x}, found {:08x}",
+                                               frame, expect, checksum));
+                        }
+                    }
+                }
+                if let Some((f, _)) = movie.events.get(*next) {
+                    if *f <= frame {
+                        return Err(format!("movie desync at frame {}: event of frame {} is missed", frame, f));
+                    }
+                }
+            },
+            _ => {},
+        }
+        Ok(())
+    }
+
+    /// save recording movie
+    pub fn finish(&self) -> Result<(), String> {
+        match self {
+            MoviePlayer::Recording { path, movie } => {
+                movie.save_file(path)?;
+                info!("Movie saved to {}", path);
+                Ok(())
+            },
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Frame rate of the LCD, 4194304 Hz / 70224 clocks a frame
+pub const FRAME_RATE: f64 = 4194304.0 / 70224.0;
+/// Window is updated at least this often, so input is polled when
+/// frames are slow or uncapped
+const REFRESH_TIME: Duration = Duration::from_micros(16600);
+/// Frames are dropped instead of caught up if emulation lags behind this much
+const MAX_LAG: Duration = Duration::from_millis(100);
+/// Speed shown in title is measured over this period
+const REPORT_TIME: Duration = Duration::from_secs(1);
+
+/// Emulation speed relative to real hardware
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Speed {
+    Paused,
+    Normal,
+    /// N times faster
+    Fast(u32),
+    /// as fast as possible
+    Uncapped,
+    /// N times slower
+    Slow(u32),
+}
+
+impl Speed {
+    /// wall time of a frame, None if paused or uncapped
+    fn frame_time(&self) -> Option<Duration> {
+        let normal = Duration::from_secs_f64(1.0 / FRAME_RATE);
+        match self {
+            Speed::Paused | Speed::Uncapped => None,
+            Speed::Normal => Some(normal),
+            Speed::Fast(n) => Some(normal / (*n).max(1)),
+            Speed::Slow(n) => Some(normal * (*n).max(1)),
+        }
+    }
+}
+
+/// Decide how many frames to run for each window update, to run at the
+/// selected speed independent of the window refresh rate
+pub struct FramePacer {
+    /// time the next frame is due
+    next: Instant,
+    /// start of the current report period
+    since: Instant,
+    /// frames run in the current report period
+    frames: u32,
+}
+
+impl FramePacer {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            next: now,
+            since: now,
+            frames: 0,
+        }
+    }
+
+    /// Run frames due at speed by calling run_frame, which returns
+    /// false if no frame could be run. Uncapped speed runs frames
+    /// until the window should be refreshed.
+    pub fn run<F>(&mut self, speed: Speed, mut run_frame: F) -> Result<(), String>
+        where F: FnMut() -> Result<bool, String>
+    {
+        let now = Instant::now();
+        let frame_time = match speed.frame_time() {
+            Some(frame_time) => frame_time,
+            None => {
+                if speed == Speed::Uncapped {
+                    while now.elapsed() < REFRESH_TIME && run_frame()? {
+                        self.frames += 1;
+                    }
+                }
+                self.next = Instant::now();
+                return Ok(());
+            },
+        };
+        if now.saturating_duration_since(self.next) > MAX_LAG {
+            self.next = now;
+        }
+        while self.next <= now {
+            self.next += frame_time;
+            if !run_frame()? {
+                break;
+            }
+            self.frames += 1;
+        }
+        Ok(())
+    }
+
+    /// sleep until the next frame is due or the window should be refreshed
+    pub fn wait(&self, refreshed: Instant) {
+        let until = self.next.min(refreshed + REFRESH_TIME);
+        let now = Instant::now();
+        if until > now {
+            thread::sleep(until - now);
+        }
+    }
+
+    /// frames run in a second and speed relative to real hardware,
+    /// measured once a report period
+    pub fn report(&mut self) -> Option<(f64, f64)> {
+        let elapsed = self.since.elapsed();
+        if elapsed < REPORT_TIME {
+            return None;
+        }
+        let fps = self.frames as f64 / elapsed.as_secs_f64();
+        self.since = Instant::now();
+        self.frames = 0;
+        Some((fps, fps / FRAME_RATE))
+    }
+}
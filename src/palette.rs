@@ -0,0 +1,205 @@
+use std::fs;
+use crate::savestate::{Snapshot, StateWriter, StateReader};
+
+/// Colors of shade 0 (lightest) to 3 (darkest)
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct Palette {
+    pub colors: [u32; 4],
+}
+
+impl Palette {
+    pub const fn new(colors: [u32; 4]) -> Self {
+        Self { colors }
+    }
+
+    pub fn color(&self, shade: u8) -> u32 {
+        self.colors[(shade & 0x3) as usize]
+    }
+
+    /// parse 4 colors in hex RRGGBB, optionally prefixed by '#' or "0x"
+    fn parse(value: &str) -> Result<Self, String> {
+        let colors = value.split_whitespace()
+            .map(|color| {
+                let hex = color.trim_start_matches('#').trim_start_matches("0x");
+                u32::from_str_radix(hex, 16)
+                    .ok()
+                    .filter(|_| hex.len() == 6)
+                    .ok_or(format!("Invalid color {}", color))
+            })
+            .collect::<Result<Vec<u32>, String>>()?;
+        if colors.len() != 4 {
+            return Err(format!("Expect 4 colors, found {}", colors.len()));
+        }
+        Ok(Self::new([colors[0], colors[1], colors[2], colors[3]]))
+    }
+}
+
+const GRAY: Palette = Palette::new([0x00FFFFFF, 0x00AAAAAA, 0x00555555, 0x00000000]);
+const GREEN: Palette = Palette::new([0x009BBC0F, 0x008BAC0F, 0x00306230, 0x000F380F]);
+const POCKET: Palette = Palette::new([0x00C4CFA1, 0x008B956D, 0x004D533C, 0x001F1F1F]);
+const LIGHT: Palette = Palette::new([0x0000B581, 0x00009A71, 0x0000694A, 0x00004F3B]);
+
+/// Palettes for background, OBJ0 and OBJ1
+#[derive(Debug,Clone,PartialEq)]
+pub struct PaletteSet {
+    pub name: String,
+    pub bg: Palette,
+    pub obp0: Palette,
+    pub obp1: Palette,
+}
+
+impl PaletteSet {
+    fn single(name: &str, palette: Palette) -> Self {
+        Self {
+            name: name.to_string(),
+            bg: palette,
+            obp0: palette,
+            obp1: palette,
+        }
+    }
+
+    /// builtin palettes, the first one is default
+    pub fn presets() -> Vec<Self> {
+        vec![
+            PaletteSet::single("gray", GRAY),
+            PaletteSet::single("green", GREEN),
+            PaletteSet::single("pocket", POCKET),
+            PaletteSet::single("light", LIGHT),
+        ]
+    }
+
+    /// Load palettes from file, each palette starts with its name in bracket
+    /// and palettes for bg, obp0 and obp1. obp0 and obp1 are the same
+    /// as bg if not specified.
+    ///
+    ///     # comment
+    ///     [autumn]
+    ///     bg   = #FFEFCE #DE944A #AD2921 #311852
+    ///     obp0 = #FFFFFF #FF8484 #943A3A #000000
+    pub fn load_file(path: &str) -> Result<Vec<Self>, String> {
+        let content = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        Self::parse(&content).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    fn parse(content: &str) -> Result<Vec<Self>, String> {
+        // palettes and whether obp0, obp1 are set
+        let mut sets: Vec<(Self, bool, bool)> = Vec::new();
+        for (num, line) in content.lines().enumerate() {
+            let line = line.trim();
+            // colors may start with '#', so only whole line comment is allowed
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if line.starts_with('[') && line.ends_with(']') {
+                let name = line[1..line.len()-1].trim();
+                sets.push((PaletteSet::single(name, GRAY), false, false));
+                continue;
+            }
+
+            let mut kv = line.splitn(2, '=');
+            let key = kv.next().unwrap_or("").trim();
+            let value = kv.next().ok_or(format!("line {}: expect key = colors", num + 1))?;
+            let palette = Palette::parse(value).map_err(|e| format!("line {}: {}", num + 1, e))?;
+            let (set, has_obp0, has_obp1) = sets.last_mut()
+                .ok_or(format!("line {}: palette name is not given", num + 1))?;
+            match key {
+                "bg" => {
+                    set.bg = palette;
+                    if !*has_obp0 { set.obp0 = palette; }
+                    if !*has_obp1 { set.obp1 = palette; }
+                },
+                "obp0" => {
+                    set.obp0 = palette;
+                    *has_obp0 = true;
+                },
+                "obp1" => {
+                    set.obp1 = palette;
+                    *has_obp1 = true;
+                },
+                _ => return Err(format!("line {}: unknown key {}", num + 1, key)),
+            }
+        }
+        Ok(sets.into_iter().map(|(set, _, _)| set).collect())
+    }
+}
+
+impl Default for PaletteSet {
+    fn default() -> Self {
+        PaletteSet::single("gray", GRAY)
+    }
+}
+
+/// Size of CGB palette RAM, 8 palettes * 4 colors * 2 bytes
+const COLOR_PALETTE_SIZE: usize = 64;
+
+/// CGB palette RAM, accessed through BCPS/BCPD or OCPS/OCPD,
+/// each color is 15 bits little endian: 0bbbbbgg gggrrrrr
+pub struct ColorPalette {
+    data: [u8; COLOR_PALETTE_SIZE],
+    /// BCPS/OCPS bit 0-5, byte index of palette RAM
+    index: u8,
+    /// BCPS/OCPS bit 7, increase index after writing data
+    auto_increment: bool,
+}
+
+impl ColorPalette {
+    pub fn new() -> Self {
+        Self {
+            // all white
+            data: [0xff; COLOR_PALETTE_SIZE],
+            index: 0,
+            auto_increment: false,
+        }
+    }
+
+    pub fn load_spec(&self) -> u8 {
+        0x40 | (self.auto_increment as u8) << 7 | self.index
+    }
+
+    pub fn store_spec(&mut self, value: u8) {
+        self.index = value & 0x3f;
+        self.auto_increment = value & 0x80 != 0;
+    }
+
+    pub fn load_data(&self) -> u8 {
+        self.data[self.index as usize]
+    }
+
+    pub fn store_data(&mut self, value: u8) {
+        self.data[self.index as usize] = value;
+        if self.auto_increment {
+            self.index = (self.index + 1) & 0x3f;
+        }
+    }
+
+    /// 24 bits color of color number in palette
+    pub fn color(&self, palette: u8, color: u8) -> u32 {
+        let idx = ((palette & 0x7) as usize * 4 + (color & 0x3) as usize) * 2;
+        let value = self.data[idx] as u16 | (self.data[idx + 1] as u16) << 8;
+        rgb555_to_rgb888(value)
+    }
+}
+
+/// expand 5 bits of each channel to 8 bits
+fn rgb555_to_rgb888(value: u16) -> u32 {
+    let expand = |c: u16| -> u32 {
+        let c = (c & 0x1f) as u32;
+        (c << 3) | (c >> 2)
+    };
+    let r = expand(value);
+    let g = expand(value >> 5);
+    let b = expand(value >> 10);
+    r << 16 | g << 8 | b
+}
+
+impl Snapshot for ColorPalette {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.data);
+        w.u8(self.load_spec());
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        r.bytes_into(&mut self.data);
+        self.store_spec(r.u8());
+    }
+}
@@ -0,0 +1,214 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cpu::Cpu;
+use crate::symbols::Symbols;
+
+/// Entries listed in each section of report
+const REPORT_LEN: usize = 20;
+
+/// Bank and address, as in symbol files
+type Location = (u16, u16);
+
+#[derive(Default,Clone,Copy)]
+struct Counter {
+    count: u64,
+    cycles: u64,
+}
+
+/// Count executed instructions and cycles by PC, by loop and by call
+/// stack. Cycles of an instruction are counted when the next one starts,
+/// including those of an interrupt dispatched after it.
+pub struct Profiler {
+    path: PathBuf,
+    instructions: HashMap<Location, Counter>,
+    /// backward jumps as (target, source), counted once an iteration
+    loops: HashMap<(Location, Location), u64>,
+    /// call stacks seen, as call targets from the outermost, and their cycles
+    stacks: Vec<(Vec<Location>, u64)>,
+    /// index in stacks of each call stack
+    stack_ids: HashMap<Vec<Location>, usize>,
+    /// instruction running and index of its call stack
+    last: Option<Location>,
+    last_stack: usize,
+    last_cycles: u64,
+    total: u64,
+}
+
+impl Profiler {
+    /// report is written to path, and folded stacks to path with .folded appended
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            instructions: HashMap::new(),
+            loops: HashMap::new(),
+            stacks: vec![(Vec::new(), 0)],
+            stack_ids: HashMap::from([(Vec::new(), 0)]),
+            last: None,
+            last_stack: 0,
+            last_cycles: 0,
+            total: 0,
+        }
+    }
+
+    /// called before each executed instruction
+    pub fn exec(&mut self, cpu: &Cpu) {
+        let cycles = cpu.cycles - self.last_cycles;
+        self.last_cycles = cpu.cycles;
+        if let Some(last) = self.last {
+            self.instructions.entry(last).or_default().cycles += cycles;
+            self.stacks[self.last_stack].1 += cycles;
+            self.total += cycles;
+        }
+        if cpu.is_stopped() {
+            self.last = None;
+            return;
+        }
+
+        let bus = &cpu.bus;
+        let current = (bus.bank(cpu.pc), cpu.pc);
+        self.instructions.entry(current).or_default().count += 1;
+        let depth = cpu.calls.frames.len();
+        // jumped back at the same depth of calls
+        if let Some(last) = self.last {
+            if last.0 == current.0 && current.1 <= last.1 && depth == self.stacks[self.last_stack].0.len() {
+                *self.loops.entry((current, last)).or_default() += 1;
+            }
+        }
+        self.last = Some(current);
+        // a stack is only looked up when it changes
+        let stack = cpu.calls.frames.iter().map(|frame| (bus.bank(frame.to), frame.to));
+        if !self.stacks[self.last_stack].0.iter().copied().eq(stack.clone()) {
+            let stack: Vec<Location> = stack.collect();
+            self.last_stack = match self.stack_ids.get(&stack) {
+                Some(id) => *id,
+                None => {
+                    self.stack_ids.insert(stack.clone(), self.stacks.len());
+                    self.stacks.push((stack, 0));
+                    self.stacks.len() - 1
+                },
+            };
+        }
+    }
+
+    fn name(symbols: &Symbols, (bank, addr): Location) -> String {
+        symbols.describe(bank, addr).unwrap_or_else(|| format!("{:02x}:{:04x}", bank, addr))
+    }
+
+    /// label shown after location, empty without symbols
+    fn label(symbols: &Symbols, (bank, addr): Location) -> String {
+        symbols.describe(bank, addr).unwrap_or_default()
+    }
+
+    /// Write report of hot instructions, loops and functions,
+    /// and folded stacks for flamegraph
+    pub fn finish(&self, symbols: &Symbols) -> Result<(), String> {
+        let percent = |cycles: u64| cycles as f64 * 100.0 / self.total.max(1) as f64;
+        let mut report = String::new();
+        let _ = writeln!(report, "{} cycles profiled", self.total);
+
+        let mut instructions: Vec<(&Location, &Counter)> = self.instructions.iter().collect();
+        instructions.sort_by_key(|(_, counter)| Reverse(counter.cycles));
+        let _ = writeln!(report, "\nhot instructions:\n{:>12} {:>7} {:>12}  location", "cycles", "%", "count");
+        for (location, counter) in instructions.iter().take(REPORT_LEN) {
+            let line = format!("{:>12} {:>6.2}% {:>12}  {:02x}:{:04x} {}", counter.cycles, percent(counter.cycles),
+                               counter.count, location.0, location.1, Self::label(symbols, **location));
+            let _ = writeln!(report, "{}", line.trim_end());
+        }
+
+        // cycles of a loop are those of instructions in its range
+        let mut loops: Vec<(u64, u64, &(Location, Location))> = self.loops.iter()
+            .map(|(range, iterations)| {
+                let ((bank, start), (_, end)) = *range;
+                let cycles = (start..=end)
+                    .filter_map(|addr| self.instructions.get(&(bank, addr)))
+                    .map(|counter| counter.cycles)
+                    .sum();
+                (cycles, *iterations, range)
+            })
+            .collect();
+        loops.sort_by_key(|(cycles, _, _)| Reverse(*cycles));
+        let _ = writeln!(report, "\nhot loops:\n{:>12} {:>7} {:>12}  range", "cycles", "%", "iterations");
+        for (cycles, iterations, ((bank, start), (_, end))) in loops.iter().take(REPORT_LEN) {
+            let line = format!("{:>12} {:>6.2}% {:>12}  {:02x}:{:04x}-{:04x} {}", cycles, percent(*cycles),
+                               iterations, bank, start, end, Self::label(symbols, (*bank, *start)));
+            let _ = writeln!(report, "{}", line.trim_end());
+        }
+
+        // self cycles are counted for the innermost call, total for every call in stack
+        let mut functions: HashMap<Option<Location>, (u64, u64)> = HashMap::new();
+        for (stack, cycles) in self.stacks.iter().filter(|(_, cycles)| *cycles > 0) {
+            functions.entry(stack.last().copied()).or_default().0 += cycles;
+            let mut seen = Vec::new();
+            for location in stack.iter().map(|location| Some(*location)).chain(std::iter::once(None)) {
+                if !seen.contains(&location) {
+                    functions.entry(location).or_default().1 += cycles;
+                    seen.push(location);
+                }
+            }
+        }
+        let mut functions: Vec<(Option<Location>, (u64, u64))> = functions.into_iter().collect();
+        functions.sort_by_key(|(_, (own, _))| Reverse(*own));
+        let _ = writeln!(report, "\nhot functions:\n{:>12} {:>7} {:>12} {:>7}  function", "self", "%", "total", "%");
+        for (location, (own, total)) in functions.iter().take(REPORT_LEN) {
+            let name = location.map_or("(root)".to_string(), |location| Self::name(symbols, location));
+            let _ = writeln!(report, "{:>12} {:>6.2}% {:>12} {:>6.2}%  {}", own, percent(*own), total, percent(*total), name);
+        }
+        fs::write(&self.path, report).map_err(|e| format!("{}: {}", self.path.display(), e))?;
+
+        // one line a stack, e.g. "root;Main;Func 1234"
+        let mut folded = String::new();
+        for (stack, cycles) in self.stacks.iter().filter(|(_, cycles)| *cycles > 0) {
+            let names: Vec<String> = stack.iter().map(|location| Self::name(symbols, *location)).collect();
+            let _ = writeln!(folded, "root{}{} {}", if names.is_empty() { "" } else { ";" }, names.join(";"), cycles);
+        }
+        let mut path = self.path.clone().into_os_string();
+        path.push(".folded");
+        fs::write(&path, folded).map_err(|e| format!("{}: {}", Path::new(&path).display(), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::boot::Model;
+
+    #[test]
+    fn folded_stacks() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x103].copy_from_slice(&[0xc3, 0x50, 0x01]); // jp $0150
+        rom[0x150..0x155].copy_from_slice(&[
+            0xcd, 0x60, 0x01,       // call $0160
+            0x18, 0xfe,             // jr $0153
+        ]);
+        rom[0x160..0x164].copy_from_slice(&[
+            0xcd, 0x70, 0x01,       // call $0170
+            0xc9,                   // ret
+        ]);
+        rom[0x170..0x172].copy_from_slice(&[
+            0x00,                   // nop
+            0xc9,                   // ret
+        ]);
+        let mut cpu = Cpu::new(rom, Model::DMG, None);
+        let symbols = Symbols::parse("00:0160 Outer\n00:0170 Inner\n").unwrap();
+        let path = std::env::temp_dir().join(format!("ruGameboy-profile-{}.txt", std::process::id()));
+        let mut profiler = Profiler::new(&path);
+        for _ in 0..7 {
+            profiler.exec(&cpu);
+            cpu.step().unwrap();
+        }
+        profiler.exec(&cpu);
+        profiler.finish(&symbols).unwrap();
+
+        let mut folded_path = path.clone().into_os_string();
+        folded_path.push(".folded");
+        let folded = fs::read_to_string(&folded_path).unwrap();
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&folded_path).unwrap();
+        // jp, call and jr / call and ret / nop and ret
+        assert_eq!(folded, "root 52\nroot;Outer 40\nroot;Outer;Inner 20\n");
+    }
+}
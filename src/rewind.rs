@@ -0,0 +1,239 @@
+use std::collections::VecDeque;
+
+use crate::vm::Vm;
+
+/// Frames between two snapshots
+const REWIND_INTERVAL: u64 = 4;
+
+/// Recent save states to step back in time. The newest state is kept whole,
+/// older ones are stored as delta to the next newer state, and the oldest
+/// are dropped when the memory budget is exceeded.
+pub struct Rewind {
+    /// frame and state of the newest snapshot
+    latest: Option<(u64, Vec<u8>)>,
+    /// older snapshots from oldest to newest, frame and delta to the next one
+    deltas: VecDeque<(u64, Vec<u8>)>,
+    /// bytes allowed for all snapshots
+    budget: usize,
+    /// bytes used by all snapshots
+    used: usize,
+    /// frames where joypad keys changed since the oldest snapshot, and the
+    /// keys held while the frame ran
+    inputs: VecDeque<(u64, u8)>,
+    /// keys held in the last frame pushed
+    keys: u8,
+}
+
+impl Rewind {
+    pub fn new(budget: usize) -> Self {
+        Self {
+            latest: None,
+            deltas: VecDeque::new(),
+            budget: budget,
+            used: 0,
+            inputs: VecDeque::new(),
+            keys: 0xFF,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.deltas.clear();
+        self.used = 0;
+        self.inputs.clear();
+    }
+
+    /// called after a frame runs, record joypad changes and take a snapshot
+    /// every few frames
+    pub fn push(&mut self, vm: &Vm) {
+        if self.budget == 0 {
+            return;
+        }
+        let keys = vm.cpu.bus.joypad.keys();
+        if keys != self.keys {
+            self.inputs.push_back((vm.frame.saturating_sub(1), keys));
+            self.keys = keys;
+        }
+        if !vm.frame.is_multiple_of(REWIND_INTERVAL) {
+            return;
+        }
+        let state = vm.save_state();
+        self.used += state.len();
+        if let Some((frame, older)) = self.latest.take() {
+            let delta = diff(&state, &older);
+            self.used = self.used - older.len() + delta.len();
+            self.deltas.push_back((frame, delta));
+        }
+        self.latest = Some((vm.frame, state));
+        while self.used > self.budget {
+            match self.deltas.pop_front() {
+                Some((_, delta)) => self.used -= delta.len(),
+                None => break,
+            }
+        }
+        // inputs before the oldest snapshot are in its state already
+        let oldest = self.deltas.front().or(self.latest.as_ref()).map_or(0, |(frame, _)| *frame);
+        while self.inputs.front().is_some_and(|(frame, _)| *frame < oldest) {
+            self.inputs.pop_front();
+        }
+    }
+
+    /// Go back to the previous frame, by loading the snapshot before it and
+    /// running up to it with the recorded joypad keys. Return false if no
+    /// older snapshot is kept.
+    pub fn step_back(&mut self, vm: &mut Vm) -> Result<bool, String> {
+        let target = match vm.frame.checked_sub(1) {
+            Some(target) => target,
+            None => return Ok(false),
+        };
+        loop {
+            match &self.latest {
+                Some((frame, _)) if *frame <= target => break,
+                Some(_) => self.pop(),
+                None => return Ok(false),
+            }
+        }
+        if let Some((_, state)) = &self.latest {
+            vm.load_state(state)?;
+        }
+        while vm.frame < target {
+            let frame = vm.frame;
+            if let Some((_, keys)) = self.inputs.iter().rev().find(|(at, _)| *at == frame) {
+                vm.cpu.bus.joypad.set_keys(*keys);
+            }
+            vm.run().map_err(|_| format!("emulation stopped at frame {}", vm.frame))?;
+        }
+        // inputs from the target frame on will be recorded again
+        while self.inputs.back().is_some_and(|(frame, _)| *frame >= target) {
+            self.inputs.pop_back();
+        }
+        self.keys = vm.cpu.bus.joypad.keys();
+        Ok(true)
+    }
+
+    /// drop the newest snapshot, restoring the one before it
+    fn pop(&mut self) {
+        if let Some((_, newer)) = self.latest.take() {
+            self.used -= newer.len();
+            if let Some((frame, delta)) = self.deltas.pop_back() {
+                let state = patch(&newer, &delta);
+                self.used = self.used - delta.len() + state.len();
+                self.latest = Some((frame, state));
+            }
+        }
+    }
+}
+
+/// Delta of target to base: target length, followed by runs of
+/// unchanged byte count and changed bytes XORed with base
+fn diff(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let byte = |i: usize| base.get(i).copied().unwrap_or(0);
+    let mut delta = Vec::new();
+    write_varint(&mut delta, target.len());
+    let mut i = 0;
+    while i < target.len() {
+        let start = i;
+        while i < target.len() && target[i] == byte(i) {
+            i += 1;
+        }
+        let skip = i - start;
+        let start = i;
+        while i < target.len() && target[i] != byte(i) {
+            i += 1;
+        }
+        write_varint(&mut delta, skip);
+        write_varint(&mut delta, i - start);
+        delta.extend((start..i).map(|i| target[i] ^ byte(i)));
+    }
+    delta
+}
+
+/// Rebuild target from base and delta made by diff
+fn patch(base: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut pos = 0;
+    let len = read_varint(delta, &mut pos);
+    let mut target: Vec<u8> = (0..len).map(|i| base.get(i).copied().unwrap_or(0)).collect();
+    let mut i = 0;
+    while pos < delta.len() {
+        i += read_varint(delta, &mut pos);
+        let changed = read_varint(delta, &mut pos);
+        for _ in 0..changed {
+            if let (Some(byte), Some(value)) = (target.get_mut(i), delta.get(pos)) {
+                *byte ^= value;
+            }
+            i += 1;
+            pos += 1;
+        }
+    }
+    target
+}
+
+/// LEB128, 7 bits a byte with bit 7 set if more bytes follow
+fn write_varint(data: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        data.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    data.push(value as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    while let Some(byte) = data.get(*pos) {
+        *pos += 1;
+        value |= ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::boot::Model;
+    use crate::joypad::JoypadKey;
+
+    /// ROM which keeps adding the joypad directions to work RAM
+    fn rom() -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x104].copy_from_slice(&[0x00, 0xc3, 0x50, 0x01]); // jp $0150
+        rom[0x150..0x160].copy_from_slice(&[
+            0x3e, 0x20,             // ld a, $20
+            0xe0, 0x00,             // ldh [$00], a
+            0xf0, 0x00,             // ldh a, [$00]
+            0x47,                   // ld b, a
+            0xfa, 0x00, 0xc0,       // ld a, [$c000]
+            0x80,                   // add b
+            0xea, 0x00, 0xc0,       // ld [$c000], a
+            0x18, 0xf4,             // jr $0154
+        ]);
+        rom
+    }
+
+    #[test]
+    fn step_back_replays_joypad_changes() {
+        let mut vm = Vm::new(rom(), Model::DMG, None);
+        let mut rewind = Rewind::new(1 << 20);
+        let mut checksums = Vec::new();
+        while vm.frame < 20 {
+            // pressed and released between two snapshots
+            match vm.frame {
+                13 => vm.cpu.bus.joypad.presskey(JoypadKey::RIGHT),
+                15 => vm.cpu.bus.joypad.releasekey(JoypadKey::RIGHT),
+                _ => {},
+            }
+            vm.run().unwrap();
+            rewind.push(&vm);
+            checksums.push(vm.checksum());
+        }
+
+        while vm.frame > 10 {
+            assert!(rewind.step_back(&mut vm).unwrap());
+            assert_eq!(vm.checksum(), checksums[vm.frame as usize - 1], "state differs at frame {}", vm.frame);
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use std::path::{Path, PathBuf};
+
+/// Save state file starts with magic and format version
+pub const STATE_MAGIC: &[u8; 8] = b"RUGBSTAT";
+/// Bumped only on incompatible changes, new data is added as new chunks
+/// or fields appended to a chunk, which older versions skip and newer
+/// versions read as zero. A chunk holds either fields or other chunks.
+pub const STATE_VERSION: u32 = 1;
+/// Number of save slots
+pub const STATE_SLOTS: usize = 10;
+
+/// Machine state saved to and loaded from save state
+pub trait Snapshot {
+    fn save_state(&self, w: &mut StateWriter);
+    fn load_state(&mut self, r: &mut StateReader);
+}
+
+/// path of save slot, next to the catridge, e.g. game.ss0
+pub fn slot_path(rom_path: &str, slot: usize) -> PathBuf {
+    Path::new(rom_path).with_extension(format!("ss{}", slot))
+}
+
+/// Write little endian values and chunks of tag, length and payload
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend(&value.to_le_bytes());
+    }
+
+    /// byte array with its length
+    pub fn bytes(&mut self, value: &[u8]) {
+        self.u32(value.len() as u32);
+        self.data.extend(value);
+    }
+
+    pub fn chunk<F: FnOnce(&mut StateWriter)>(&mut self, tag: &[u8; 4], f: F) {
+        let mut payload = StateWriter::default();
+        f(&mut payload);
+        self.data.extend(tag);
+        self.bytes(&payload.data);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// Read values written by StateWriter, values beyond the end read as zero
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    /// data ends before the length of its chunk, or of a chunk outside
+    cut_short: bool,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data: data, pos: 0, cut_short: false }
+    }
+
+    /// whether the chunk read is cut short, e.g. by a truncated file,
+    /// unlike a chunk of older version which has less fields
+    pub fn is_cut_short(&self) -> bool {
+        self.cut_short
+    }
+
+    fn take(&mut self, len: usize) -> &'a [u8] {
+        let start = self.pos.min(self.data.len());
+        let end = (self.pos + len).min(self.data.len());
+        self.pos += len;
+        &self.data[start..end]
+    }
+
+    fn array<const N: usize>(&mut self) -> [u8; N] {
+        let mut buf = [0; N];
+        let bytes = self.take(N);
+        buf[..bytes.len()].copy_from_slice(bytes);
+        buf
+    }
+
+    pub fn u8(&mut self) -> u8 {
+        self.array::<1>()[0]
+    }
+
+    pub fn bool(&mut self) -> bool {
+        self.u8() != 0
+    }
+
+    pub fn u16(&mut self) -> u16 {
+        u16::from_le_bytes(self.array())
+    }
+
+    pub fn u32(&mut self) -> u32 {
+        u32::from_le_bytes(self.array())
+    }
+
+    pub fn u64(&mut self) -> u64 {
+        u64::from_le_bytes(self.array())
+    }
+
+    /// byte array with its length
+    pub fn bytes(&mut self) -> &'a [u8] {
+        let len = self.u32() as usize;
+        self.take(len)
+    }
+
+    /// read byte array into buffer, leave the rest unchanged if shorter
+    pub fn bytes_into(&mut self, buf: &mut [u8]) {
+        let bytes = self.bytes();
+        let len = bytes.len().min(buf.len());
+        buf[..len].copy_from_slice(&bytes[..len]);
+    }
+
+    /// find chunk by tag from the start, unknown chunks are skipped
+    pub fn chunk(&self, tag: &[u8; 4]) -> Option<StateReader<'a>> {
+        let mut r = StateReader::new(self.data);
+        while r.pos < r.data.len() {
+            let found = r.take(4) == tag;
+            let len = r.u32() as usize;
+            let payload = r.take(len);
+            if found {
+                let mut chunk = StateReader::new(payload);
+                chunk.cut_short = self.cut_short || payload.len() < len;
+                return Some(chunk);
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,17 @@
+use std::fs::File;
+use std::io::BufWriter;
+
+/// Save screen buffer of 0RGB pixels as PNG
+pub fn save(path: &str, buffer: &[u32], width: usize, height: usize) -> Result<(), String> {
+    let file = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), width as u32, height as u32);
+    encoder.set_color(png::ColorType::RGB);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let data: Vec<u8> = buffer.iter()
+        .flat_map(|pixel| vec![(pixel >> 16) as u8, (pixel >> 8) as u8, *pixel as u8])
+        .collect();
+    encoder.write_header()
+        .and_then(|mut writer| writer.write_image_data(&data))
+        .map_err(|e| format!("{}: {}", path, e))
+}
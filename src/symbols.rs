@@ -0,0 +1,206 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+
+use crate::watch;
+
+/// Start of memory regions, an offset from a label is not shown
+/// across a region boundary
+const REGIONS: [u16; 10] = [0x0000, 0x4000, 0x8000, 0xa000, 0xc000, 0xd000, 0xe000, 0xfe00, 0xff00, 0xff80];
+
+fn region(addr: u16) -> u16 {
+    REGIONS.iter().copied().filter(|start| *start <= addr).max().unwrap_or(0)
+}
+
+/// Labels of a symbol file emitted by assemblers like RGBDS,
+/// each line is "BB:AAAA Label", comments start with ';',
+/// only [labels] section is read if the file has sections like WLA-DX
+#[derive(Default)]
+pub struct Symbols {
+    /// label at bank and address
+    labels: BTreeMap<(u16, u16), String>,
+    /// bank and address of label
+    addrs: HashMap<String, (u16, u16)>,
+}
+
+impl Symbols {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Self::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut symbols = Self::default();
+        let mut in_labels = true;
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or("").trim();
+            if line.starts_with('[') && line.ends_with(']') {
+                in_labels = line.eq_ignore_ascii_case("[labels]");
+                continue;
+            }
+            if line.is_empty() || !in_labels {
+                continue;
+            }
+            let (location, name) = match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+                [location, name] => (*location, *name),
+                _ => return Err(format!("line {}: expect BB:AAAA Label, found {}", i + 1, line)),
+            };
+            let mut parts = location.splitn(2, ':');
+            let bank = parts.next().and_then(|bank| u16::from_str_radix(bank, 16).ok());
+            let addr = parts.next().and_then(|addr| u16::from_str_radix(addr, 16).ok());
+            let (bank, addr) = match (bank, addr) {
+                (Some(bank), Some(addr)) => (bank, addr),
+                _ => return Err(format!("line {}: invalid location {}", i + 1, location)),
+            };
+            // the first label is shown if several are at the same address
+            symbols.labels.entry((bank, addr)).or_insert_with(|| name.to_string());
+            symbols.addrs.insert(name.to_string(), (bank, addr));
+        }
+        Ok(symbols)
+    }
+
+    /// Load symbols of ROM from file with extension .sym, if it exists
+    pub fn for_rom(rom_path: &Path) -> Result<Self, String> {
+        let path = rom_path.with_extension("sym");
+        if path.exists() {
+            Self::load(&path)
+        } else {
+            Ok(Self::default())
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.addrs.len()
+    }
+
+    /// label at exactly addr of bank
+    pub fn label(&self, bank: u16, addr: u16) -> Option<&str> {
+        self.labels.get(&(bank, addr)).map(|name| name.as_str())
+    }
+
+    /// nearest label before addr of bank, with offset, e.g. Main.loop+3
+    pub fn describe(&self, bank: u16, addr: u16) -> Option<String> {
+        let ((_, start), name) = self.labels.range((bank, region(addr))..=(bank, addr)).next_back()?;
+        Some(if *start == addr { name.clone() } else { format!("{}+{:x}", name, addr - start) })
+    }
+
+    /// bank and address of label
+    pub fn addr(&self, name: &str) -> Option<(u16, u16)> {
+        self.addrs.get(name).copied()
+    }
+
+    /// Parse label, name of IO register or address in hex
+    pub fn parse_location(&self, arg: &str) -> Result<u16, String> {
+        match self.addr(arg) {
+            Some((_, addr)) => Ok(addr),
+            None => watch::parse_location(arg),
+        }
+    }
+
+    /// Replace 16 bits operands like $0150 in disassembly with labels,
+    /// bank gives the bank mapped at an address
+    pub fn annotate<F: Fn(u16) -> u16>(&self, text: &str, bank: F) -> String {
+        if self.labels.is_empty() {
+            return text.to_string();
+        }
+        let mut result = String::new();
+        let mut rest = text;
+        while let Some(idx) = rest.find('$') {
+            result.push_str(&rest[..idx]);
+            let operand = rest[idx + 1..].get(..4)
+                .filter(|hex| hex.chars().all(|c| c.is_ascii_hexdigit()))
+                .and_then(|hex| u16::from_str_radix(hex, 16).ok())
+                .and_then(|addr| self.label(bank(addr), addr));
+            match operand {
+                Some(name) => {
+                    result.push_str(name);
+                    rest = &rest[idx + 5..];
+                },
+                None => {
+                    result.push('$');
+                    rest = &rest[idx + 1..];
+                },
+            }
+        }
+        result.push_str(rest);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SYM: &str = "; File generated by rgblink
+00:0150 Main
+00:0158 Main.loop ; comment after label
+01:4000 Bank1
+00:c000 wCounter
+00:0150 Alias
+";
+
+    #[test]
+    fn parse() {
+        let symbols = Symbols::parse(SYM).unwrap();
+        assert_eq!(symbols.len(), 5);
+        assert_eq!(symbols.label(0, 0x0150), Some("Main"));
+        assert_eq!(symbols.addr("Alias"), Some((0, 0x0150)));
+        assert_eq!(symbols.addr("Bank1"), Some((1, 0x4000)));
+        assert_eq!(symbols.label(0, 0x4000), None);
+
+        assert!(Symbols::parse("00:0150").is_err());
+        assert!(Symbols::parse("00:0150 Main extra").is_err());
+        assert!(Symbols::parse("0150 Main").is_err());
+        assert!(Symbols::parse("xx:0150 Main").is_err());
+    }
+
+    #[test]
+    fn parse_sections() {
+        let text = "[labels]
+00:0150 Main
+[definitions]
+00000010 SIZE
+[LABELS]
+01:4000 Bank1
+";
+        let symbols = Symbols::parse(text).unwrap();
+        assert_eq!(symbols.len(), 2);
+        assert_eq!(symbols.addr("Bank1"), Some((1, 0x4000)));
+        assert_eq!(symbols.addr("SIZE"), None);
+    }
+
+    #[test]
+    fn describe() {
+        let symbols = Symbols::parse(SYM).unwrap();
+        assert_eq!(symbols.describe(0, 0x0150).as_deref(), Some("Main"));
+        assert_eq!(symbols.describe(0, 0x0153).as_deref(), Some("Main+3"));
+        assert_eq!(symbols.describe(0, 0x0160).as_deref(), Some("Main.loop+8"));
+        assert_eq!(symbols.describe(0, 0x0100), None);
+        assert_eq!(symbols.describe(1, 0x4001).as_deref(), Some("Bank1+1"));
+        // not across region boundary
+        assert_eq!(symbols.describe(0, 0x4001), None);
+        assert_eq!(symbols.describe(0, 0xd000), None);
+    }
+
+    #[test]
+    fn annotate() {
+        let symbols = Symbols::parse(SYM).unwrap();
+        let bank = |addr| if (0x4000..0x8000).contains(&addr) { 1 } else { 0 };
+        assert_eq!(symbols.annotate("jp $0158", bank), "jp Main.loop");
+        assert_eq!(symbols.annotate("call $4000", bank), "call Bank1");
+        assert_eq!(symbols.annotate("ld [$c000], a", bank), "ld [wCounter], a");
+        assert_eq!(symbols.annotate("ld a, $01", bank), "ld a, $01");
+        assert_eq!(symbols.annotate("jp $0159", bank), "jp $0159");
+        assert_eq!(Symbols::default().annotate("jp $0158", bank), "jp $0158");
+    }
+
+    #[test]
+    fn parse_location() {
+        let symbols = Symbols::parse(SYM).unwrap();
+        assert_eq!(symbols.parse_location("Main.loop"), Ok(0x0158));
+        assert_eq!(symbols.parse_location("Bank1"), Ok(0x4000));
+        assert_eq!(symbols.parse_location("LCDC"), Ok(0xff40));
+        assert_eq!(symbols.parse_location("c100"), Ok(0xc100));
+        assert!(symbols.parse_location("Missing").is_err());
+    }
+}
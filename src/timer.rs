@@ -1,9 +1,13 @@
 use crate::bus::Device;
 use std::default::Default;
+use crate::savestate::{Snapshot, StateWriter, StateReader};
 
 pub const TIMER_START: u16 = 0xff04;
 pub const TIMER_END: u16 = 0xff07;
 
+/// Timer is updated every M-cycle
+const M_CYCLE: u64 = 4;
+
 enum TimerScale {
     X1  = 0b00, // freq 4096
     X4  = 0b11, // freq 16384
@@ -15,6 +19,18 @@ impl Default for TimerScale {
     fn default() -> Self { TimerScale::X1 }
 }
 
+impl TimerScale {
+    /// bit of internal divider, TIMA increments on its falling edge
+    fn bit(&self) -> u16 {
+        match self {
+            TimerScale::X1  => 9, // 4MHz / 1024 = 4.096 KHz
+            TimerScale::X4  => 7, // 4MHz / 256  = 16.384 KHz
+            TimerScale::X16 => 5, // 4MHz / 64   = 65.536 KHz
+            TimerScale::X64 => 3, // 4MHz / 16   = 262.144 KHz
+        }
+    }
+}
+
 #[derive(Default)]
 pub struct TimerControl {
     scale: TimerScale,
@@ -23,8 +39,9 @@ pub struct TimerControl {
 
 #[derive(Default)]
 pub struct Timer {
-    /// ff04 div, incremented 16384 times a second
-    div: u8,
+    /// internal divider incremented every clock,
+    /// ff04 div is its upper 8 bits, incremented 16384 times a second
+    counter: u16,
     /// ff05 tima, incremented by frequency set by TAC
     tima: u8,
     /// ff06 tma, when tima overflow, it load value from tma
@@ -41,9 +58,16 @@ pub struct Timer {
     tac: TimerControl,
 
     // implementation
-    div_counter: u64,
-    timer_counter: u64,
-    roundvalue: u64,
+    /// clocks short of an M-cycle, run in next update
+    clock: u64,
+    /// divider bit selected by TAC AND timer enable, in last update
+    signal: bool,
+    /// TIMA overflowed in last M-cycle, it reads 0 until reloaded from TMA,
+    /// writing TIMA now cancels the reload
+    reloading: bool,
+    /// TIMA is reloaded in last M-cycle, writing TIMA now is ignored
+    /// and writing TMA is also copied to TIMA
+    reloaded: bool,
     pub is_interrupt: bool,
 }
 
@@ -56,28 +80,55 @@ impl Timer {
         self.is_interrupt
     }
 
+    /// set DIV as left by boot ROM
+    pub fn set_div(&mut self, value: u8) {
+        self.counter = (value as u16) << 8;
+        self.signal = self.signal();
+    }
+
     pub fn update(&mut self, clock: u64) {
-        // handle div
-        // div has a constant update rate: 16384 Hz
-        // which means its round value is 4MHz / 16384 = 256
-        self.div_counter += clock;
-        if self.div_counter >= 256 {
-            self.div_counter -= 256;
-            self.div = self.div.wrapping_add(1);
+        // step by M-cycle so that the delayed reload happens in order
+        self.clock += clock;
+        while self.clock >= M_CYCLE {
+            self.clock -= M_CYCLE;
+            self.step();
+        }
+    }
+
+    fn step(&mut self) {
+        self.reloaded = false;
+        // TMA is loaded and interrupt is requested one M-cycle after overflow
+        if self.reloading {
+            self.reloading = false;
+            self.reloaded = true;
+            self.tima = self.tma;
+            self.is_interrupt = true;
         }
 
-        // handle tac
-        if self.tac.running {
-            self.timer_counter += clock;
-            if self.timer_counter >= self.roundvalue {
-                self.timer_counter -= self.roundvalue;
+        self.counter = self.counter.wrapping_add(M_CYCLE as u16);
+        self.detect_edge();
+    }
 
-                if self.tma == 0xff {
-                    self.tma = self.tima;
-                } else {
-                    self.tma = self.tma.wrapping_add(1);
-                }
-            }
+    fn signal(&self) -> bool {
+        self.tac.running && (self.counter >> self.tac.scale.bit()) & 0x1 != 0
+    }
+
+    /// increase TIMA on falling edge of the signal, which also happens
+    /// when DIV is reset or TAC is rewritten
+    fn detect_edge(&mut self) {
+        let signal = self.signal();
+        if self.signal && !signal {
+            self.increase();
+        }
+        self.signal = signal;
+    }
+
+    fn increase(&mut self) {
+        if self.tima == 0xff {
+            self.tima = 0;
+            self.reloading = true;
+        } else {
+            self.tima += 1;
         }
     }
 }
@@ -85,10 +136,12 @@ impl Timer {
 impl Device for Timer {
     fn load(&self, addr: u16) -> Result<u8, ()> {
         match addr {
-            0xFF04 => Ok(self.div),
+            0xFF04 => Ok((self.counter >> 8) as u8),
             0xFF05 => Ok(self.tima),
             0xFF06 => Ok(self.tma),
             0xFF07 => Ok({
+                // unused bits read as 1
+                0xf8 |
                 ( if self.tac.running { 1 << 2 } else { 0 } ) |
                 ( match self.tac.scale {
                     TimerScale::X1  => 0b00,
@@ -103,9 +156,22 @@ impl Device for Timer {
 
     fn store(&mut self, addr: u16, value: u8) -> Result<(), ()> {
         match addr {
-            0xFF04 => self.div = 0,
-            0xFF05 => self.tima = value,
-            0xFF06 => self.tma = value,
+            0xFF04 => {
+                self.counter = 0;
+                self.detect_edge();
+            },
+            0xFF05 => {
+                if !self.reloaded {
+                    self.tima = value;
+                    self.reloading = false;
+                }
+            },
+            0xFF06 => {
+                self.tma = value;
+                if self.reloaded {
+                    self.tima = value;
+                }
+            },
             0xFF07 => {
                 self.tac.running = (value & 0x4) != 0;
                 self.tac.scale = match value & 0x3 {
@@ -115,17 +181,106 @@ impl Device for Timer {
                     3 => TimerScale::X4,
                     _ => return Err(()),
                 };
-                self.roundvalue = match self.tac.scale {
-                    TimerScale::X1  => 1024, // 4MHz / 1024 = 4.096 KHz
-                    TimerScale::X4  => 256,  // 4MHz / 256  = 16.384 KHz
-                    TimerScale::X16 => 64,   // 4MHz / 64   = 65.536 KHz
-                    TimerScale::X64 => 16,   // 4MHz / 16   = 262.144 KHz
-                };
-                // reset timer_counter so it will surpass limit too much
-                self.timer_counter = 0;
+                self.detect_edge();
             },
             _ => return Err(()),
         }
         Ok(())
     }
 }
+
+impl Snapshot for Timer {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u16(self.counter);
+        w.u8(self.tima);
+        w.u8(self.tma);
+        w.u8(self.load(0xFF07).unwrap_or(0));
+        w.bool(self.signal);
+        w.bool(self.reloading);
+        w.bool(self.reloaded);
+        w.bool(self.is_interrupt);
+        w.u64(self.clock);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.counter = r.u16();
+        self.tima = r.u8();
+        self.tma = r.u8();
+        let tac = r.u8();
+        self.tac.running = (tac & 0x4) != 0;
+        self.tac.scale = match tac & 0x3 {
+            1 => TimerScale::X64,
+            2 => TimerScale::X16,
+            3 => TimerScale::X4,
+            _ => TimerScale::X1,
+        };
+        self.signal = r.bool();
+        self.reloading = r.bool();
+        self.reloaded = r.bool();
+        self.is_interrupt = r.bool();
+        self.clock = r.u64();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMA: u16 = 0xFF05;
+    const TMA: u16 = 0xFF06;
+    const TAC: u16 = 0xFF07;
+
+    /// timer started with TAC, from DIV reset
+    fn started(tac: u8) -> Timer {
+        let mut timer = Timer::new();
+        timer.store(TAC, tac).unwrap();
+        timer
+    }
+
+    fn tima(timer: &Timer) -> u8 {
+        timer.load(TIMA).unwrap()
+    }
+
+    #[test]
+    fn tima_increments_at_tac_frequency() {
+        for (tac, period) in [(0b100, 1024), (0b101, 16), (0b110, 64), (0b111, 256)] {
+            let mut timer = started(tac);
+            timer.update(period - M_CYCLE);
+            assert_eq!(tima(&timer), 0, "TAC {:03b} before {} clocks", tac, period);
+            timer.update(M_CYCLE);
+            assert_eq!(tima(&timer), 1, "TAC {:03b} after {} clocks", tac, period);
+            timer.update(period);
+            assert_eq!(tima(&timer), 2, "TAC {:03b} after {} clocks", tac, period * 2);
+        }
+    }
+
+    #[test]
+    fn tima_stops_when_disabled() {
+        let mut timer = started(0b001);
+        timer.update(1024);
+        assert_eq!(tima(&timer), 0);
+    }
+
+    #[test]
+    fn clocks_short_of_m_cycle_are_kept() {
+        let mut timer = started(0b101);
+        for _ in 0..8 {
+            timer.update(2);
+        }
+        assert_eq!(tima(&timer), 1);
+    }
+
+    #[test]
+    fn overflow_reloads_tma_one_m_cycle_later() {
+        let mut timer = started(0b101);
+        timer.store(TMA, 0x42).unwrap();
+        timer.store(TIMA, 0xff).unwrap();
+        timer.update(16);
+        // TIMA reads 0 for an M-cycle, and no interrupt yet
+        assert_eq!(tima(&timer), 0);
+        assert!(!timer.is_interrupt);
+        timer.update(M_CYCLE);
+        assert_eq!(tima(&timer), 0x42);
+        assert!(timer.is_interrupt);
+    }
+}
@@ -0,0 +1,110 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::cpu::Cpu;
+
+/// Write CPU state before each executed instruction, one line each in the
+/// format of gameboy-doctor, so it can be diffed against other emulators:
+/// A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02
+pub struct Trace {
+    out: BufWriter<File>,
+    /// only instructions with PC in range are written, inclusive
+    start: u16,
+    end: u16,
+    /// lines to write, None for no limit
+    limit: Option<u64>,
+    lines: u64,
+}
+
+impl Trace {
+    pub fn create(path: &Path, start: u16, end: u16, limit: Option<u64>) -> Result<Self, String> {
+        let file = File::create(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok(Self {
+            out: BufWriter::new(file),
+            start: start,
+            end: end,
+            limit: limit,
+            lines: 0,
+        })
+    }
+
+    /// whether the limit of lines is reached
+    pub fn is_finished(&self) -> bool {
+        self.limit.is_some_and(|limit| self.lines >= limit)
+    }
+
+    pub fn log(&mut self, cpu: &Cpu) -> io::Result<()> {
+        if self.is_finished() || cpu.is_stopped() || cpu.pc < self.start || cpu.pc > self.end {
+            return Ok(());
+        }
+        let regs = &cpu.regs;
+        let pcmem: Vec<String> = (0..4)
+            .map(|i| format!("{:02X}", cpu.bus.peek8(cpu.pc.wrapping_add(i)).unwrap_or(0xff)))
+            .collect();
+        writeln!(self.out, "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{}",
+                 regs.a, u8::from(&regs.f), regs.b, regs.c, regs.d, regs.e, regs.h, regs.l,
+                 cpu.sp, cpu.pc, pcmem.join(","))?;
+        self.lines += 1;
+        if self.is_finished() {
+            self.out.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::boot::Model;
+
+    /// run trace over a few instructions and return the lines written
+    fn trace(name: &str, start: u16, end: u16, limit: Option<u64>) -> Vec<String> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x104].copy_from_slice(&[0xc3, 0x50, 0x01, 0x00]); // jp $0150
+        rom[0x150..0x154].copy_from_slice(&[
+            0x00,                   // nop
+            0x00,                   // nop
+            0x18, 0xfc,             // jr $0150
+        ]);
+        let mut cpu = Cpu::new(rom, Model::DMG, None);
+        let path = std::env::temp_dir().join(format!("ruGameboy-{}-{}.log", name, std::process::id()));
+        let mut trace = Trace::create(&path, start, end, limit).unwrap();
+        for _ in 0..10 {
+            trace.log(&cpu).unwrap();
+            cpu.step().unwrap();
+        }
+        trace.finish().unwrap();
+        let text = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        text.lines().map(|line| line.to_string()).collect()
+    }
+
+    #[test]
+    fn gameboy_doctor_format() {
+        let lines = trace("format", 0x0000, 0xffff, Some(2));
+        assert_eq!(lines, [
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:C3,50,01,00",
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0150 PCMEM:00,00,18,FC",
+        ]);
+    }
+
+    #[test]
+    fn pc_range() {
+        let lines = trace("range", 0x0151, 0x0152, None);
+        let pcs: Vec<&str> = lines.iter().filter_map(|line| line.split_whitespace().nth(9)).collect();
+        assert_eq!(pcs, ["PC:0151", "PC:0152", "PC:0151", "PC:0152", "PC:0151", "PC:0152"]);
+    }
+
+    #[test]
+    fn limit() {
+        let lines = trace("limit", 0x0150, 0x0150, Some(2));
+        assert_eq!(lines.len(), 2);
+        assert!(lines.iter().all(|line| line.contains("PC:0150")));
+    }
+}
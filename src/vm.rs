@@ -1,5 +1,7 @@
 use crate::cpu::Cpu;
-use crate::gpu::GpuMode;
+use crate::boot::Model;
+use crate::checksum::crc32;
+use crate::savestate::{Snapshot, StateWriter, StateReader, STATE_MAGIC, STATE_VERSION};
 use log::{debug};
 
 pub const WIDTH: usize = 160;
@@ -8,24 +10,116 @@ pub const HEIGHT: usize = 144;
 pub struct Vm {
     pub cpu: Cpu,
     pub buffer: Vec<u32>,
+    /// frames finished since power on
+    pub frame: u64,
+    /// CRC-32 of catridge, save state is only loaded to the same catridge
+    rom: u32,
 }
 
 impl Vm {
-    pub fn new(binary: Vec<u8>) -> Self {
+    pub fn new(binary: Vec<u8>, model: Model, boot_rom: Option<Vec<u8>>) -> Self {
         Self {
-            cpu: Cpu::new(binary),
+            rom: crc32(&binary),
+            cpu: Cpu::new(binary, model, boot_rom),
             buffer: vec![0; WIDTH * HEIGHT],
+            frame: 0,
         }
     }
 
     pub fn run(&mut self) -> Result<(), ()> {
-        // TODO: better way to control this
-        while self.cpu.bus.gpu.mode != GpuMode::VBlank {
+        self.run_until(|_| false).map(|_| ())
+    }
+
+    /// Run the rest of frame, stop before an instruction if break_at returns
+    /// true, return true if stopped. Calling again continues the frame.
+    pub fn run_until<F: FnMut(&Cpu) -> bool>(&mut self, mut break_at: F) -> Result<bool, ()> {
+        // run until gpu finish a frame, which is the start of VBlank,
+        // or a frame time passed when LCD is off
+        while !self.cpu.bus.gpu.frame_ready {
+            if break_at(&self.cpu) {
+                return Ok(true);
+            }
             self.cpu.step()?;
         }
+        self.cpu.bus.gpu.frame_ready = false;
         self.cpu.bus.gpu.build_screen(&mut self.buffer);
-        while self.cpu.bus.gpu.mode == GpuMode::VBlank {
-            self.cpu.step()?;
+        self.frame += 1;
+        Ok(false)
+    }
+
+    /// checksum of work RAM, HRAM and PC, which does not depend on palette,
+    /// used to detect desync of movie playback
+    pub fn checksum(&self) -> u32 {
+        let bus = &self.cpu.bus;
+        let mut data: Vec<u8> = (0xc000..=0xdfff).chain(0xff80..=0xfffe)
+            .map(|addr| bus.peek8(addr).unwrap_or(0))
+            .collect();
+        data.extend(&self.cpu.pc.to_le_bytes());
+        crc32(&data)
+    }
+
+    /// Save state between frames, with emulator version and a screen
+    /// thumbnail in RGB for frontends to show
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::default();
+        w.chunk(b"INFO", |w| {
+            w.bytes(env!("CARGO_PKG_VERSION").as_bytes());
+            w.u32(self.rom);
+            w.u64(self.frame);
+        });
+        w.chunk(b"THMB", |w| {
+            w.u16(WIDTH as u16);
+            w.u16(HEIGHT as u16);
+            let rgb: Vec<u8> = self.buffer.iter()
+                .flat_map(|pixel| vec![(pixel >> 16) as u8, (pixel >> 8) as u8, *pixel as u8])
+                .collect();
+            w.bytes(&rgb);
+        });
+        w.chunk(b"CPU ", |w| self.cpu.save_state(w));
+
+        let mut data = STATE_MAGIC.to_vec();
+        data.extend(&STATE_VERSION.to_le_bytes());
+        data.extend(w.into_bytes());
+        data
+    }
+
+    /// Load state saved by this or older versions
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        if !data.starts_with(STATE_MAGIC) {
+            return Err("not a save state".to_string());
+        }
+        let mut r = StateReader::new(&data[STATE_MAGIC.len()..]);
+        let version = r.u32();
+        if version > STATE_VERSION {
+            return Err(format!("save state version {} is newer than supported {}", version, STATE_VERSION));
+        }
+        let r = StateReader::new(&data[STATE_MAGIC.len() + 4..]);
+        let mut info = r.chunk(b"INFO").ok_or("save state has no info")?;
+        let _emulator = info.bytes();
+        if info.u32() != self.rom {
+            return Err("save state is taken with another catridge".to_string());
+        }
+        let frame = info.u64();
+        let mut cpu = r.chunk(b"CPU ").ok_or("save state has no CPU")?;
+        // missing fields read as zero, which is only right for older versions
+        for (tag, name) in [(b"REGS", "CPU registers"), (b"BUS ", "bus")] {
+            match cpu.chunk(tag) {
+                Some(chunk) if !chunk.is_cut_short() => {},
+                Some(_) => return Err(format!("save state is cut short in {}", name)),
+                None => return Err(format!("save state has no {}", name)),
+            }
+        }
+
+        self.cpu.load_state(&mut cpu);
+        self.frame = frame;
+        if let Some(mut thumbnail) = r.chunk(b"THMB") {
+            let size = (thumbnail.u16() as usize, thumbnail.u16() as usize);
+            let rgb = thumbnail.bytes();
+            if size == (WIDTH, HEIGHT) && rgb.len() == WIDTH * HEIGHT * 3 {
+                for (pixel, c) in self.buffer.iter_mut().zip(rgb.chunks(3)) {
+                    *pixel = (c[0] as u32) << 16 | (c[1] as u32) << 8 | c[2] as u32;
+                }
+            }
         }
         Ok(())
     }
@@ -34,3 +128,70 @@ impl Vm {
         debug!("{}", self.cpu.dump());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// ROM which keeps changing work RAM, palette, scroll and tiles
+    fn rom() -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x104].copy_from_slice(&[0x00, 0xc3, 0x50, 0x01]); // jp $0150
+        rom[0x150..0x166].copy_from_slice(&[
+            0x21, 0x00, 0x80,       // ld hl, $8000
+            0x3c,                   // inc a
+            0xea, 0x00, 0xc0,       // ld [$c000], a
+            0xe0, 0x47,             // ldh [$47], a
+            0xe0, 0x43,             // ldh [$43], a
+            0x22,                   // ld [hl+], a
+            0xcb, 0x9c,             // res 3, h
+            0x18, 0xf3,             // jr $0153
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ]);
+        rom
+    }
+
+    /// screen and checksum of each frame
+    fn run(vm: &mut Vm, frames: u64) -> Vec<(Vec<u32>, u32)> {
+        (0..frames).map(|_| {
+            vm.run().unwrap();
+            (vm.buffer.clone(), vm.checksum())
+        }).collect()
+    }
+
+    fn assert_same_frames(found: &[(Vec<u32>, u32)], expect: &[(Vec<u32>, u32)]) {
+        assert_eq!(found.len(), expect.len());
+        for (i, (found, expect)) in found.iter().zip(expect).enumerate() {
+            assert!(found.0 == expect.0, "screen differs at frame {}", i);
+            assert_eq!(found.1, expect.1, "state differs at frame {}", i);
+        }
+    }
+
+    #[test]
+    fn load_state_runs_the_same_frames() {
+        let mut vm = Vm::new(rom(), Model::DMG, None);
+        run(&mut vm, 30);
+        let state = vm.save_state();
+        let expect = run(&mut vm, 20);
+        assert!(expect.windows(2).any(|frames| frames[0] != frames[1]));
+
+        vm.load_state(&state).unwrap();
+        assert_same_frames(&run(&mut vm, 20), &expect);
+
+        // nothing is left from the machine state is loaded to
+        let mut other = Vm::new(rom(), Model::DMG, None);
+        other.load_state(&state).unwrap();
+        assert_same_frames(&run(&mut other, 20), &expect);
+    }
+
+    #[test]
+    fn load_state_rejects_truncated_state() {
+        let mut vm = Vm::new(rom(), Model::DMG, None);
+        run(&mut vm, 5);
+        let state = vm.save_state();
+        for len in [state.len() - 1, state.len() - 100, state.len() / 2, 16] {
+            assert!(vm.load_state(&state[..len]).is_err(), "state cut to {} bytes is loaded", len);
+        }
+        assert!(vm.load_state(&state).is_ok());
+    }
+}
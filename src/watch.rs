@@ -0,0 +1,197 @@
+use std::cell::RefCell;
+
+use crate::symbols::Symbols;
+
+/// Names of IO registers, which can be watched by name
+const IO_REGISTERS: [(&str, u16); 54] = [
+    ("P1", 0xff00), ("SB", 0xff01), ("SC", 0xff02), ("DIV", 0xff04),
+    ("TIMA", 0xff05), ("TMA", 0xff06), ("TAC", 0xff07), ("IF", 0xff0f),
+    ("NR10", 0xff10), ("NR11", 0xff11), ("NR12", 0xff12), ("NR13", 0xff13),
+    ("NR14", 0xff14), ("NR21", 0xff16), ("NR22", 0xff17), ("NR23", 0xff18),
+    ("NR24", 0xff19), ("NR30", 0xff1a), ("NR31", 0xff1b), ("NR32", 0xff1c),
+    ("NR33", 0xff1d), ("NR34", 0xff1e), ("NR41", 0xff20), ("NR42", 0xff21),
+    ("NR43", 0xff22), ("NR44", 0xff23), ("NR50", 0xff24), ("NR51", 0xff25),
+    ("NR52", 0xff26), ("LCDC", 0xff40), ("STAT", 0xff41), ("SCY", 0xff42),
+    ("SCX", 0xff43), ("LY", 0xff44), ("LYC", 0xff45), ("DMA", 0xff46),
+    ("BGP", 0xff47), ("OBP0", 0xff48), ("OBP1", 0xff49), ("WY", 0xff4a),
+    ("WX", 0xff4b), ("KEY1", 0xff4d), ("VBK", 0xff4f), ("HDMA1", 0xff51),
+    ("HDMA2", 0xff52), ("HDMA3", 0xff53), ("HDMA4", 0xff54), ("HDMA5", 0xff55),
+    ("BCPS", 0xff68), ("BCPD", 0xff69), ("OCPS", 0xff6a), ("OCPD", 0xff6b),
+    ("SVBK", 0xff70), ("IE", 0xffff),
+];
+
+/// name of IO register at addr
+pub fn io_name(addr: u16) -> Option<&'static str> {
+    IO_REGISTERS.iter().find(|(_, a)| *a == addr).map(|(name, _)| *name)
+}
+
+/// Parse address in hex or name of IO register, e.g. c000 or LCDC
+pub fn parse_location(arg: &str) -> Result<u16, String> {
+    IO_REGISTERS.iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(arg))
+        .map(|(_, addr)| Ok(*addr))
+        .unwrap_or_else(|| crate::parse_addr(arg))
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Compare {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Compare {
+    /// longer operators first, so that "<=" is not taken as "<"
+    pub const OPERATORS: [(&'static str, Compare); 6] = [
+        ("==", Compare::Eq), ("!=", Compare::Ne), ("<=", Compare::Le),
+        (">=", Compare::Ge), ("<", Compare::Lt), (">", Compare::Gt),
+    ];
+
+    pub fn name(&self) -> &'static str {
+        Self::OPERATORS.iter().find(|(_, op)| op == self).map_or("", |(name, _)| name)
+    }
+
+    pub fn test(&self, left: u16, right: u16) -> bool {
+        match self {
+            Compare::Eq => left == right,
+            Compare::Ne => left != right,
+            Compare::Lt => left < right,
+            Compare::Le => left <= right,
+            Compare::Gt => left > right,
+            Compare::Ge => left >= right,
+        }
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+impl Access {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Access::Read => "read",
+            Access::Write => "write",
+            Access::Execute => "execute",
+        }
+    }
+}
+
+/// Watch accesses to an address range, inclusive
+pub struct Watchpoint {
+    pub start: u16,
+    pub end: u16,
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+    /// only hit if value read or written matches
+    pub cond: Option<(Compare, u8)>,
+}
+
+impl Watchpoint {
+    /// Parse kind r, w, rw or x, range START[-END] and condition like ==0x10,
+    /// START and END may be labels of symbols
+    pub fn parse(kind: &str, range: &str, cond: Option<&str>, symbols: &Symbols) -> Result<Self, String> {
+        let (read, write, execute) = match kind {
+            "r" => (true, false, false),
+            "w" => (false, true, false),
+            "rw" => (true, true, false),
+            "x" => (false, false, true),
+            _ => return Err(format!("expect r, w, rw or x, found {}", kind)),
+        };
+        let mut bounds = range.splitn(2, '-');
+        let start = symbols.parse_location(bounds.next().unwrap_or(""))?;
+        let end = match bounds.next() {
+            Some(end) => symbols.parse_location(end)?,
+            None => start,
+        };
+        if end < start {
+            return Err(format!("invalid range {}", range));
+        }
+        let cond = match cond {
+            Some(cond) => {
+                let (name, op) = Compare::OPERATORS.iter()
+                    .find(|(name, _)| cond.starts_with(name))
+                    .ok_or(format!("expect condition like ==0x10, found {}", cond))?;
+                let value = crate::parse_addr(&cond[name.len()..])?;
+                if value > 0xff {
+                    return Err(format!("value {} is not a byte", &cond[name.len()..]));
+                }
+                Some((*op, value as u8))
+            },
+            None => None,
+        };
+        Ok(Self {
+            start: start,
+            end: end,
+            read: read,
+            write: write,
+            execute: execute,
+            cond: cond,
+        })
+    }
+
+    fn watches(&self, access: Access, addr: u16) -> bool {
+        let kind = match access {
+            Access::Read => self.read,
+            Access::Write => self.write,
+            Access::Execute => self.execute,
+        };
+        kind && self.start <= addr && addr <= self.end
+    }
+}
+
+/// Access caught by a watchpoint
+pub struct Hit {
+    /// index of watchpoint
+    pub index: usize,
+    pub access: Access,
+    pub addr: u16,
+    /// value before write, or value read
+    pub old: u8,
+    /// value written, or value read
+    pub new: u8,
+}
+
+/// Watchpoints checked on CPU access to bus. Loads only borrow the bus,
+/// so the hit is kept in a RefCell until the debugger takes it.
+#[derive(Default)]
+pub struct Watchpoints {
+    pub points: Vec<Watchpoint>,
+    hit: RefCell<Option<Hit>>,
+}
+
+impl Watchpoints {
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    /// whether access to addr is watched, so the old value should be given
+    pub fn watches(&self, access: Access, addr: u16) -> bool {
+        self.points.iter().any(|point| point.watches(access, addr))
+    }
+
+    /// record the first access matching a watchpoint
+    pub fn check(&self, access: Access, addr: u16, old: u8, new: u8) {
+        let index = self.points.iter().position(|point| {
+            point.watches(access, addr) &&
+                point.cond.is_none_or(|(op, value)| op.test(new as u16, value as u16))
+        });
+        if let Some(index) = index {
+            let mut hit = self.hit.borrow_mut();
+            if hit.is_none() {
+                *hit = Some(Hit { index: index, access: access, addr: addr, old: old, new: new });
+            }
+        }
+    }
+
+    pub fn take_hit(&self) -> Option<Hit> {
+        self.hit.borrow_mut().take()
+    }
+}